@@ -0,0 +1,66 @@
+// Minimal ACL support, for now only the `default` user exists.
+
+pub const DEFAULT_USER: &str = "default";
+
+pub const CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "set",
+    "sortedset",
+    "list",
+    "hash",
+    "string",
+    "bitmap",
+    "hyperloglog",
+    "geo",
+    "stream",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+];
+
+pub struct AclUser {
+    pub name: String,
+    pub enabled: bool,
+    pub nopass: bool,
+}
+
+impl AclUser {
+    pub fn default_user() -> Self {
+        Self {
+            name: DEFAULT_USER.to_string(),
+            enabled: true,
+            nopass: true,
+        }
+    }
+
+    // The user description as printed by ACL LIST.
+    pub fn describe(&self) -> String {
+        format!(
+            "user {} {}{} ~* &* +@all",
+            self.name,
+            if self.enabled { "on" } else { "off" },
+            if self.nopass { " nopass" } else { "" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_default_user() {
+        assert_eq!(
+            "user default on nopass ~* &* +@all",
+            AclUser::default_user().describe()
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::ServerConfig;
+
+// A tiny http endpoint for health checks of orchestration tools.
+// Only `GET /health` is supported, it answers 200 once the server accepts
+// connections and 503 before that.
+
+pub async fn start(config: Arc<ServerConfig>, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    serve(listener, config).await
+}
+
+async fn serve(listener: TcpListener, config: Arc<ServerConfig>) -> Result<()> {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let config_cloned = config.clone();
+                tokio::spawn(async move {
+                    handle_request(stream, config_cloned)
+                        .await
+                        .unwrap_or_else(|error| eprintln!("admin: {:?}", error));
+                });
+            }
+            Err(e) => {
+                println!("admin error: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, config: Arc<ServerConfig>) -> Result<()> {
+    let mut buffer = vec![0; 1024];
+    let n = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..n]);
+    let request_line = request.lines().next().unwrap_or_default();
+
+    let (status, body) = if !request_line.starts_with("GET /health ") {
+        ("404 Not Found", "not found")
+    } else if config.is_ready() {
+        ("200 OK", "ok")
+    } else {
+        ("503 Service Unavailable", "loading")
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ServerRole;
+
+    use super::*;
+
+    async fn get(port: u16, path: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn start_admin(config: Arc<ServerConfig>) -> u16 {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, config));
+        port
+    }
+
+    #[tokio::test]
+    async fn test_health_once_ready() {
+        let config = Arc::new(ServerConfig::new(ServerRole::Leader, 1234));
+        let port = start_admin(config.clone()).await;
+
+        assert!(get(port, "/health")
+            .await
+            .starts_with("HTTP/1.1 503 Service Unavailable"));
+
+        config.set_ready();
+
+        assert!(get(port, "/health").await.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_path() {
+        let config = Arc::new(ServerConfig::new(ServerRole::Leader, 1234));
+        let port = start_admin(config).await;
+
+        assert!(get(port, "/other").await.starts_with("HTTP/1.1 404"));
+    }
+}
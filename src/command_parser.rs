@@ -1,19 +1,46 @@
+use std::time::Duration;
+
 use anyhow::{bail, Context, Result};
+use chrono::DateTime;
 
-use crate::message::Message;
+use crate::{
+    command_table::find_command,
+    db::{expire_date_from_now, SetCondition, SetExpiry, SetOperation},
+    message::Message,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     Ping,
     Echo(Message),
+    Hello {
+        protover: Option<u8>,
+        auth: Option<(String, String)>,
+    },
     Set {
         key: Message,
         value: Message,
-        expire_time: Option<i64>,
+        expiry: Option<SetExpiry>,
+        condition: SetCondition,
+        get: bool,
+    },
+    SetNx {
+        key: Message,
+        value: Message,
+    },
+    SetEx {
+        key: Message,
+        seconds: i64,
+        value: Message,
     },
     Get {
         key: Message,
     },
+    GetRange {
+        key: Message,
+        start: i64,
+        end: i64,
+    },
     Info {
         sections: Vec<Message>,
     },
@@ -22,7 +49,205 @@ pub enum Command {
         value: Message,
     },
     Psync,
-    Wait,
+    Wait {
+        numreplicas: usize,
+        // None blocks until enough replicas acknowledged
+        timeout: Option<Duration>,
+    },
+    Docs {
+        names: Vec<String>,
+    },
+    Cluster {
+        subcommand: String,
+    },
+    Acl {
+        subcommand: String,
+    },
+    Config {
+        subcommand: ConfigSubcommand,
+    },
+    Slowlog {
+        subcommand: SlowlogSubcommand,
+    },
+    Latency {
+        subcommand: LatencySubcommand,
+    },
+    Function {
+        subcommand: String,
+    },
+    RandomKey,
+    Subscribe {
+        channels: Vec<String>,
+    },
+    // no channels unsubscribes from all
+    Unsubscribe {
+        channels: Vec<String>,
+    },
+    PSubscribe {
+        patterns: Vec<String>,
+    },
+    // no patterns unsubscribes from all
+    PUnsubscribe {
+        patterns: Vec<String>,
+    },
+    Publish {
+        channel: String,
+        message: Message,
+    },
+    DbSize,
+    Multi,
+    Exec,
+    Discard,
+    Watch {
+        keys: Vec<Message>,
+    },
+    Unwatch,
+    FlushAll,
+    Keys {
+        pattern: String,
+    },
+    Scan {
+        cursor: u64,
+        pattern: Option<String>,
+        count: usize,
+    },
+    Exists {
+        keys: Vec<Message>,
+    },
+    // UNLINK is parsed as DEL, the memory is freed right away either way
+    Del {
+        keys: Vec<Message>,
+    },
+    DebugSleep {
+        duration: Duration,
+        global: bool,
+    },
+    Incr {
+        key: Message,
+    },
+    Decr {
+        key: Message,
+    },
+    IncrBy {
+        key: Message,
+        increment: i64,
+    },
+    DecrBy {
+        key: Message,
+        decrement: i64,
+    },
+    IncrByFloat {
+        key: Message,
+        increment: f64,
+    },
+    Append {
+        key: Message,
+        value: Message,
+    },
+    Ttl {
+        key: Message,
+    },
+    Pttl {
+        key: Message,
+    },
+    Expire {
+        key: Message,
+        milliseconds: i64,
+    },
+    Persist {
+        key: Message,
+    },
+    Type {
+        key: Message,
+    },
+    ObjectEncoding {
+        key: Message,
+    },
+    Dump {
+        key: Message,
+    },
+    Restore {
+        key: Message,
+        // milliseconds, 0 for no expiry
+        ttl: i64,
+        payload: Message,
+        replace: bool,
+        // the ttl is a unix time in milliseconds
+        absttl: bool,
+    },
+    LPush {
+        key: Message,
+        values: Vec<Message>,
+    },
+    RPush {
+        key: Message,
+        values: Vec<Message>,
+    },
+    LRange {
+        key: Message,
+        start: i64,
+        stop: i64,
+    },
+    LLen {
+        key: Message,
+    },
+    LPop {
+        key: Message,
+        count: Option<usize>,
+    },
+    RPop {
+        key: Message,
+        count: Option<usize>,
+    },
+    HSet {
+        key: Message,
+        fields: Vec<(String, Message)>,
+    },
+    HGet {
+        key: Message,
+        field: String,
+    },
+    HGetAll {
+        key: Message,
+    },
+    HDel {
+        key: Message,
+        fields: Vec<String>,
+    },
+    SAdd {
+        key: Message,
+        members: Vec<Message>,
+    },
+    SMembers {
+        key: Message,
+    },
+    SetStore {
+        operation: SetOperation,
+        destination: Message,
+        keys: Vec<Message>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigSubcommand {
+    Get { patterns: Vec<String> },
+    Set { name: String, value: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LatencySubcommand {
+    History { event: String },
+    Latest,
+    // no events resets all
+    Reset { events: Vec<String> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SlowlogSubcommand {
+    // None returns all entries
+    Get { count: Option<usize> },
+    Len,
+    Reset,
 }
 
 impl Command {
@@ -32,20 +257,62 @@ impl Command {
             Self::Echo(message) => {
                 vec![Message::BulkString("ECHO".to_string()), message.clone()]
             }
+            Self::Hello { protover, auth } => {
+                let mut messages = vec![Message::BulkString("HELLO".to_string())];
+                if let Some(protover) = protover {
+                    messages.push(Message::BulkString(protover.to_string()));
+                }
+                if let Some((username, password)) = auth {
+                    messages.push(Message::BulkString("AUTH".to_string()));
+                    messages.push(Message::BulkString(username.clone()));
+                    messages.push(Message::BulkString(password.clone()));
+                }
+                messages
+            }
             Self::Get { key } => vec![Message::BulkString("GET".to_string()), key.clone()],
+            Self::GetRange { key, start, end } => vec![
+                Message::BulkString("GETRANGE".to_string()),
+                key.clone(),
+                Message::BulkString(start.to_string()),
+                Message::BulkString(end.to_string()),
+            ],
             Self::Set {
                 key,
                 value,
-                expire_time,
+                expiry,
+                condition,
+                get,
             } => {
                 let mut set_messages = vec![
                     Message::BulkString("SET".to_string()),
                     key.clone(),
                     value.clone(),
                 ];
-                if let Some(time) = expire_time {
-                    set_messages.push(Message::BulkString("SET".to_string()));
-                    set_messages.push(Message::BulkString(time.to_string()));
+                match expiry {
+                    Some(SetExpiry::Milliseconds(millis)) => {
+                        set_messages.push(Message::BulkString("PX".to_string()));
+                        set_messages.push(Message::BulkString(millis.to_string()));
+                    }
+                    Some(SetExpiry::UnixMilliseconds(millis)) => {
+                        set_messages.push(Message::BulkString("PXAT".to_string()));
+                        set_messages.push(Message::BulkString(millis.to_string()));
+                    }
+                    Some(SetExpiry::KeepTtl) => {
+                        set_messages.push(Message::BulkString("KEEPTTL".to_string()))
+                    }
+                    None => {}
+                }
+                match condition {
+                    SetCondition::IfNotExists => {
+                        set_messages.push(Message::BulkString("NX".to_string()))
+                    }
+                    SetCondition::IfExists => {
+                        set_messages.push(Message::BulkString("XX".to_string()))
+                    }
+                    SetCondition::Always => {}
+                }
+                if *get {
+                    set_messages.push(Message::BulkString("GET".to_string()));
                 }
 
                 set_messages
@@ -53,7 +320,323 @@ impl Command {
             Self::Replconf { .. } => unimplemented!(),
             Self::Psync => unimplemented!(),
             Self::Info { .. } => unimplemented!(),
-            Self::Wait => vec![Message::BulkString("WAIT".to_string())],
+            Self::Wait {
+                numreplicas,
+                timeout,
+            } => vec![
+                Message::BulkString("WAIT".to_string()),
+                Message::BulkString(numreplicas.to_string()),
+                Message::BulkString(timeout.map_or(0, |timeout| timeout.as_millis()).to_string()),
+            ],
+            Self::Docs { names } => {
+                let mut messages = vec![
+                    Message::BulkString("COMMAND".to_string()),
+                    Message::BulkString("DOCS".to_string()),
+                ];
+                messages.extend(names.iter().map(|name| Message::BulkString(name.clone())));
+                messages
+            }
+            Self::Cluster { subcommand } => vec![
+                Message::BulkString("CLUSTER".to_string()),
+                Message::BulkString(subcommand.clone()),
+            ],
+            Self::Acl { subcommand } => vec![
+                Message::BulkString("ACL".to_string()),
+                Message::BulkString(subcommand.clone()),
+            ],
+            Self::Latency { subcommand } => {
+                let mut messages = vec![Message::BulkString("LATENCY".to_string())];
+                match subcommand {
+                    LatencySubcommand::History { event } => {
+                        messages.push(Message::BulkString("HISTORY".to_string()));
+                        messages.push(Message::BulkString(event.clone()));
+                    }
+                    LatencySubcommand::Latest => {
+                        messages.push(Message::BulkString("LATEST".to_string()))
+                    }
+                    LatencySubcommand::Reset { events } => {
+                        messages.push(Message::BulkString("RESET".to_string()));
+                        messages.extend(
+                            events
+                                .iter()
+                                .map(|event| Message::BulkString(event.clone())),
+                        );
+                    }
+                }
+                messages
+            }
+            Self::Slowlog { subcommand } => {
+                let mut messages = vec![Message::BulkString("SLOWLOG".to_string())];
+                match subcommand {
+                    SlowlogSubcommand::Get { count } => {
+                        messages.push(Message::BulkString("GET".to_string()));
+                        let count = count.map_or(-1, |count| count as i64);
+                        messages.push(Message::BulkString(count.to_string()));
+                    }
+                    SlowlogSubcommand::Len => messages.push(Message::BulkString("LEN".to_string())),
+                    SlowlogSubcommand::Reset => {
+                        messages.push(Message::BulkString("RESET".to_string()))
+                    }
+                }
+                messages
+            }
+            Self::Config { subcommand } => {
+                let mut messages = vec![Message::BulkString("CONFIG".to_string())];
+                match subcommand {
+                    ConfigSubcommand::Get { patterns } => {
+                        messages.push(Message::BulkString("GET".to_string()));
+                        messages.extend(
+                            patterns
+                                .iter()
+                                .map(|pattern| Message::BulkString(pattern.clone())),
+                        );
+                    }
+                    ConfigSubcommand::Set { name, value } => {
+                        messages.push(Message::BulkString("SET".to_string()));
+                        messages.push(Message::BulkString(name.clone()));
+                        messages.push(Message::BulkString(value.clone()));
+                    }
+                }
+                messages
+            }
+            Self::Function { subcommand } => vec![
+                Message::BulkString("FUNCTION".to_string()),
+                Message::BulkString(subcommand.clone()),
+            ],
+            Self::RandomKey => vec![Message::BulkString("RANDOMKEY".to_string())],
+            Self::Subscribe { channels } => {
+                let mut messages = vec![Message::BulkString("SUBSCRIBE".to_string())];
+                messages.extend(
+                    channels
+                        .iter()
+                        .map(|channel| Message::BulkString(channel.clone())),
+                );
+                messages
+            }
+            Self::Unsubscribe { channels } => {
+                let mut messages = vec![Message::BulkString("UNSUBSCRIBE".to_string())];
+                messages.extend(
+                    channels
+                        .iter()
+                        .map(|channel| Message::BulkString(channel.clone())),
+                );
+                messages
+            }
+            Self::PSubscribe { patterns } => {
+                let mut messages = vec![Message::BulkString("PSUBSCRIBE".to_string())];
+                messages.extend(
+                    patterns
+                        .iter()
+                        .map(|pattern| Message::BulkString(pattern.clone())),
+                );
+                messages
+            }
+            Self::PUnsubscribe { patterns } => {
+                let mut messages = vec![Message::BulkString("PUNSUBSCRIBE".to_string())];
+                messages.extend(
+                    patterns
+                        .iter()
+                        .map(|pattern| Message::BulkString(pattern.clone())),
+                );
+                messages
+            }
+            Self::Publish { channel, message } => vec![
+                Message::BulkString("PUBLISH".to_string()),
+                Message::BulkString(channel.clone()),
+                message.clone(),
+            ],
+            Self::DbSize => vec![Message::BulkString("DBSIZE".to_string())],
+            Self::Multi => vec![Message::BulkString("MULTI".to_string())],
+            Self::Exec => vec![Message::BulkString("EXEC".to_string())],
+            Self::Discard => vec![Message::BulkString("DISCARD".to_string())],
+            Self::Watch { keys } => {
+                let mut messages = vec![Message::BulkString("WATCH".to_string())];
+                messages.extend(keys.iter().cloned());
+                messages
+            }
+            Self::Unwatch => vec![Message::BulkString("UNWATCH".to_string())],
+            Self::FlushAll => vec![Message::BulkString("FLUSHALL".to_string())],
+            Self::Keys { pattern } => vec![
+                Message::BulkString("KEYS".to_string()),
+                Message::BulkString(pattern.clone()),
+            ],
+            Self::Scan {
+                cursor,
+                pattern,
+                count,
+            } => {
+                let mut messages = vec![
+                    Message::BulkString("SCAN".to_string()),
+                    Message::BulkString(cursor.to_string()),
+                ];
+                if let Some(pattern) = pattern {
+                    messages.push(Message::BulkString("MATCH".to_string()));
+                    messages.push(Message::BulkString(pattern.clone()));
+                }
+                messages.push(Message::BulkString("COUNT".to_string()));
+                messages.push(Message::BulkString(count.to_string()));
+                messages
+            }
+            Self::Exists { keys } => {
+                let mut messages = vec![Message::BulkString("EXISTS".to_string())];
+                messages.extend(keys.iter().cloned());
+                messages
+            }
+            Self::Del { keys } => {
+                let mut messages = vec![Message::BulkString("DEL".to_string())];
+                messages.extend(keys.iter().cloned());
+                messages
+            }
+            Self::DebugSleep { duration, global } => {
+                let mut messages = vec![
+                    Message::BulkString("DEBUG".to_string()),
+                    Message::BulkString("SLEEP".to_string()),
+                    Message::BulkString(duration.as_secs_f64().to_string()),
+                ];
+                if *global {
+                    messages.push(Message::BulkString("GLOBAL".to_string()));
+                }
+                messages
+            }
+            Self::Incr { key } => vec![Message::BulkString("INCR".to_string()), key.clone()],
+            Self::Decr { key } => vec![Message::BulkString("DECR".to_string()), key.clone()],
+            Self::IncrBy { key, increment } => vec![
+                Message::BulkString("INCRBY".to_string()),
+                key.clone(),
+                Message::BulkString(increment.to_string()),
+            ],
+            Self::DecrBy { key, decrement } => vec![
+                Message::BulkString("DECRBY".to_string()),
+                key.clone(),
+                Message::BulkString(decrement.to_string()),
+            ],
+            Self::IncrByFloat { key, increment } => vec![
+                Message::BulkString("INCRBYFLOAT".to_string()),
+                key.clone(),
+                Message::BulkString(increment.to_string()),
+            ],
+            Self::Ttl { key } => vec![Message::BulkString("TTL".to_string()), key.clone()],
+            Self::Pttl { key } => vec![Message::BulkString("PTTL".to_string()), key.clone()],
+            Self::Expire { key, milliseconds } => vec![
+                Message::BulkString("PEXPIRE".to_string()),
+                key.clone(),
+                Message::BulkString(milliseconds.to_string()),
+            ],
+            Self::Persist { key } => vec![Message::BulkString("PERSIST".to_string()), key.clone()],
+            Self::Type { key } => vec![Message::BulkString("TYPE".to_string()), key.clone()],
+            Self::Dump { key } => vec![Message::BulkString("DUMP".to_string()), key.clone()],
+            Self::Restore {
+                key,
+                ttl,
+                payload,
+                replace,
+                absttl,
+            } => {
+                let mut command = vec![
+                    Message::BulkString("RESTORE".to_string()),
+                    key.clone(),
+                    Message::BulkString(ttl.to_string()),
+                    payload.clone(),
+                ];
+                if *replace {
+                    command.push(Message::BulkString("REPLACE".to_string()));
+                }
+                if *absttl {
+                    command.push(Message::BulkString("ABSTTL".to_string()));
+                }
+                command
+            }
+            Self::ObjectEncoding { key } => vec![
+                Message::BulkString("OBJECT".to_string()),
+                Message::BulkString("ENCODING".to_string()),
+                key.clone(),
+            ],
+            Self::LPush { key, values } => {
+                let mut messages = vec![Message::BulkString("LPUSH".to_string()), key.clone()];
+                messages.extend(values.iter().cloned());
+                messages
+            }
+            Self::RPush { key, values } => {
+                let mut messages = vec![Message::BulkString("RPUSH".to_string()), key.clone()];
+                messages.extend(values.iter().cloned());
+                messages
+            }
+            Self::LRange { key, start, stop } => vec![
+                Message::BulkString("LRANGE".to_string()),
+                key.clone(),
+                Message::BulkString(start.to_string()),
+                Message::BulkString(stop.to_string()),
+            ],
+            Self::LLen { key } => vec![Message::BulkString("LLEN".to_string()), key.clone()],
+            Self::LPop { key, count } => pop_messages("LPOP", key, count),
+            Self::RPop { key, count } => pop_messages("RPOP", key, count),
+            Self::HSet { key, fields } => {
+                let mut messages = vec![Message::BulkString("HSET".to_string()), key.clone()];
+                for (field, value) in fields {
+                    messages.push(Message::BulkString(field.clone()));
+                    messages.push(value.clone());
+                }
+                messages
+            }
+            Self::HGet { key, field } => vec![
+                Message::BulkString("HGET".to_string()),
+                key.clone(),
+                Message::BulkString(field.clone()),
+            ],
+            Self::HGetAll { key } => {
+                vec![Message::BulkString("HGETALL".to_string()), key.clone()]
+            }
+            Self::HDel { key, fields } => {
+                let mut messages = vec![Message::BulkString("HDEL".to_string()), key.clone()];
+                messages.extend(
+                    fields
+                        .iter()
+                        .map(|field| Message::BulkString(field.clone())),
+                );
+                messages
+            }
+            Self::SAdd { key, members } => {
+                let mut messages = vec![Message::BulkString("SADD".to_string()), key.clone()];
+                messages.extend(members.iter().cloned());
+                messages
+            }
+            Self::SMembers { key } => {
+                vec![Message::BulkString("SMEMBERS".to_string()), key.clone()]
+            }
+            Self::SetStore {
+                operation,
+                destination,
+                keys,
+            } => {
+                let name = match operation {
+                    SetOperation::Intersection => "SINTERSTORE",
+                    SetOperation::Union => "SUNIONSTORE",
+                    SetOperation::Difference => "SDIFFSTORE",
+                };
+                let mut messages = vec![Message::BulkString(name.to_string()), destination.clone()];
+                messages.extend(keys.iter().cloned());
+                messages
+            }
+            Self::SetNx { key, value } => vec![
+                Message::BulkString("SETNX".to_string()),
+                key.clone(),
+                value.clone(),
+            ],
+            Self::SetEx {
+                key,
+                seconds,
+                value,
+            } => vec![
+                Message::BulkString("SETEX".to_string()),
+                key.clone(),
+                Message::BulkString(seconds.to_string()),
+                value.clone(),
+            ],
+            Self::Append { key, value } => vec![
+                Message::BulkString("APPEND".to_string()),
+                key.clone(),
+                value.clone(),
+            ],
         };
 
         Message::Array(inner)
@@ -63,6 +646,77 @@ impl Command {
         Message::Array(vec![Message::BulkString("PING".to_string())])
     }
 
+    // Whether the command can change the keyspace, only those are sent to
+    // the replicas. New commands have to be added here explicitly.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Self::Set { .. }
+            | Self::SetNx { .. }
+            | Self::SetEx { .. }
+            | Self::FlushAll
+            | Self::Del { .. }
+            | Self::Incr { .. }
+            | Self::Decr { .. }
+            | Self::IncrBy { .. }
+            | Self::DecrBy { .. }
+            | Self::IncrByFloat { .. }
+            | Self::Append { .. }
+            | Self::Expire { .. }
+            | Self::Persist { .. }
+            | Self::Restore { .. }
+            | Self::LPush { .. }
+            | Self::RPush { .. }
+            | Self::LPop { .. }
+            | Self::RPop { .. }
+            | Self::HSet { .. }
+            | Self::HDel { .. }
+            | Self::SAdd { .. }
+            | Self::SetStore { .. } => true,
+            Self::Ping
+            | Self::Echo(_)
+            | Self::Hello { .. }
+            | Self::Get { .. }
+            | Self::GetRange { .. }
+            | Self::Info { .. }
+            | Self::Replconf { .. }
+            | Self::Psync
+            | Self::Wait { .. }
+            | Self::Docs { .. }
+            | Self::Cluster { .. }
+            | Self::Acl { .. }
+            | Self::Config { .. }
+            | Self::Slowlog { .. }
+            | Self::Latency { .. }
+            | Self::Function { .. }
+            | Self::RandomKey
+            | Self::Subscribe { .. }
+            | Self::Unsubscribe { .. }
+            | Self::PSubscribe { .. }
+            | Self::PUnsubscribe { .. }
+            | Self::Publish { .. }
+            | Self::DbSize
+            | Self::Multi
+            | Self::Exec
+            | Self::Discard
+            | Self::Watch { .. }
+            | Self::Unwatch
+            | Self::Keys { .. }
+            | Self::Scan { .. }
+            | Self::Exists { .. }
+            | Self::DebugSleep { .. }
+            | Self::Ttl { .. }
+            | Self::Pttl { .. }
+            | Self::Type { .. }
+            | Self::ObjectEncoding { .. }
+            | Self::Dump { .. }
+            | Self::LRange { .. }
+            | Self::LLen { .. }
+            | Self::HGet { .. }
+            | Self::HGetAll { .. }
+            | Self::SMembers { .. } => false,
+        }
+    }
+
     pub fn get_replconf_command<T1: ToString, T2: ToString>(name: T1, value: T2) -> Message {
         Message::Array(vec![
             Message::BulkString("REPLCONF".to_string()),
@@ -80,9 +734,17 @@ impl Command {
     }
 }
 
+fn pop_messages(name: &str, key: &Message, count: &Option<usize>) -> Vec<Message> {
+    let mut messages = vec![Message::BulkString(name.to_string()), key.clone()];
+    if let Some(count) = count {
+        messages.push(Message::BulkString(count.to_string()));
+    }
+    messages
+}
+
 pub fn parse_command(message: &Message) -> Result<Command> {
     match message {
-        Message::Array(vec) if vec.len() > 0 => handle_array(vec),
+        Message::Array(vec) if !vec.is_empty() => handle_array(vec),
         _ => bail!("unknown message {} for command", message),
     }
 }
@@ -92,66 +754,739 @@ fn handle_array(messages: &[Message]) -> Result<Command> {
         .first()
         .context("at least one message must exist")?;
     if let Message::BulkString(command_string) = command_message {
+        // checked up front, so the commands below can rely on their arguments
+        if let Some(doc) = find_command(command_string) {
+            if !doc.accepts_len(messages.len()) {
+                bail!(wrong_arguments(doc.name));
+            }
+        }
+
         match command_string.to_uppercase().as_str() {
             "PING" => Ok(Command::Ping),
-            "ECHO" => Ok(Command::Echo(messages[1].clone())),
-            "SET" => {
-                let key = messages[1].clone();
-                let value = messages[2].clone();
-                let expire_time = get_expire_time(messages)?;
-                Ok(Command::Set {
+            "ECHO" => Ok(Command::Echo(single_argument("echo", messages)?)),
+            "SET" => parse_set(messages),
+            "HELLO" => parse_hello(&messages[1..]),
+            "SETNX" => match messages {
+                [_, key, value] => Ok(Command::SetNx {
+                    key: key.clone(),
+                    value: value.clone(),
+                }),
+                _ => bail!(wrong_arguments("setnx")),
+            },
+            "SETEX" => match messages {
+                [_, key, seconds, value] => {
+                    let seconds = parse_integer_argument(seconds)?;
+                    let in_range = seconds
+                        .checked_mul(1000)
+                        .is_some_and(|millis| expire_date_from_now(millis).is_ok());
+                    if seconds <= 0 || !in_range {
+                        bail!("invalid expire time in 'setex' command");
+                    }
+                    Ok(Command::SetEx {
+                        key: key.clone(),
+                        seconds,
+                        value: value.clone(),
+                    })
+                }
+                _ => bail!(wrong_arguments("setex")),
+            },
+            "GET" => Ok(Command::Get {
+                key: single_argument("get", messages)?,
+            }),
+            "GETRANGE" => match messages {
+                [_, key, start, end] => Ok(Command::GetRange {
+                    key: key.clone(),
+                    start: parse_integer_argument(start)?,
+                    end: parse_integer_argument(end)?,
+                }),
+                _ => bail!(wrong_arguments("getrange")),
+            },
+            "INFO" => Ok(Command::Info {
+                sections: messages[1..].to_vec(),
+            }),
+            "REPLCONF" => match messages {
+                [_, Message::BulkString(name), value, ..] => Ok(Command::Replconf {
+                    name: name.clone(),
+                    value: value.clone(),
+                }),
+                [_, _, _, ..] => bail!("First part of replconf should be bulk string"),
+                _ => bail!(wrong_arguments("replconf")),
+            },
+            "PSYNC" => Ok(Command::Psync),
+            "WAIT" => match messages {
+                [_, numreplicas, timeout] => {
+                    let numreplicas = parse_integer_argument(numreplicas)?;
+                    let timeout = parse_integer_argument(timeout)?;
+                    if timeout < 0 {
+                        bail!("timeout is negative");
+                    }
+                    Ok(Command::Wait {
+                        numreplicas: numreplicas.max(0) as usize,
+                        timeout: (timeout > 0).then(|| Duration::from_millis(timeout as u64)),
+                    })
+                }
+                _ => bail!(wrong_arguments("wait")),
+            },
+            "COMMAND" => parse_command_subcommand(&messages[1..]),
+            "CLUSTER" => match messages.get(1) {
+                Some(Message::BulkString(subcommand)) => Ok(Command::Cluster {
+                    subcommand: subcommand.to_uppercase(),
+                }),
+                _ => bail!(wrong_arguments("cluster")),
+            },
+            "CONFIG" => parse_config(&messages[1..]),
+            "SLOWLOG" => parse_slowlog(&messages[1..]),
+            "LATENCY" => parse_latency(&messages[1..]),
+            "ACL" => match messages.get(1) {
+                Some(Message::BulkString(subcommand)) => Ok(Command::Acl {
+                    subcommand: subcommand.to_uppercase(),
+                }),
+                _ => bail!(wrong_arguments("acl")),
+            },
+            "FUNCTION" => match messages.get(1) {
+                Some(Message::BulkString(subcommand)) => Ok(Command::Function {
+                    subcommand: subcommand.to_uppercase(),
+                }),
+                _ => bail!(wrong_arguments("function")),
+            },
+            "RANDOMKEY" => Ok(Command::RandomKey),
+            "SUBSCRIBE" => {
+                if messages.len() < 2 {
+                    bail!(wrong_arguments("subscribe"));
+                }
+                Ok(Command::Subscribe {
+                    channels: parse_channels(&messages[1..])?,
+                })
+            }
+            "UNSUBSCRIBE" => Ok(Command::Unsubscribe {
+                channels: parse_channels(&messages[1..])?,
+            }),
+            "PSUBSCRIBE" => {
+                if messages.len() < 2 {
+                    bail!(wrong_arguments("psubscribe"));
+                }
+                Ok(Command::PSubscribe {
+                    patterns: parse_channels(&messages[1..])?,
+                })
+            }
+            "PUNSUBSCRIBE" => Ok(Command::PUnsubscribe {
+                patterns: parse_channels(&messages[1..])?,
+            }),
+            "PUBLISH" => match messages {
+                [_, Message::BulkString(channel), message] => Ok(Command::Publish {
+                    channel: channel.clone(),
+                    message: message.clone(),
+                }),
+                _ => bail!(wrong_arguments("publish")),
+            },
+            "DBSIZE" => Ok(Command::DbSize),
+            "MULTI" => Ok(Command::Multi),
+            "EXEC" => Ok(Command::Exec),
+            "DISCARD" => Ok(Command::Discard),
+            "WATCH" => Ok(Command::Watch {
+                keys: messages[1..].to_vec(),
+            }),
+            "UNWATCH" => Ok(Command::Unwatch),
+            "FLUSHALL" => Ok(Command::FlushAll),
+            "KEYS" => match messages {
+                [_, Message::BulkString(pattern)] => Ok(Command::Keys {
+                    pattern: pattern.clone(),
+                }),
+                _ => bail!(wrong_arguments("keys")),
+            },
+            "DEBUG" => parse_debug(&messages[1..]),
+            "SCAN" => parse_scan(&messages[1..]),
+            "EXISTS" => {
+                if messages.len() < 2 {
+                    bail!(wrong_arguments("exists"));
+                }
+                Ok(Command::Exists {
+                    keys: messages[1..].to_vec(),
+                })
+            }
+            "DEL" | "UNLINK" => {
+                if messages.len() < 2 {
+                    bail!(wrong_arguments(&command_string.to_lowercase()));
+                }
+                Ok(Command::Del {
+                    keys: messages[1..].to_vec(),
+                })
+            }
+            "INCR" => Ok(Command::Incr {
+                key: single_argument("incr", messages)?,
+            }),
+            "DECR" => Ok(Command::Decr {
+                key: single_argument("decr", messages)?,
+            }),
+            "INCRBY" => {
+                let (key, increment) = parse_key_and_integer("incrby", messages)?;
+                Ok(Command::IncrBy { key, increment })
+            }
+            "DECRBY" => {
+                let (key, decrement) = parse_key_and_integer("decrby", messages)?;
+                if decrement == i64::MIN {
+                    bail!("decrement would overflow");
+                }
+                Ok(Command::DecrBy { key, decrement })
+            }
+            "INCRBYFLOAT" => match messages {
+                [_, key, Message::BulkString(increment)] => Ok(Command::IncrByFloat {
+                    key: key.clone(),
+                    increment: increment
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|increment| increment.is_finite())
+                        .context("value is not a valid float")?,
+                }),
+                _ => bail!(wrong_arguments("incrbyfloat")),
+            },
+            "TTL" => Ok(Command::Ttl {
+                key: single_argument("ttl", messages)?,
+            }),
+            "PTTL" => Ok(Command::Pttl {
+                key: single_argument("pttl", messages)?,
+            }),
+            "EXPIRE" => {
+                let (key, seconds) = parse_key_and_integer("expire", messages)?;
+                Ok(Command::Expire {
                     key,
-                    value,
-                    expire_time,
+                    milliseconds: seconds
+                        .checked_mul(1000)
+                        .context("invalid expire time in 'expire' command")?,
                 })
             }
-            "GET" => Ok(Command::Get {
-                key: messages[1].clone(),
+            "PEXPIRE" => {
+                let (key, milliseconds) = parse_key_and_integer("pexpire", messages)?;
+                Ok(Command::Expire { key, milliseconds })
+            }
+            "PERSIST" => Ok(Command::Persist {
+                key: single_argument("persist", messages)?,
+            }),
+            "TYPE" => Ok(Command::Type {
+                key: single_argument("type", messages)?,
             }),
-            "INFO" => match messages.get(1) {
-                Some(ele) => Ok(Command::Info {
-                    sections: vec![ele.clone()],
+            "DUMP" => Ok(Command::Dump {
+                key: single_argument("dump", messages)?,
+            }),
+            "RESTORE" => parse_restore(messages),
+            "OBJECT" => match messages {
+                [_, Message::BulkString(subcommand), key]
+                    if subcommand.to_uppercase() == "ENCODING" =>
+                {
+                    Ok(Command::ObjectEncoding { key: key.clone() })
+                }
+                [_, Message::BulkString(subcommand), ..]
+                    if subcommand.to_uppercase() == "ENCODING" =>
+                {
+                    bail!(wrong_arguments("object|encoding"))
+                }
+                [_, subcommand, ..] => {
+                    bail!("unknown subcommand '{}'. Try OBJECT HELP.", subcommand)
+                }
+                _ => bail!(wrong_arguments("object")),
+            },
+            "LPUSH" | "RPUSH" => {
+                if messages.len() < 3 {
+                    bail!(wrong_arguments(&command_string.to_lowercase()));
+                }
+                let key = messages[1].clone();
+                let values = messages[2..].to_vec();
+                if command_string.to_uppercase() == "LPUSH" {
+                    Ok(Command::LPush { key, values })
+                } else {
+                    Ok(Command::RPush { key, values })
+                }
+            }
+            "LRANGE" => match messages {
+                [_, key, start, stop] => Ok(Command::LRange {
+                    key: key.clone(),
+                    start: parse_integer_argument(start)?,
+                    stop: parse_integer_argument(stop)?,
                 }),
-                None => Ok(Command::Info { sections: vec![] }),
+                _ => bail!(wrong_arguments("lrange")),
             },
-            "REPLCONF" => {
-                if let Message::BulkString(name) = messages[1].clone() {
-                    Ok(Command::Replconf {
-                        name,
-                        value: messages[2].clone(),
-                    })
+            "LPOP" | "RPOP" => {
+                let (key, count) = match messages {
+                    [_, key] => (key.clone(), None),
+                    [_, key, count] => {
+                        let count = usize::try_from(parse_integer_argument(count)?)
+                            .context("value is out of range, must be positive")?;
+                        (key.clone(), Some(count))
+                    }
+                    _ => bail!(wrong_arguments(&command_string.to_lowercase())),
+                };
+                if command_string.to_uppercase() == "LPOP" {
+                    Ok(Command::LPop { key, count })
                 } else {
-                    bail!("First part of replconf should be bulk string");
+                    Ok(Command::RPop { key, count })
                 }
             }
-            "PSYNC" => Ok(Command::Psync),
-            "WAIT" => Ok(Command::Wait),
-            _ => bail!("unknown command {}", command_string),
+            "HSET" => {
+                if messages.len() < 4 || !messages.len().is_multiple_of(2) {
+                    bail!(wrong_arguments("hset"));
+                }
+                let fields = messages[2..]
+                    .chunks(2)
+                    .map(|pair| Ok((parse_field(&pair[0])?, pair[1].clone())))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Command::HSet {
+                    key: messages[1].clone(),
+                    fields,
+                })
+            }
+            "HGET" => match messages {
+                [_, key, field] => Ok(Command::HGet {
+                    key: key.clone(),
+                    field: parse_field(field)?,
+                }),
+                _ => bail!(wrong_arguments("hget")),
+            },
+            "HGETALL" => Ok(Command::HGetAll {
+                key: single_argument("hgetall", messages)?,
+            }),
+            "HDEL" => {
+                if messages.len() < 3 {
+                    bail!(wrong_arguments("hdel"));
+                }
+                Ok(Command::HDel {
+                    key: messages[1].clone(),
+                    fields: messages[2..]
+                        .iter()
+                        .map(parse_field)
+                        .collect::<Result<Vec<_>>>()?,
+                })
+            }
+            "SADD" => {
+                if messages.len() < 3 {
+                    bail!(wrong_arguments("sadd"));
+                }
+                Ok(Command::SAdd {
+                    key: messages[1].clone(),
+                    members: messages[2..].to_vec(),
+                })
+            }
+            "SMEMBERS" => Ok(Command::SMembers {
+                key: single_argument("smembers", messages)?,
+            }),
+            "SINTERSTORE" => parse_set_store(SetOperation::Intersection, "sinterstore", messages),
+            "SUNIONSTORE" => parse_set_store(SetOperation::Union, "sunionstore", messages),
+            "SDIFFSTORE" => parse_set_store(SetOperation::Difference, "sdiffstore", messages),
+            "LLEN" => Ok(Command::LLen {
+                key: single_argument("llen", messages)?,
+            }),
+            "APPEND" => match messages {
+                [_, key, value @ (Message::BulkString(_) | Message::BulkBytes(_))] => {
+                    Ok(Command::Append {
+                        key: key.clone(),
+                        value: value.clone(),
+                    })
+                }
+                _ => bail!(wrong_arguments("append")),
+            },
+            _ => bail!(unknown_command(command_string, &messages[1..])),
         }
     } else {
         bail!("unknown command type {}", command_message);
     }
 }
 
-fn get_expire_time(messages: &[Message]) -> Result<Option<i64>> {
-    match messages.get(3) {
-        Some(_) => {
-            let time = messages[4].clone();
-            match time {
-                Message::BulkString(value) => Ok(Some(value.parse::<i64>().unwrap())),
-                m => bail!("unknown message for expire_time {}", m),
-            }
+// The argument preview of the unknown command error is cut like redis
+// does, clients match on this exact format.
+const UNKNOWN_COMMAND_PREVIEW_LEN: usize = 128;
+
+fn unknown_command(name: &str, args: &[Message]) -> String {
+    let mut preview = String::new();
+    for arg in args {
+        let remaining = UNKNOWN_COMMAND_PREVIEW_LEN.saturating_sub(preview.chars().count());
+        if remaining == 0 {
+            break;
         }
-        None => Ok(None),
+        let arg = match arg {
+            Message::BulkString(value) | Message::SimpleString(value) => value.clone(),
+            m => m.to_string(),
+        };
+        preview.push_str(&format!(
+            "'{}' ",
+            arg.chars().take(remaining).collect::<String>()
+        ));
     }
+
+    format!(
+        "unknown command '{}', with args beginning with: {}",
+        name.chars()
+            .take(UNKNOWN_COMMAND_PREVIEW_LEN)
+            .collect::<String>(),
+        preview
+    )
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn parse_set_store(operation: SetOperation, name: &str, messages: &[Message]) -> Result<Command> {
+    if messages.len() < 3 {
+        bail!(wrong_arguments(name));
+    }
+    Ok(Command::SetStore {
+        operation,
+        destination: messages[1].clone(),
+        keys: messages[2..].to_vec(),
+    })
+}
 
-    fn get_set_message(
-        key: &str,
+fn wrong_arguments(name: &str) -> String {
+    format!("wrong number of arguments for '{}' command", name)
+}
+
+// The only argument of commands like GET, given as `[name, argument]`.
+fn single_argument(name: &str, messages: &[Message]) -> Result<Message> {
+    match messages {
+        [_, argument] => Ok(argument.clone()),
+        _ => bail!(wrong_arguments(name)),
+    }
+}
+
+fn parse_integer_argument(message: &Message) -> Result<i64> {
+    match message {
+        Message::BulkString(value) => value
+            .parse::<i64>()
+            .context("value is not an integer or out of range"),
+        _ => bail!("value is not an integer or out of range"),
+    }
+}
+
+fn parse_hello(messages: &[Message]) -> Result<Command> {
+    let (protover, mut options) = match messages.first() {
+        Some(Message::BulkString(protover)) if protover.to_uppercase() != "AUTH" => {
+            let protover = protover
+                .parse::<u8>()
+                .context("Protocol version is not an integer or out of range")?;
+            (Some(protover), messages[1..].iter())
+        }
+        _ => (None, messages.iter()),
+    };
+
+    let mut auth = None;
+    while let Some(option) = options.next() {
+        match (option, options.next(), options.next()) {
+            (
+                Message::BulkString(option),
+                Some(Message::BulkString(username)),
+                Some(Message::BulkString(password)),
+            ) if option.to_uppercase() == "AUTH" => {
+                auth = Some((username.clone(), password.clone()));
+            }
+            _ => bail!("syntax error in HELLO option"),
+        }
+    }
+
+    Ok(Command::Hello { protover, auth })
+}
+
+fn parse_config(messages: &[Message]) -> Result<Command> {
+    let arguments = messages
+        .iter()
+        .map(|message| match message {
+            Message::BulkString(argument) => Ok(argument.clone()),
+            m => bail!("unknown message for CONFIG argument {}", m),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let subcommand = match arguments.as_slice() {
+        [subcommand, patterns @ ..]
+            if subcommand.to_uppercase() == "GET" && !patterns.is_empty() =>
+        {
+            ConfigSubcommand::Get {
+                patterns: patterns.to_vec(),
+            }
+        }
+        [subcommand, name, value] if subcommand.to_uppercase() == "SET" => ConfigSubcommand::Set {
+            name: name.clone(),
+            value: value.clone(),
+        },
+        [subcommand, ..] if ["GET", "SET"].contains(&subcommand.to_uppercase().as_str()) => {
+            bail!(wrong_arguments(&format!(
+                "config|{}",
+                subcommand.to_lowercase()
+            )))
+        }
+        [subcommand, ..] => bail!("unknown subcommand '{}'. Try CONFIG HELP.", subcommand),
+        [] => bail!(wrong_arguments("config")),
+    };
+    Ok(Command::Config { subcommand })
+}
+
+fn parse_latency(messages: &[Message]) -> Result<Command> {
+    let arguments = messages
+        .iter()
+        .map(|message| match message {
+            Message::BulkString(argument) => Ok(argument.clone()),
+            m => bail!("unknown message for LATENCY argument {}", m),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let subcommand = match arguments.as_slice() {
+        [subcommand, rest @ ..] => match (subcommand.to_uppercase().as_str(), rest) {
+            ("HISTORY", [event]) => LatencySubcommand::History {
+                event: event.to_lowercase(),
+            },
+            ("LATEST", []) => LatencySubcommand::Latest,
+            ("RESET", events) => LatencySubcommand::Reset {
+                events: events.iter().map(|event| event.to_lowercase()).collect(),
+            },
+            ("HISTORY" | "LATEST", _) => {
+                bail!(wrong_arguments(&format!(
+                    "latency|{}",
+                    subcommand.to_lowercase()
+                )))
+            }
+            _ => bail!("unknown subcommand '{}'. Try LATENCY HELP.", subcommand),
+        },
+        [] => bail!(wrong_arguments("latency")),
+    };
+    Ok(Command::Latency { subcommand })
+}
+
+const SLOWLOG_DEFAULT_COUNT: usize = 10;
+
+fn parse_slowlog(messages: &[Message]) -> Result<Command> {
+    let subcommand = match messages {
+        [Message::BulkString(subcommand), rest @ ..] => {
+            match (subcommand.to_uppercase().as_str(), rest) {
+                ("GET", []) => SlowlogSubcommand::Get {
+                    count: Some(SLOWLOG_DEFAULT_COUNT),
+                },
+                ("GET", [count]) => {
+                    let count = parse_integer_argument(count)?;
+                    if count < -1 {
+                        bail!("count should be greater than or equal to -1");
+                    }
+                    SlowlogSubcommand::Get {
+                        count: usize::try_from(count).ok(),
+                    }
+                }
+                ("LEN", []) => SlowlogSubcommand::Len,
+                ("RESET", []) => SlowlogSubcommand::Reset,
+                ("GET" | "LEN" | "RESET", _) => {
+                    bail!(wrong_arguments(&format!(
+                        "slowlog|{}",
+                        subcommand.to_lowercase()
+                    )))
+                }
+                _ => bail!("unknown subcommand '{}'. Try SLOWLOG HELP.", subcommand),
+            }
+        }
+        _ => bail!(wrong_arguments("slowlog")),
+    };
+    Ok(Command::Slowlog { subcommand })
+}
+
+const SCAN_DEFAULT_COUNT: usize = 10;
+
+fn parse_scan(messages: &[Message]) -> Result<Command> {
+    let cursor = match messages.first() {
+        Some(Message::BulkString(cursor)) => cursor.parse::<u64>().context("invalid cursor")?,
+        _ => bail!(wrong_arguments("scan")),
+    };
+
+    let mut pattern = None;
+    let mut count = SCAN_DEFAULT_COUNT;
+    let mut options = messages[1..].iter();
+    while let Some(option) = options.next() {
+        let Message::BulkString(option) = option else {
+            bail!("syntax error");
+        };
+        match option.to_uppercase().as_str() {
+            "MATCH" => match options.next() {
+                Some(Message::BulkString(value)) => pattern = Some(value.clone()),
+                _ => bail!("syntax error"),
+            },
+            "COUNT" => {
+                let value = parse_integer_argument(options.next().context("syntax error")?)?;
+                if value < 1 {
+                    bail!("syntax error");
+                }
+                count = value as usize;
+            }
+            _ => bail!("syntax error"),
+        }
+    }
+
+    Ok(Command::Scan {
+        cursor,
+        pattern,
+        count,
+    })
+}
+
+fn parse_channels(messages: &[Message]) -> Result<Vec<String>> {
+    messages
+        .iter()
+        .map(|message| match message {
+            Message::BulkString(channel) => Ok(channel.clone()),
+            m => bail!("unknown message for channel {}", m),
+        })
+        .collect()
+}
+
+fn parse_field(message: &Message) -> Result<String> {
+    match message {
+        Message::BulkString(field) => Ok(field.clone()),
+        m => bail!("unknown message for hash field {}", m),
+    }
+}
+
+fn parse_key_and_integer(name: &str, messages: &[Message]) -> Result<(Message, i64)> {
+    match messages {
+        [_, key, value] => Ok((key.clone(), parse_integer_argument(value)?)),
+        _ => bail!(wrong_arguments(name)),
+    }
+}
+
+fn parse_command_subcommand(messages: &[Message]) -> Result<Command> {
+    match messages.first() {
+        Some(Message::BulkString(subcommand)) if subcommand.to_uppercase() == "DOCS" => {
+            let names = messages[1..]
+                .iter()
+                .map(|message| match message {
+                    Message::BulkString(name) => Ok(name.clone()),
+                    m => bail!("unknown message for command name {}", m),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Command::Docs { names })
+        }
+        Some(m) => bail!("unknown COMMAND subcommand {}", m),
+        None => bail!("COMMAND without subcommand not supported"),
+    }
+}
+
+fn parse_debug(messages: &[Message]) -> Result<Command> {
+    match messages {
+        [Message::BulkString(subcommand), Message::BulkString(seconds), rest @ ..]
+            if subcommand.to_uppercase() == "SLEEP" =>
+        {
+            let seconds = seconds
+                .parse::<f64>()
+                .context("DEBUG SLEEP needs a number of seconds")?;
+            if !seconds.is_finite() || seconds < 0.0 {
+                bail!("invalid DEBUG SLEEP time {}", seconds);
+            }
+
+            let global = match rest {
+                [] => false,
+                [Message::BulkString(flag)] if flag.to_uppercase() == "GLOBAL" => true,
+                _ => bail!("unknown DEBUG SLEEP arguments {:?}", rest),
+            };
+
+            Ok(Command::DebugSleep {
+                duration: Duration::from_secs_f64(seconds),
+                global,
+            })
+        }
+        _ => bail!("unknown DEBUG subcommand {:?}", messages),
+    }
+}
+
+// IDLETIME and FREQ are not supported, keys have no access statistics.
+fn parse_restore(messages: &[Message]) -> Result<Command> {
+    let [_, key, ttl, payload, options @ ..] = messages else {
+        bail!(wrong_arguments("restore"));
+    };
+    let ttl = parse_integer_argument(ttl)?;
+    if ttl < 0 {
+        bail!("Invalid TTL value, must be >= 0");
+    }
+
+    let mut replace = false;
+    let mut absttl = false;
+    for option in options {
+        match option {
+            Message::BulkString(option) if option.to_uppercase() == "REPLACE" => replace = true,
+            Message::BulkString(option) if option.to_uppercase() == "ABSTTL" => absttl = true,
+            _ => bail!("syntax error"),
+        }
+    }
+
+    Ok(Command::Restore {
+        key: key.clone(),
+        ttl,
+        payload: payload.clone(),
+        replace,
+        absttl,
+    })
+}
+
+fn parse_set(messages: &[Message]) -> Result<Command> {
+    if messages.len() < 3 {
+        bail!(wrong_arguments("set"));
+    }
+
+    let mut expiry = None;
+    let mut condition = SetCondition::Always;
+    let mut get = false;
+
+    let mut options = messages[3..].iter();
+    while let Some(option) = options.next() {
+        let Message::BulkString(option) = option else {
+            bail!("syntax error");
+        };
+
+        match option.to_uppercase().as_str() {
+            "NX" if condition == SetCondition::Always => condition = SetCondition::IfNotExists,
+            "XX" if condition == SetCondition::Always => condition = SetCondition::IfExists,
+            "GET" if !get => get = true,
+            "KEEPTTL" if expiry.is_none() => expiry = Some(SetExpiry::KeepTtl),
+            unit @ ("EX" | "PX" | "EXAT" | "PXAT") if expiry.is_none() => {
+                let time = match options.next() {
+                    Some(Message::BulkString(time)) => time
+                        .parse::<i64>()
+                        .context("value is not an integer or out of range")?,
+                    _ => bail!("syntax error"),
+                };
+                if time <= 0 {
+                    bail!("invalid expire time in 'set' command");
+                }
+
+                let millis = if unit.starts_with('E') {
+                    time.checked_mul(1000)
+                } else {
+                    Some(time)
+                };
+                // the expire date has to be representable, for EX and PX
+                // counted from now
+                expiry = match millis {
+                    Some(millis)
+                        if unit.ends_with("AT")
+                            && DateTime::from_timestamp_millis(millis).is_some() =>
+                    {
+                        Some(SetExpiry::UnixMilliseconds(millis))
+                    }
+                    Some(millis)
+                        if !unit.ends_with("AT") && expire_date_from_now(millis).is_ok() =>
+                    {
+                        Some(SetExpiry::Milliseconds(millis))
+                    }
+                    _ => bail!("invalid expire time in 'set' command"),
+                };
+            }
+            _ => bail!("syntax error"),
+        }
+    }
+
+    Ok(Command::Set {
+        key: messages[1].clone(),
+        value: messages[2].clone(),
+        expiry,
+        condition,
+        get,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command_table::COMMANDS;
+
+    use super::*;
+
+    fn get_set_message(
+        key: &str,
         value: &str,
         expire_time: Option<i64>,
     ) -> (Message, Message, Message) {
@@ -177,18 +1512,133 @@ mod tests {
         (key, value, message_set)
     }
 
+    fn set_with_options(options: &[&str]) -> Message {
+        let mut messages = vec![
+            Message::BulkString("SET".to_string()),
+            Message::BulkString("key".to_string()),
+            Message::BulkString("value".to_string()),
+        ];
+        messages.extend(
+            options
+                .iter()
+                .map(|option| Message::BulkString(option.to_string())),
+        );
+        Message::Array(messages)
+    }
+
+    fn assert_set_options(
+        options: &[&str],
+        expiry: Option<SetExpiry>,
+        condition: SetCondition,
+        get: bool,
+    ) {
+        assert_command(
+            Command::Set {
+                key: Message::BulkString("key".to_string()),
+                value: Message::BulkString("value".to_string()),
+                expiry,
+                condition,
+                get,
+            },
+            set_with_options(options),
+        );
+    }
+
+    fn assert_set_error(options: &[&str], error: &str) {
+        let result = parse_command(&set_with_options(options));
+        assert_eq!(error, result.unwrap_err().to_string());
+    }
+
     #[test]
     fn test_get_expire_time() {
         let expire_time = 100;
         if let (_, _, Message::Array(vec_messages)) =
             get_set_message("key", "val", Some(expire_time))
         {
-            assert_eq!(Some(expire_time), get_expire_time(&vec_messages).unwrap());
+            if let Command::Set { expiry, .. } = parse_set(&vec_messages).unwrap() {
+                assert_eq!(Some(SetExpiry::Milliseconds(expire_time)), expiry);
+            } else {
+                unreachable!();
+            }
         } else {
             unreachable!();
         }
     }
 
+    #[test]
+    fn test_set_expiry_options() {
+        use SetCondition::Always;
+
+        assert_set_options(
+            &["ex", "10"],
+            Some(SetExpiry::Milliseconds(10000)),
+            Always,
+            false,
+        );
+        assert_set_options(
+            &["PX", "10"],
+            Some(SetExpiry::Milliseconds(10)),
+            Always,
+            false,
+        );
+        assert_set_options(
+            &["EXAT", "1700000000"],
+            Some(SetExpiry::UnixMilliseconds(1700000000000)),
+            Always,
+            false,
+        );
+        assert_set_options(
+            &["pxat", "1700000000123"],
+            Some(SetExpiry::UnixMilliseconds(1700000000123)),
+            Always,
+            false,
+        );
+        assert_set_options(&["KEEPTTL"], Some(SetExpiry::KeepTtl), Always, false);
+    }
+
+    #[test]
+    fn test_set_condition_and_get_options() {
+        assert_set_options(&["NX"], None, SetCondition::IfNotExists, false);
+        assert_set_options(&["xx"], None, SetCondition::IfExists, false);
+        assert_set_options(&["GET"], None, SetCondition::Always, true);
+        assert_set_options(
+            &["nx", "EX", "5", "get"],
+            Some(SetExpiry::Milliseconds(5000)),
+            SetCondition::IfNotExists,
+            true,
+        );
+    }
+
+    #[test]
+    fn test_set_invalid_options() {
+        assert_set_error(&["NX", "XX"], "syntax error");
+        assert_set_error(&["EX", "10", "KEEPTTL"], "syntax error");
+        assert_set_error(&["EX", "10", "PX", "10"], "syntax error");
+        assert_set_error(&["PX"], "syntax error");
+        assert_set_error(&["UNKNOWN"], "syntax error");
+        assert_set_error(&["PX", "ten"], "value is not an integer or out of range");
+        assert_set_error(&["EX", "0"], "invalid expire time in 'set' command");
+        for unit in ["EX", "PX", "EXAT", "PXAT"] {
+            assert_set_error(
+                &[unit, &i64::MAX.to_string()],
+                "invalid expire time in 'set' command",
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_to_message_round_trip() {
+        let command = Command::Set {
+            key: Message::BulkString("key".to_string()),
+            value: Message::BulkString("value".to_string()),
+            expiry: Some(SetExpiry::UnixMilliseconds(1700000000123)),
+            condition: SetCondition::IfExists,
+            get: true,
+        };
+
+        assert_command(command.clone(), command.to_message());
+    }
+
     fn assert_command(expected_command: Command, message: Message) {
         assert_eq!(expected_command, parse_command(&message).unwrap())
     }
@@ -216,7 +1666,9 @@ mod tests {
             Command::Set {
                 key,
                 value,
-                expire_time: None,
+                expiry: None,
+                condition: SetCondition::Always,
+                get: false,
             },
             message_set,
         );
@@ -227,7 +1679,9 @@ mod tests {
             Command::Set {
                 key,
                 value,
-                expire_time: Some(123),
+                expiry: Some(SetExpiry::Milliseconds(123)),
+                condition: SetCondition::Always,
+                get: false,
             },
             message_set,
         );
@@ -240,4 +1694,727 @@ mod tests {
 
         assert_command(Command::Get { key }, message_get);
     }
+
+    #[test]
+    fn test_exists_command() {
+        let key1 = Message::BulkString("key1".to_string());
+        let key2 = Message::BulkString("key2".to_string());
+        let message = Message::Array(vec![
+            Message::BulkString("exists".to_string()),
+            key1.clone(),
+            key2.clone(),
+        ]);
+
+        assert_command(
+            Command::Exists {
+                keys: vec![key1, key2],
+            },
+            message,
+        );
+    }
+
+    #[test]
+    fn test_too_few_arguments_fail_for_every_command() {
+        for doc in COMMANDS {
+            let min_len = doc.arity.unsigned_abs() as usize;
+            if min_len < 2 {
+                continue;
+            }
+
+            let mut parts = vec![doc.name];
+            parts.resize(min_len - 1, "x");
+            assert_eq!(
+                wrong_arguments(doc.name),
+                parse_command(&message_from(&parts))
+                    .unwrap_err()
+                    .to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_write() {
+        let key = Message::BulkString("key".to_string());
+        assert!(Command::Del {
+            keys: vec![key.clone()]
+        }
+        .is_write());
+        assert!(Command::LPush {
+            key: key.clone(),
+            values: vec![key.clone()]
+        }
+        .is_write());
+        assert!(!Command::Get { key: key.clone() }.is_write());
+        assert!(!Command::Exists { keys: vec![key] }.is_write());
+    }
+
+    #[test]
+    fn test_object_encoding_command() {
+        let object = Command::ObjectEncoding {
+            key: Message::BulkString("key".to_string()),
+        };
+        assert_command(object.clone(), message_from(&["object", "encoding", "key"]));
+        assert_command(object.clone(), object.to_message());
+
+        assert!(parse_command(&message_from(&["OBJECT", "ENCODING"])).is_err());
+        assert!(parse_command(&message_from(&["OBJECT", "FREQ", "key"])).is_err());
+    }
+
+    #[test]
+    fn test_del_command() {
+        let del = Command::Del {
+            keys: vec![
+                Message::BulkString("key1".to_string()),
+                Message::BulkString("key2".to_string()),
+            ],
+        };
+
+        assert_command(del.clone(), message_from(&["del", "key1", "key2"]));
+        assert_command(del.clone(), message_from(&["UNLINK", "key1", "key2"]));
+        assert_command(del.clone(), del.to_message());
+        assert_eq!(
+            "wrong number of arguments for 'unlink' command",
+            parse_command(&message_from(&["unlink"]))
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_unknown_command_preview_is_cut() {
+        let long = "x".repeat(100);
+        let error = parse_command(&message_from(&["unknown", &long, &long, "more"])).unwrap_err();
+
+        assert_eq!(
+            format!(
+                "unknown command 'unknown', with args beginning with: '{}' '{}' ",
+                long,
+                "x".repeat(25)
+            ),
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_hello_command() {
+        assert_command(
+            Command::Hello {
+                protover: None,
+                auth: None,
+            },
+            message_from(&["hello"]),
+        );
+        let hello = Command::Hello {
+            protover: Some(3),
+            auth: Some(("default".to_string(), "secret".to_string())),
+        };
+        assert_command(
+            hello.clone(),
+            message_from(&["HELLO", "3", "auth", "default", "secret"]),
+        );
+        assert_command(hello.clone(), hello.to_message());
+        assert_command(
+            Command::Hello {
+                protover: None,
+                auth: Some(("default".to_string(), "secret".to_string())),
+            },
+            message_from(&["HELLO", "AUTH", "default", "secret"]),
+        );
+
+        assert!(parse_command(&message_from(&["HELLO", "three"])).is_err());
+        assert!(parse_command(&message_from(&["HELLO", "3", "AUTH", "default"])).is_err());
+    }
+
+    #[test]
+    fn test_config_command() {
+        let get = Command::Config {
+            subcommand: ConfigSubcommand::Get {
+                patterns: vec!["maxmemory".to_string(), "s*".to_string()],
+            },
+        };
+        assert_command(
+            get.clone(),
+            message_from(&["config", "get", "maxmemory", "s*"]),
+        );
+        assert_command(get.clone(), get.to_message());
+
+        let set = Command::Config {
+            subcommand: ConfigSubcommand::Set {
+                name: "appendonly".to_string(),
+                value: "yes".to_string(),
+            },
+        };
+        assert_command(
+            set.clone(),
+            message_from(&["CONFIG", "SET", "appendonly", "yes"]),
+        );
+        assert_command(set.clone(), set.to_message());
+
+        assert!(parse_command(&message_from(&["CONFIG"])).is_err());
+        assert!(parse_command(&message_from(&["CONFIG", "GET"])).is_err());
+        assert!(parse_command(&message_from(&["CONFIG", "SET", "save"])).is_err());
+        assert!(parse_command(&message_from(&["CONFIG", "REWRITE"])).is_err());
+    }
+
+    #[test]
+    fn test_latency_command() {
+        let history = Command::Latency {
+            subcommand: LatencySubcommand::History {
+                event: "command".to_string(),
+            },
+        };
+        assert_command(
+            history.clone(),
+            message_from(&["latency", "history", "COMMAND"]),
+        );
+        assert_command(history.clone(), history.to_message());
+        assert_command(
+            Command::Latency {
+                subcommand: LatencySubcommand::Latest,
+            },
+            message_from(&["LATENCY", "LATEST"]),
+        );
+        let reset = Command::Latency {
+            subcommand: LatencySubcommand::Reset {
+                events: vec!["command".to_string()],
+            },
+        };
+        assert_command(
+            reset.clone(),
+            message_from(&["LATENCY", "RESET", "command"]),
+        );
+        assert_command(reset.clone(), reset.to_message());
+
+        assert!(parse_command(&message_from(&["LATENCY"])).is_err());
+        assert!(parse_command(&message_from(&["LATENCY", "HISTORY"])).is_err());
+        assert!(parse_command(&message_from(&["LATENCY", "DOCTOR"])).is_err());
+    }
+
+    #[test]
+    fn test_slowlog_command() {
+        assert_command(
+            Command::Slowlog {
+                subcommand: SlowlogSubcommand::Get {
+                    count: Some(SLOWLOG_DEFAULT_COUNT),
+                },
+            },
+            message_from(&["slowlog", "get"]),
+        );
+        let get_all = Command::Slowlog {
+            subcommand: SlowlogSubcommand::Get { count: None },
+        };
+        assert_command(get_all.clone(), message_from(&["SLOWLOG", "GET", "-1"]));
+        assert_command(get_all.clone(), get_all.to_message());
+        assert_command(
+            Command::Slowlog {
+                subcommand: SlowlogSubcommand::Len,
+            },
+            message_from(&["SLOWLOG", "len"]),
+        );
+        assert_command(
+            Command::Slowlog {
+                subcommand: SlowlogSubcommand::Reset,
+            },
+            message_from(&["SLOWLOG", "RESET"]),
+        );
+
+        assert!(parse_command(&message_from(&["SLOWLOG"])).is_err());
+        assert!(parse_command(&message_from(&["SLOWLOG", "GET", "-2"])).is_err());
+        assert!(parse_command(&message_from(&["SLOWLOG", "LEN", "1"])).is_err());
+    }
+
+    #[test]
+    fn test_pubsub_commands() {
+        let subscribe = Command::Subscribe {
+            channels: vec!["a".to_string(), "b".to_string()],
+        };
+        assert_command(subscribe.clone(), message_from(&["subscribe", "a", "b"]));
+        assert_command(subscribe.clone(), subscribe.to_message());
+        assert_command(
+            Command::Unsubscribe { channels: vec![] },
+            message_from(&["UNSUBSCRIBE"]),
+        );
+        let publish = Command::Publish {
+            channel: "a".to_string(),
+            message: Message::BulkString("hello".to_string()),
+        };
+        assert_command(publish.clone(), message_from(&["PUBLISH", "a", "hello"]));
+        assert_command(publish.clone(), publish.to_message());
+
+        let psubscribe = Command::PSubscribe {
+            patterns: vec!["news.*".to_string()],
+        };
+        assert_command(psubscribe.clone(), message_from(&["PSUBSCRIBE", "news.*"]));
+        assert_command(psubscribe.clone(), psubscribe.to_message());
+        assert_command(
+            Command::PUnsubscribe { patterns: vec![] },
+            message_from(&["punsubscribe"]),
+        );
+
+        assert!(parse_command(&message_from(&["SUBSCRIBE"])).is_err());
+        assert!(parse_command(&message_from(&["PSUBSCRIBE"])).is_err());
+        assert!(parse_command(&message_from(&["PUBLISH", "a"])).is_err());
+    }
+
+    #[test]
+    fn test_transaction_commands() {
+        assert_command(Command::Multi, message_from(&["multi"]));
+        assert_command(Command::Exec, message_from(&["EXEC"]));
+        assert_command(Command::Discard, message_from(&["DISCARD"]));
+        assert_command(Command::Unwatch, message_from(&["UNWATCH"]));
+        let watch = Command::Watch {
+            keys: vec![Message::BulkString("key".to_string())],
+        };
+        assert_command(watch.clone(), message_from(&["WATCH", "key"]));
+        assert_command(watch.clone(), watch.to_message());
+
+        assert!(parse_command(&message_from(&["WATCH"])).is_err());
+        assert!(parse_command(&message_from(&["EXEC", "now"])).is_err());
+    }
+
+    #[test]
+    fn test_keys_command() {
+        assert_command(
+            Command::Keys {
+                pattern: "user:*".to_string(),
+            },
+            message_from(&["keys", "user:*"]),
+        );
+        assert!(parse_command(&message_from(&["KEYS"])).is_err());
+    }
+
+    #[test]
+    fn test_scan_command() {
+        assert_command(
+            Command::Scan {
+                cursor: 0,
+                pattern: None,
+                count: SCAN_DEFAULT_COUNT,
+            },
+            message_from(&["scan", "0"]),
+        );
+
+        let scan = Command::Scan {
+            cursor: 42,
+            pattern: Some("user:*".to_string()),
+            count: 5,
+        };
+        assert_command(
+            scan.clone(),
+            message_from(&["SCAN", "42", "count", "5", "match", "user:*"]),
+        );
+        assert_command(scan.clone(), scan.to_message());
+
+        assert!(parse_command(&message_from(&["SCAN"])).is_err());
+        assert!(parse_command(&message_from(&["SCAN", "x"])).is_err());
+        assert!(parse_command(&message_from(&["SCAN", "0", "COUNT", "0"])).is_err());
+        assert!(parse_command(&message_from(&["SCAN", "0", "MATCH"])).is_err());
+    }
+
+    #[test]
+    fn test_incr_decr_command() {
+        let key = Message::BulkString("counter".to_string());
+
+        let message = Message::Array(vec![Message::BulkString("incr".to_string()), key.clone()]);
+        assert_command(Command::Incr { key: key.clone() }, message);
+
+        let message = Message::Array(vec![Message::BulkString("DECR".to_string()), key.clone()]);
+        assert_command(Command::Decr { key }, message);
+    }
+
+    fn message_from(parts: &[&str]) -> Message {
+        Message::Array(
+            parts
+                .iter()
+                .map(|part| Message::BulkString(part.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_incrby_decrby_command() {
+        let key = Message::BulkString("counter".to_string());
+
+        assert_command(
+            Command::IncrBy {
+                key: key.clone(),
+                increment: -5,
+            },
+            message_from(&["incrby", "counter", "-5"]),
+        );
+        assert_command(
+            Command::DecrBy { key, decrement: 7 },
+            message_from(&["DECRBY", "counter", "7"]),
+        );
+
+        let result = parse_command(&message_from(&["INCRBY", "counter", "1.5"]));
+        assert_eq!(
+            "value is not an integer or out of range",
+            result.unwrap_err().to_string()
+        );
+        let result = parse_command(&message_from(&["DECRBY", "counter", &i64::MIN.to_string()]));
+        assert_eq!("decrement would overflow", result.unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_incrbyfloat_command() {
+        let command = Command::IncrByFloat {
+            key: Message::BulkString("float".to_string()),
+            increment: 3000.0,
+        };
+        assert_command(
+            command.clone(),
+            message_from(&["INCRBYFLOAT", "float", "3.0e3"]),
+        );
+        assert_eq!(
+            message_from(&["INCRBYFLOAT", "float", "3000"]),
+            command.to_message()
+        );
+
+        let result = parse_command(&message_from(&["INCRBYFLOAT", "float", "abc"]));
+        assert_eq!(
+            "value is not a valid float",
+            result.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_setnx_setex_command() {
+        let key = Message::BulkString("key".to_string());
+        let value = Message::BulkString("value".to_string());
+
+        assert_command(
+            Command::SetNx {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            message_from(&["setnx", "key", "value"]),
+        );
+        let setex = Command::SetEx {
+            key,
+            seconds: 10,
+            value,
+        };
+        assert_command(
+            setex.clone(),
+            message_from(&["SETEX", "key", "10", "value"]),
+        );
+        assert_command(setex.clone(), setex.to_message());
+
+        assert!(parse_command(&message_from(&["SETNX", "key"])).is_err());
+        assert!(parse_command(&message_from(&["SETEX", "key", "10"])).is_err());
+        for seconds in ["0", "-1", "ten", "9223372036854775807"] {
+            assert!(parse_command(&message_from(&["SETEX", "key", seconds, "value"])).is_err());
+        }
+    }
+
+    #[test]
+    fn test_append_command() {
+        assert_command(
+            Command::Append {
+                key: Message::BulkString("key".to_string()),
+                value: Message::BulkString("value".to_string()),
+            },
+            message_from(&["append", "key", "value"]),
+        );
+        assert!(parse_command(&message_from(&["APPEND", "key"])).is_err());
+    }
+
+    #[test]
+    fn test_getrange_command() {
+        let getrange = Command::GetRange {
+            key: Message::BulkString("key".to_string()),
+            start: 0,
+            end: -1,
+        };
+        assert_command(
+            getrange.clone(),
+            message_from(&["getrange", "key", "0", "-1"]),
+        );
+        assert_command(getrange.clone(), getrange.to_message());
+        assert!(parse_command(&message_from(&["GETRANGE", "key", "a", "1"])).is_err());
+    }
+
+    #[test]
+    fn test_ttl_pttl_command() {
+        let key = Message::BulkString("key".to_string());
+
+        assert_command(
+            Command::Ttl { key: key.clone() },
+            message_from(&["ttl", "key"]),
+        );
+        assert_command(Command::Pttl { key }, message_from(&["PTTL", "key"]));
+    }
+
+    #[test]
+    fn test_expire_command() {
+        let key = Message::BulkString("key".to_string());
+        let expire = Command::Expire {
+            key: key.clone(),
+            milliseconds: 10000,
+        };
+
+        assert_command(expire.clone(), message_from(&["expire", "key", "10"]));
+        assert_command(expire.clone(), message_from(&["PEXPIRE", "key", "10000"]));
+        assert_command(expire.clone(), expire.to_message());
+        assert_command(Command::Persist { key }, message_from(&["persist", "key"]));
+
+        let result = parse_command(&message_from(&["EXPIRE", "key", &i64::MAX.to_string()]));
+        assert_eq!(
+            "invalid expire time in 'expire' command",
+            result.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_dump_and_restore_commands() {
+        let key = Message::BulkString("key".to_string());
+        assert_command(
+            Command::Dump { key: key.clone() },
+            message_from(&["dump", "key"]),
+        );
+
+        let restore = Command::Restore {
+            key,
+            ttl: 1000,
+            payload: Message::BulkBytes(vec![0, 0xff]),
+            replace: true,
+            absttl: false,
+        };
+        assert_command(restore.clone(), restore.to_message());
+        let mut message = restore.to_message();
+        if let Message::Array(parts) = &mut message {
+            parts[4] = Message::BulkString("replace".to_string());
+        }
+        assert_command(restore, message);
+
+        assert_eq!(
+            "Invalid TTL value, must be >= 0",
+            parse_command(&message_from(&["RESTORE", "key", "-1", "payload"]))
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "syntax error",
+            parse_command(&message_from(&[
+                "RESTORE", "key", "0", "payload", "IDLETIME"
+            ]))
+            .unwrap_err()
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_type_command() {
+        assert_command(
+            Command::Type {
+                key: Message::BulkString("key".to_string()),
+            },
+            message_from(&["type", "key"]),
+        );
+    }
+
+    #[test]
+    fn test_list_commands() {
+        let key = Message::BulkString("list".to_string());
+        let values = vec![
+            Message::BulkString("a".to_string()),
+            Message::BulkString("b".to_string()),
+        ];
+
+        assert_command(
+            Command::LPush {
+                key: key.clone(),
+                values: values.clone(),
+            },
+            message_from(&["lpush", "list", "a", "b"]),
+        );
+        assert_command(
+            Command::RPush {
+                key: key.clone(),
+                values,
+            },
+            message_from(&["RPUSH", "list", "a", "b"]),
+        );
+        assert_command(
+            Command::LRange {
+                key: key.clone(),
+                start: 0,
+                stop: -1,
+            },
+            message_from(&["lrange", "list", "0", "-1"]),
+        );
+        assert_command(Command::LLen { key }, message_from(&["LLEN", "list"]));
+
+        assert_command(
+            Command::LPop {
+                key: Message::BulkString("list".to_string()),
+                count: None,
+            },
+            message_from(&["lpop", "list"]),
+        );
+        assert_command(
+            Command::RPop {
+                key: Message::BulkString("list".to_string()),
+                count: Some(3),
+            },
+            message_from(&["RPOP", "list", "3"]),
+        );
+
+        assert!(parse_command(&message_from(&["LPUSH", "list"])).is_err());
+        let result = parse_command(&message_from(&["LPOP", "list", "-1"]));
+        assert_eq!(
+            "value is out of range, must be positive",
+            result.unwrap_err().to_string()
+        );
+        assert!(parse_command(&message_from(&["LRANGE", "list", "a", "1"])).is_err());
+    }
+
+    #[test]
+    fn test_hash_commands() {
+        let key = Message::BulkString("hash".to_string());
+
+        let hset = Command::HSet {
+            key: key.clone(),
+            fields: vec![
+                ("a".to_string(), Message::BulkString("1".to_string())),
+                ("b".to_string(), Message::BulkString("2".to_string())),
+            ],
+        };
+        assert_command(
+            hset.clone(),
+            message_from(&["hset", "hash", "a", "1", "b", "2"]),
+        );
+        assert_command(hset.clone(), hset.to_message());
+        assert_command(
+            Command::HGet {
+                key: key.clone(),
+                field: "a".to_string(),
+            },
+            message_from(&["HGET", "hash", "a"]),
+        );
+        assert_command(
+            Command::HGetAll { key: key.clone() },
+            message_from(&["hgetall", "hash"]),
+        );
+        assert_command(
+            Command::HDel {
+                key,
+                fields: vec!["a".to_string(), "b".to_string()],
+            },
+            message_from(&["HDEL", "hash", "a", "b"]),
+        );
+
+        assert!(parse_command(&message_from(&["HSET", "hash", "a"])).is_err());
+        assert!(parse_command(&message_from(&["HSET", "hash", "a", "1", "b"])).is_err());
+    }
+
+    #[test]
+    fn test_set_commands() {
+        let key = Message::BulkString("set".to_string());
+
+        let sadd = Command::SAdd {
+            key: key.clone(),
+            members: vec![
+                Message::BulkString("a".to_string()),
+                Message::BulkString("b".to_string()),
+            ],
+        };
+        assert_command(sadd.clone(), message_from(&["sadd", "set", "a", "b"]));
+        assert_command(sadd.clone(), sadd.to_message());
+        assert_command(
+            Command::SMembers { key: key.clone() },
+            message_from(&["SMEMBERS", "set"]),
+        );
+
+        for (name, operation) in [
+            ("SINTERSTORE", SetOperation::Intersection),
+            ("sunionstore", SetOperation::Union),
+            ("SDIFFSTORE", SetOperation::Difference),
+        ] {
+            let store = Command::SetStore {
+                operation,
+                destination: Message::BulkString("dest".to_string()),
+                keys: vec![key.clone()],
+            };
+            assert_command(store.clone(), message_from(&[name, "dest", "set"]));
+            assert_command(store.clone(), store.to_message());
+        }
+
+        assert!(parse_command(&message_from(&["SADD", "set"])).is_err());
+        assert!(parse_command(&message_from(&["SINTERSTORE", "dest"])).is_err());
+    }
+
+    #[test]
+    fn test_wait_command() {
+        let wait = Command::Wait {
+            numreplicas: 2,
+            timeout: Some(Duration::from_millis(500)),
+        };
+        assert_command(wait.clone(), message_from(&["wait", "2", "500"]));
+        assert_command(wait.clone(), wait.to_message());
+        assert_command(
+            Command::Wait {
+                numreplicas: 1,
+                timeout: None,
+            },
+            message_from(&["WAIT", "1", "0"]),
+        );
+
+        assert_eq!(
+            "timeout is negative",
+            parse_command(&message_from(&["WAIT", "1", "-1"]))
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_debug_sleep_command() {
+        let message = Message::Array(vec![
+            Message::BulkString("DEBUG".to_string()),
+            Message::BulkString("sleep".to_string()),
+            Message::BulkString("0.5".to_string()),
+            Message::BulkString("global".to_string()),
+        ]);
+
+        assert_command(
+            Command::DebugSleep {
+                duration: Duration::from_millis(500),
+                global: true,
+            },
+            message,
+        );
+    }
+
+    #[test]
+    fn test_command_docs_command() {
+        let message = Message::Array(vec![
+            Message::BulkString("COMMAND".to_string()),
+            Message::BulkString("docs".to_string()),
+            Message::BulkString("set".to_string()),
+        ]);
+
+        assert_command(
+            Command::Docs {
+                names: vec!["set".to_string()],
+            },
+            message,
+        );
+    }
+
+    #[test]
+    fn test_cluster_command() {
+        let message = Message::Array(vec![
+            Message::BulkString("cluster".to_string()),
+            Message::BulkString("myid".to_string()),
+        ]);
+
+        assert_command(
+            Command::Cluster {
+                subcommand: "MYID".to_string(),
+            },
+            message,
+        );
+    }
 }
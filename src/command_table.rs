@@ -0,0 +1,752 @@
+use crate::message::Message;
+
+// Static metadata about the supported commands, used for COMMAND DOCS.
+
+pub struct ArgumentDoc {
+    pub name: &'static str,
+    pub arg_type: &'static str,
+    pub token: Option<&'static str>,
+    pub optional: bool,
+}
+
+pub struct CommandDoc {
+    pub name: &'static str,
+    // Like redis, the number of arguments including the command name,
+    // a negative arity is the minimum.
+    pub arity: i32,
+    pub summary: &'static str,
+    pub since: &'static str,
+    pub group: &'static str,
+    pub arguments: &'static [ArgumentDoc],
+}
+
+const fn arg(name: &'static str, arg_type: &'static str) -> ArgumentDoc {
+    ArgumentDoc {
+        name,
+        arg_type,
+        token: None,
+        optional: false,
+    }
+}
+
+const fn optional_arg(name: &'static str, arg_type: &'static str) -> ArgumentDoc {
+    ArgumentDoc {
+        name,
+        arg_type,
+        token: None,
+        optional: true,
+    }
+}
+
+const fn optional_token(
+    name: &'static str,
+    arg_type: &'static str,
+    token: &'static str,
+) -> ArgumentDoc {
+    ArgumentDoc {
+        name,
+        arg_type,
+        token: Some(token),
+        optional: true,
+    }
+}
+
+pub static COMMANDS: &[CommandDoc] = &[
+    CommandDoc {
+        name: "ping",
+        arity: -1,
+        summary: "Returns the server's liveliness response.",
+        since: "1.0.0",
+        group: "connection",
+        arguments: &[optional_arg("message", "string")],
+    },
+    CommandDoc {
+        name: "echo",
+        arity: 2,
+        summary: "Returns the given string.",
+        since: "1.0.0",
+        group: "connection",
+        arguments: &[arg("message", "string")],
+    },
+    CommandDoc {
+        name: "hello",
+        arity: -1,
+        summary: "Handshakes with the Redis server.",
+        since: "6.0.0",
+        group: "connection",
+        arguments: &[
+            optional_arg("protover", "integer"),
+            optional_token("auth", "block", "AUTH"),
+        ],
+    },
+    CommandDoc {
+        name: "set",
+        arity: -3,
+        summary: "Sets the string value of a key, ignoring its type.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[
+            arg("key", "key"),
+            arg("value", "string"),
+            optional_token("seconds", "integer", "EX"),
+            optional_token("milliseconds", "integer", "PX"),
+            optional_token("unix-time-seconds", "unix-time", "EXAT"),
+            optional_token("unix-time-milliseconds", "unix-time", "PXAT"),
+            optional_token("keepttl", "pure-token", "KEEPTTL"),
+            optional_token("nx", "pure-token", "NX"),
+            optional_token("xx", "pure-token", "XX"),
+            optional_token("get", "pure-token", "GET"),
+        ],
+    },
+    CommandDoc {
+        name: "setnx",
+        arity: 3,
+        summary: "Set the string value of a key only when the key doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[arg("key", "key"), arg("value", "string")],
+    },
+    CommandDoc {
+        name: "setex",
+        arity: 4,
+        summary: "Sets the string value and expiration time of a key. Creates the key if it doesn't exist.",
+        since: "2.0.0",
+        group: "string",
+        arguments: &[
+            arg("key", "key"),
+            arg("seconds", "integer"),
+            arg("value", "string"),
+        ],
+    },
+    CommandDoc {
+        name: "get",
+        arity: 2,
+        summary: "Returns the string value of a key.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "getrange",
+        arity: 4,
+        summary: "Returns a substring of the string stored at a key.",
+        since: "2.4.0",
+        group: "string",
+        arguments: &[
+            arg("key", "key"),
+            arg("start", "integer"),
+            arg("end", "integer"),
+        ],
+    },
+    CommandDoc {
+        name: "incr",
+        arity: 2,
+        summary: "Increments the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "decr",
+        arity: 2,
+        summary: "Decrements the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "incrby",
+        arity: 3,
+        summary: "Increments the integer value of a key by a number. Uses 0 as initial value if the key doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[arg("key", "key"), arg("increment", "integer")],
+    },
+    CommandDoc {
+        name: "decrby",
+        arity: 3,
+        summary: "Decrements a number from the integer value of a key. Uses 0 as initial value if the key doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        arguments: &[arg("key", "key"), arg("decrement", "integer")],
+    },
+    CommandDoc {
+        name: "incrbyfloat",
+        arity: 3,
+        summary: "Increment the floating point value of a key by a number. Uses 0 as initial value if the key doesn't exist.",
+        since: "2.6.0",
+        group: "string",
+        arguments: &[arg("key", "key"), arg("increment", "double")],
+    },
+    CommandDoc {
+        name: "append",
+        arity: 3,
+        summary: "Appends a string to the value of a key. Creates the key if it doesn't exist.",
+        since: "2.0.0",
+        group: "string",
+        arguments: &[arg("key", "key"), arg("value", "string")],
+    },
+    CommandDoc {
+        name: "expire",
+        arity: -3,
+        summary: "Sets the expiration time of a key in seconds.",
+        since: "1.0.0",
+        group: "generic",
+        arguments: &[arg("key", "key"), arg("seconds", "integer")],
+    },
+    CommandDoc {
+        name: "pexpire",
+        arity: -3,
+        summary: "Sets the expiration time of a key in milliseconds.",
+        since: "2.6.0",
+        group: "generic",
+        arguments: &[arg("key", "key"), arg("milliseconds", "integer")],
+    },
+    CommandDoc {
+        name: "persist",
+        arity: 2,
+        summary: "Removes the expiration time of a key.",
+        since: "2.2.0",
+        group: "generic",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "dump",
+        arity: 2,
+        summary: "Returns a serialized representation of the value stored at a key.",
+        since: "2.6.0",
+        group: "generic",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "restore",
+        arity: -4,
+        summary: "Creates a key from the serialized representation of a value.",
+        since: "2.6.0",
+        group: "generic",
+        arguments: &[
+            arg("key", "key"),
+            arg("ttl", "integer"),
+            arg("serialized-value", "string"),
+            optional_token("replace", "pure-token", "REPLACE"),
+            optional_token("absttl", "pure-token", "ABSTTL"),
+        ],
+    },
+    CommandDoc {
+        name: "object",
+        arity: -2,
+        summary: "A container for object introspection commands.",
+        since: "2.2.3",
+        group: "generic",
+        arguments: &[arg("subcommand", "string")],
+    },
+    CommandDoc {
+        name: "type",
+        arity: 2,
+        summary: "Determines the type of value stored at a key.",
+        since: "1.0.0",
+        group: "generic",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "ttl",
+        arity: 2,
+        summary: "Returns the expiration time in seconds of a key.",
+        since: "1.0.0",
+        group: "generic",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "pttl",
+        arity: 2,
+        summary: "Returns the expiration time in milliseconds of a key.",
+        since: "2.6.0",
+        group: "generic",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "lpush",
+        arity: -3,
+        summary: "Prepends one or more elements to a list. Creates the key if it doesn't exist.",
+        since: "1.0.0",
+        group: "list",
+        arguments: &[arg("key", "key"), arg("element", "string")],
+    },
+    CommandDoc {
+        name: "rpush",
+        arity: -3,
+        summary: "Appends one or more elements to a list. Creates the key if it doesn't exist.",
+        since: "1.0.0",
+        group: "list",
+        arguments: &[arg("key", "key"), arg("element", "string")],
+    },
+    CommandDoc {
+        name: "lrange",
+        arity: 4,
+        summary: "Returns a range of elements from a list.",
+        since: "1.0.0",
+        group: "list",
+        arguments: &[
+            arg("key", "key"),
+            arg("start", "integer"),
+            arg("stop", "integer"),
+        ],
+    },
+    CommandDoc {
+        name: "llen",
+        arity: 2,
+        summary: "Returns the length of a list.",
+        since: "1.0.0",
+        group: "list",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "lpop",
+        arity: -2,
+        summary: "Returns the first elements in a list after removing it. Deletes the list if the last element was popped.",
+        since: "1.0.0",
+        group: "list",
+        arguments: &[arg("key", "key"), optional_arg("count", "integer")],
+    },
+    CommandDoc {
+        name: "rpop",
+        arity: -2,
+        summary: "Returns and removes the last elements of a list. Deletes the list if the last element was popped.",
+        since: "1.0.0",
+        group: "list",
+        arguments: &[arg("key", "key"), optional_arg("count", "integer")],
+    },
+    CommandDoc {
+        name: "hset",
+        arity: -4,
+        summary: "Creates or modifies the value of a field in a hash.",
+        since: "2.0.0",
+        group: "hash",
+        arguments: &[
+            arg("key", "key"),
+            arg("field", "string"),
+            arg("value", "string"),
+        ],
+    },
+    CommandDoc {
+        name: "hget",
+        arity: 3,
+        summary: "Returns the value of a field in a hash.",
+        since: "2.0.0",
+        group: "hash",
+        arguments: &[arg("key", "key"), arg("field", "string")],
+    },
+    CommandDoc {
+        name: "hgetall",
+        arity: 2,
+        summary: "Returns all fields and values in a hash.",
+        since: "2.0.0",
+        group: "hash",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "hdel",
+        arity: -3,
+        summary: "Deletes one or more fields and their values from a hash. Deletes the hash if no fields remain.",
+        since: "2.0.0",
+        group: "hash",
+        arguments: &[arg("key", "key"), arg("field", "string")],
+    },
+    CommandDoc {
+        name: "sadd",
+        arity: -3,
+        summary: "Adds one or more members to a set. Creates the key if it doesn't exist.",
+        since: "1.0.0",
+        group: "set",
+        arguments: &[arg("key", "key"), arg("member", "string")],
+    },
+    CommandDoc {
+        name: "smembers",
+        arity: 2,
+        summary: "Returns all members of a set.",
+        since: "1.0.0",
+        group: "set",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "sinterstore",
+        arity: -3,
+        summary: "Stores the intersect of multiple sets in a key.",
+        since: "1.0.0",
+        group: "set",
+        arguments: &[arg("destination", "key"), arg("key", "key")],
+    },
+    CommandDoc {
+        name: "sunionstore",
+        arity: -3,
+        summary: "Stores the union of multiple sets in a key.",
+        since: "1.0.0",
+        group: "set",
+        arguments: &[arg("destination", "key"), arg("key", "key")],
+    },
+    CommandDoc {
+        name: "sdiffstore",
+        arity: -3,
+        summary: "Stores the difference of multiple sets in a key.",
+        since: "1.0.0",
+        group: "set",
+        arguments: &[arg("destination", "key"), arg("key", "key")],
+    },
+    CommandDoc {
+        name: "del",
+        arity: -2,
+        summary: "Deletes one or more keys.",
+        since: "1.0.0",
+        group: "generic",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "unlink",
+        arity: -2,
+        summary: "Asynchronously deletes one or more keys.",
+        since: "4.0.0",
+        group: "generic",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "exists",
+        arity: -2,
+        summary: "Determines whether one or more keys exist.",
+        since: "1.0.0",
+        group: "generic",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "keys",
+        arity: 2,
+        summary: "Returns all key names that match a pattern.",
+        since: "1.0.0",
+        group: "generic",
+        arguments: &[arg("pattern", "pattern")],
+    },
+    CommandDoc {
+        name: "scan",
+        arity: -2,
+        summary: "Iterates over the key names in the database.",
+        since: "2.8.0",
+        group: "generic",
+        arguments: &[
+            arg("cursor", "integer"),
+            optional_token("pattern", "pattern", "MATCH"),
+            optional_token("count", "integer", "COUNT"),
+        ],
+    },
+    CommandDoc {
+        name: "randomkey",
+        arity: 1,
+        summary: "Returns a random key name from the database.",
+        since: "1.0.0",
+        group: "generic",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "dbsize",
+        arity: 1,
+        summary: "Returns the number of keys in the database.",
+        since: "1.0.0",
+        group: "server",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "flushall",
+        arity: -1,
+        summary: "Removes all keys from all databases.",
+        since: "1.0.0",
+        group: "server",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "multi",
+        arity: 1,
+        summary: "Starts a transaction.",
+        since: "1.2.0",
+        group: "transactions",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "exec",
+        arity: 1,
+        summary: "Executes all commands in a transaction.",
+        since: "1.2.0",
+        group: "transactions",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "discard",
+        arity: 1,
+        summary: "Discards a transaction.",
+        since: "2.0.0",
+        group: "transactions",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "watch",
+        arity: -2,
+        summary: "Monitors changes to keys to determine the execution of a transaction.",
+        since: "2.2.0",
+        group: "transactions",
+        arguments: &[arg("key", "key")],
+    },
+    CommandDoc {
+        name: "unwatch",
+        arity: 1,
+        summary: "Forgets about watched keys of a transaction.",
+        since: "2.2.0",
+        group: "transactions",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "subscribe",
+        arity: -2,
+        summary: "Listens for messages published to channels.",
+        since: "2.0.0",
+        group: "pubsub",
+        arguments: &[arg("channel", "string")],
+    },
+    CommandDoc {
+        name: "unsubscribe",
+        arity: -1,
+        summary: "Stops listening to messages posted to channels.",
+        since: "2.0.0",
+        group: "pubsub",
+        arguments: &[optional_arg("channel", "string")],
+    },
+    CommandDoc {
+        name: "psubscribe",
+        arity: -2,
+        summary: "Listens for messages published to channels that match one or more patterns.",
+        since: "2.0.0",
+        group: "pubsub",
+        arguments: &[arg("pattern", "pattern")],
+    },
+    CommandDoc {
+        name: "punsubscribe",
+        arity: -1,
+        summary: "Stops listening to messages published to channels that match one or more patterns.",
+        since: "2.0.0",
+        group: "pubsub",
+        arguments: &[optional_arg("pattern", "pattern")],
+    },
+    CommandDoc {
+        name: "publish",
+        arity: 3,
+        summary: "Posts a message to a channel.",
+        since: "2.0.0",
+        group: "pubsub",
+        arguments: &[arg("channel", "string"), arg("message", "string")],
+    },
+    CommandDoc {
+        name: "info",
+        arity: -1,
+        summary: "Returns information and statistics about the server.",
+        since: "1.0.0",
+        group: "server",
+        arguments: &[optional_arg("section", "string")],
+    },
+    CommandDoc {
+        name: "replconf",
+        arity: -1,
+        summary: "An internal command for configuring the replication stream.",
+        since: "3.0.0",
+        group: "server",
+        arguments: &[],
+    },
+    CommandDoc {
+        name: "psync",
+        arity: -3,
+        summary: "An internal command used in replication.",
+        since: "2.8.0",
+        group: "server",
+        arguments: &[arg("replicationid", "string"), arg("offset", "integer")],
+    },
+    CommandDoc {
+        name: "wait",
+        arity: 3,
+        summary: "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed.",
+        since: "3.0.0",
+        group: "generic",
+        arguments: &[arg("numreplicas", "integer"), arg("timeout", "integer")],
+    },
+    CommandDoc {
+        name: "config",
+        arity: -2,
+        summary: "A container for server configuration commands.",
+        since: "2.0.0",
+        group: "server",
+        arguments: &[arg("subcommand", "string")],
+    },
+    CommandDoc {
+        name: "slowlog",
+        arity: -2,
+        summary: "A container for slow log commands.",
+        since: "2.2.12",
+        group: "server",
+        arguments: &[arg("subcommand", "string")],
+    },
+    CommandDoc {
+        name: "latency",
+        arity: -2,
+        summary: "A container for latency diagnostics commands.",
+        since: "2.8.13",
+        group: "server",
+        arguments: &[arg("subcommand", "string")],
+    },
+    CommandDoc {
+        name: "acl",
+        arity: -2,
+        summary: "A container for Access List Control commands.",
+        since: "6.0.0",
+        group: "server",
+        arguments: &[arg("subcommand", "string")],
+    },
+    CommandDoc {
+        name: "function",
+        arity: -2,
+        summary: "A container for function commands.",
+        since: "7.0.0",
+        group: "scripting",
+        arguments: &[arg("subcommand", "string")],
+    },
+    CommandDoc {
+        name: "cluster",
+        arity: -2,
+        summary: "A container for Redis Cluster commands.",
+        since: "3.0.0",
+        group: "cluster",
+        arguments: &[arg("subcommand", "string")],
+    },
+    CommandDoc {
+        name: "debug",
+        arity: -2,
+        summary: "A container for debugging commands.",
+        since: "1.0.0",
+        group: "server",
+        arguments: &[
+            arg("subcommand", "string"),
+            arg("seconds", "double"),
+            optional_token("global", "pure-token", "GLOBAL"),
+        ],
+    },
+    CommandDoc {
+        name: "command",
+        arity: -1,
+        summary: "Returns detailed information about all commands.",
+        since: "2.8.13",
+        group: "server",
+        arguments: &[],
+    },
+];
+
+pub fn find_command(name: &str) -> Option<&'static CommandDoc> {
+    let name = name.to_lowercase();
+    COMMANDS.iter().find(|doc| doc.name == name)
+}
+
+impl CommandDoc {
+    // Whether a call with `len` messages, the name included, has a
+    // valid number of arguments.
+    pub fn accepts_len(&self, len: usize) -> bool {
+        if self.arity < 0 {
+            len >= self.arity.unsigned_abs() as usize
+        } else {
+            len == self.arity as usize
+        }
+    }
+}
+
+fn bulk(value: &str) -> Message {
+    Message::BulkString(value.to_string())
+}
+
+impl ArgumentDoc {
+    fn to_message(&self) -> Message {
+        let mut fields = vec![
+            bulk("name"),
+            bulk(self.name),
+            bulk("type"),
+            bulk(self.arg_type),
+        ];
+        if let Some(token) = self.token {
+            fields.push(bulk("token"));
+            fields.push(bulk(token));
+        }
+        if self.optional {
+            fields.push(bulk("flags"));
+            fields.push(Message::Array(vec![Message::SimpleString(
+                "optional".to_string(),
+            )]));
+        }
+
+        Message::Array(fields)
+    }
+}
+
+impl CommandDoc {
+    // The docs of one command as name/value pairs, as returned by COMMAND DOCS.
+    pub fn docs_message(&self) -> Message {
+        let mut fields = vec![
+            bulk("summary"),
+            bulk(self.summary),
+            bulk("since"),
+            bulk(self.since),
+            bulk("group"),
+            bulk(self.group),
+        ];
+        if !self.arguments.is_empty() {
+            fields.push(bulk("arguments"));
+            fields.push(Message::Array(
+                self.arguments.iter().map(ArgumentDoc::to_message).collect(),
+            ));
+        }
+
+        Message::Array(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_command_ignores_case() {
+        assert_eq!("set", find_command("SeT").unwrap().name);
+        assert!(find_command("unknown").is_none());
+    }
+
+    #[test]
+    fn test_accepts_len() {
+        let get = find_command("get").unwrap();
+        assert!(!get.accepts_len(1));
+        assert!(get.accepts_len(2));
+        assert!(!get.accepts_len(3));
+
+        let set = find_command("set").unwrap();
+        assert!(!set.accepts_len(2));
+        assert!(set.accepts_len(3));
+        assert!(set.accepts_len(6));
+    }
+
+    #[test]
+    fn test_argument_with_token() {
+        let doc = optional_token("seconds", "integer", "EX");
+
+        assert_eq!(
+            Message::Array(vec![
+                bulk("name"),
+                bulk("seconds"),
+                bulk("type"),
+                bulk("integer"),
+                bulk("token"),
+                bulk("EX"),
+                bulk("flags"),
+                Message::Array(vec![Message::SimpleString("optional".to_string())]),
+            ]),
+            doc.to_message()
+        );
+    }
+}
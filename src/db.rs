@@ -1,77 +1,2037 @@
-use std::{collections::HashMap, ops::Add};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::BuildHasher,
+    sync::{atomic::AtomicU64, Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::{bail, Result};
 use chrono::{prelude::*, TimeDelta};
-use tokio::sync::RwLock;
+use thiserror::Error;
+use tokio::{
+    sync::{broadcast::Sender, RwLock},
+    task::JoinHandle,
+    time::interval,
+};
 
-use crate::message::Message;
+use crate::{dump, glob::glob_match, handler::distribute_message, message::Message};
+
+type Entry = (DbValue, Option<DateTime<Utc>>);
+
+// Random picks that hit an expired key are retried this often before
+// falling back to a full scan.
+const RANDOM_KEY_TRIES: usize = 100;
+
+// The active expiry checks this many keys with an expiry per round and
+// runs another round right away when more than a quarter of them were expired.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+const EXPIRY_SAMPLE_SIZE: usize = 20;
+// Upper bound of entries looked at while collecting a sample.
+const EXPIRY_SCAN_LIMIT: usize = EXPIRY_SAMPLE_SIZE * 10;
+
+fn is_expired(expire_date: &Option<DateTime<Utc>>) -> bool {
+    match expire_date {
+        Some(date) => Utc::now() > *date,
+        None => false,
+    }
+}
+
+pub fn expire_date_from_now(millis: i64) -> Result<DateTime<Utc>> {
+    match TimeDelta::try_milliseconds(millis).and_then(|delta| Utc::now().checked_add_signed(delta))
+    {
+        Some(date) => Ok(date),
+        None => bail!("expire date is out of range"),
+    }
+}
+
+// The expire date of RESTORE, its ttl of 0 means no expiry.
+pub fn restore_expire_date(ttl: i64, absttl: bool) -> Result<Option<DateTime<Utc>>> {
+    if ttl == 0 {
+        return Ok(None);
+    }
+    let expire_date = if absttl {
+        DateTime::from_timestamp_millis(ttl)
+    } else {
+        expire_date_from_now(ttl).ok()
+    };
+    match expire_date {
+        Some(date) => Ok(Some(date)),
+        None => bail!(DbError::InvalidValue("Invalid TTL value, out of range")),
+    }
+}
+
+// The size of a stored value in bytes.
+fn value_len(value: &Message) -> usize {
+    match value.as_bytes() {
+        Some(content) => content.len(),
+        None => value.to_data().len(),
+    }
+}
+
+fn random_index(len: usize) -> usize {
+    let random = RandomState::new().hash_one(Utc::now().timestamp_subsec_nanos());
+    (random % len as u64) as usize
+}
+
+fn parse_integer(message: &Message) -> Result<i64> {
+    match message {
+        Message::BulkString(value) | Message::SimpleString(value) => match value.parse::<i64>() {
+            Ok(value) => Ok(value),
+            Err(_) => bail!(DbError::InvalidValue(
+                "value is not an integer or out of range"
+            )),
+        },
+        _ => bail!(DbError::InvalidValue(
+            "value is not an integer or out of range"
+        )),
+    }
+}
+
+fn parse_float(message: &Message) -> Result<f64> {
+    match message {
+        Message::BulkString(value) | Message::SimpleString(value) => match value.parse::<f64>() {
+            Ok(value) if value.is_finite() => Ok(value),
+            _ => bail!(DbError::InvalidValue("value is not a valid float")),
+        },
+        _ => bail!(DbError::InvalidValue("value is not a valid float")),
+    }
+}
+
+// Formats like redis, without exponent and trailing zeros. Redis adds in
+// long double and prints 17 digits, which hides the rounding error of
+// e.g. 0.1 + 0.2. With f64 the same is reached by rounding to the 15
+// digits an f64 always holds, the shortest form of that is printed.
+pub fn format_float(value: f64) -> String {
+    match format!("{:.14e}", value).parse::<f64>() {
+        Ok(rounded) if rounded.is_finite() => rounded.to_string(),
+        _ => value.to_string(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetExpiry {
+    // relative to now
+    Milliseconds(i64),
+    // absolute unix time
+    UnixMilliseconds(i64),
+    // keep the expiry of the current value
+    KeepTtl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetCondition {
+    Always,
+    IfNotExists,
+    IfExists,
+}
+
+// Errors caused by the stored data, their text is the reply to the client.
+#[derive(Error, Debug, PartialEq)]
+pub enum DbError {
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR {0}")]
+    InvalidValue(&'static str),
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+}
+
+// How redis would store a hash. Only reported, the storage is the same.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashEncoding {
+    Listpack,
+    Hashtable,
+}
+
+// Up to these limits a hash stays listpack encoded, from
+// hash-max-listpack-entries and hash-max-listpack-value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ListpackLimits {
+    pub max_entries: usize,
+    pub max_value: usize,
+}
+
+impl Default for ListpackLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 128,
+            max_value: 64,
+        }
+    }
+}
+
+// How redis would store a string. An appended string stays raw, the
+// others are reported by their content.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StringEncoding {
+    Compact,
+    Raw,
+}
+
+// The value stored for a key, tagged with its redis type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DbValue {
+    String(Message, StringEncoding),
+    List(VecDeque<Message>),
+    Hash(HashMap<String, Message>, HashEncoding),
+    Set(HashSet<Message>),
+}
+
+impl DbValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(..) => "string",
+            Self::List(_) => "list",
+            Self::Hash(..) => "hash",
+            Self::Set(_) => "set",
+        }
+    }
+
+    // The encoding OBJECT ENCODING reports, like redis 7.2.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Self::String(Message::BulkString(value), StringEncoding::Compact)
+                if value.len() <= 20 =>
+            {
+                // like string2ll, a sign prefix or leading zeros are no integer
+                if value
+                    .parse::<i64>()
+                    .is_ok_and(|integer| integer.to_string() == *value)
+                {
+                    "int"
+                } else {
+                    "embstr"
+                }
+            }
+            Self::String(value, StringEncoding::Compact) if value_len(value) <= 44 => "embstr",
+            Self::String(..) => "raw",
+            // list-max-listpack-size is not supported, redis 7 reports
+            // listpack for small lists where this reports quicklist
+            Self::List(_) => "quicklist",
+            Self::Hash(_, HashEncoding::Listpack) => "listpack",
+            Self::Hash(_, HashEncoding::Hashtable) => "hashtable",
+            // the intset and listpack encodings of small sets are not supported
+            Self::Set(_) => "hashtable",
+        }
+    }
+
+    fn as_string(&self) -> Result<&Message> {
+        match self {
+            Self::String(m, _) => Ok(m),
+            _ => bail!(DbError::WrongType),
+        }
+    }
+}
+
+// How SINTERSTORE, SUNIONSTORE and SDIFFSTORE combine their sets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetOperation {
+    Intersection,
+    Union,
+    Difference,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TtlResult {
+    Missing,
+    NoExpiry,
+    // remaining milliseconds
+    Remaining(i64),
+}
+
+pub struct KeyspaceStats {
+    pub keys: usize,
+    pub expires: usize,
+    // average remaining time to live in milliseconds of the keys with an expiry
+    pub avg_ttl: i64,
+}
+
+// What happens to a key after its expire date passed.
+enum ExpiredKeys {
+    // Removed when found, a DEL is sent to the replicas if there are any.
+    Remove(Option<(Sender<Message>, Arc<AtomicU64>)>),
+    // A replica only hides them, they are removed with the DEL of the leader
+    // so both stay consistent.
+    KeepForLeader,
+}
+
+// A key watched by transactions and how often it was written since
+// the first WATCH.
+struct WatchedKey {
+    watchers: usize,
+    version: u64,
+}
 
 pub struct Db {
-    storage: RwLock<HashMap<Message, (Message, Option<DateTime<Utc>>)>>,
+    storage: RwLock<HashMap<Message, Entry>>,
+    // Orders the keys for SCAN, fixed for the lifetime of the db so a
+    // cursor stays valid between calls.
+    scan_hasher: RandomState,
+    expired_keys: ExpiredKeys,
+    // Only watched keys are versioned, an unwatched key costs nothing.
+    watched: Mutex<HashMap<Message, WatchedKey>>,
 }
 
 impl Db {
-    pub fn new() -> Self {
+    // A leader db, keys it expires are deleted on the replicas with DEL.
+    pub fn new_leader(sender: Sender<Message>, repl_offset: Arc<AtomicU64>) -> Self {
+        Self::with_expired_keys(ExpiredKeys::Remove(Some((sender, repl_offset))))
+    }
+
+    // A replica db, expired keys are kept until the leader deletes them.
+    pub fn new_follower() -> Self {
+        Self::with_expired_keys(ExpiredKeys::KeepForLeader)
+    }
+
+    fn with_expired_keys(expired_keys: ExpiredKeys) -> Self {
         Self {
             storage: RwLock::new(HashMap::new()),
+            scan_hasher: RandomState::new(),
+            expired_keys,
+            watched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Starts watching the key, returns its current version.
+    pub fn watch(&self, key: &Message) -> u64 {
+        let mut watched = self.watched.lock().unwrap();
+        let watched_key = watched.entry(key.clone()).or_insert(WatchedKey {
+            watchers: 0,
+            version: 0,
+        });
+        watched_key.watchers += 1;
+        watched_key.version
+    }
+
+    // Ends one watch of the key, the version is forgotten with the last one.
+    pub fn unwatch(&self, key: &Message) {
+        let mut watched = self.watched.lock().unwrap();
+        if let Some(watched_key) = watched.get_mut(key) {
+            watched_key.watchers -= 1;
+            if watched_key.watchers == 0 {
+                watched.remove(key);
+            }
+        }
+    }
+
+    // The number of writes to a watched key, 0 for keys nobody watches.
+    pub fn version_of(&self, key: &Message) -> u64 {
+        let watched = self.watched.lock().unwrap();
+        watched
+            .get(key)
+            .map_or(0, |watched_key| watched_key.version)
+    }
+
+    // Marks a write to the key for the transactions watching it.
+    fn touch(&self, key: &Message) {
+        if let Some(watched_key) = self.watched.lock().unwrap().get_mut(key) {
+            watched_key.version += 1;
+        }
+    }
+
+    fn touch_all(&self) {
+        for watched_key in self.watched.lock().unwrap().values_mut() {
+            watched_key.version += 1;
+        }
+    }
+
+    fn removes_expired(&self) -> bool {
+        matches!(self.expired_keys, ExpiredKeys::Remove(_))
+    }
+
+    // Removes a key found to be expired and tells the replicas.
+    fn remove_expired(&self, map: &mut HashMap<Message, Entry>, key: &Message) {
+        map.remove(key);
+        self.touch(key);
+        if let ExpiredKeys::Remove(Some((sender, repl_offset))) = &self.expired_keys {
+            let del = Message::Array(vec![Message::BulkString("DEL".to_string()), key.clone()]);
+            distribute_message(sender, repl_offset, &del);
+        }
+    }
+
+    pub async fn get(&self, key: &Message) -> Result<Option<Message>> {
+        {
+            let map = self.storage.read().await;
+            match map.get(key) {
+                Some((value, expire_date)) if !is_expired(expire_date) => {
+                    return Ok(Some(value.as_string()?.clone()))
+                }
+                None => return Ok(None),
+                Some(_) if !self.removes_expired() => return Ok(None),
+                Some(_) => {}
+            }
+        }
+
+        // The entry is expired, remove it. The key is checked again as it
+        // could have been set between giving up the read lock and getting the write lock.
+        let mut map = self.storage.write().await;
+        if let Some((_, expire_date)) = map.get(key) {
+            if is_expired(expire_date) {
+                self.remove_expired(&mut map, key);
+            }
+        }
+
+        match map.get(key) {
+            Some((value, _)) => Ok(Some(value.as_string()?.clone())),
+            None => Ok(None),
         }
     }
 
-    pub async fn get(&self, key: &Message) -> Option<Message> {
+    pub async fn exists(&self, key: &Message) -> bool {
         let map = self.storage.read().await;
         match map.get(key) {
-            Some((m, expire_date)) => {
-                let now = Utc::now();
-                if expire_date.is_none() || now <= expire_date.unwrap() {
-                    Some(m.clone())
-                } else {
-                    // TODO: remove entry
-                    Some(Message::NullBulkString)
-                }
+            Some((_, expire_date)) => !is_expired(expire_date),
+            None => false,
+        }
+    }
+
+    // Returns the type name of the value at key.
+    pub async fn value_type(&self, key: &Message) -> Option<&'static str> {
+        let map = self.storage.read().await;
+        match map.get(key) {
+            Some((value, expire_date)) if !is_expired(expire_date) => Some(value.type_name()),
+            _ => None,
+        }
+    }
+
+    // Changes the expiry of an existing key. Returns whether the key exists.
+    pub async fn set_expiry(&self, key: &Message, expire_date: Option<DateTime<Utc>>) -> bool {
+        let mut map = self.storage.write().await;
+        match map.get_mut(key) {
+            Some((_, current)) if !is_expired(current) => {
+                *current = expire_date;
+                self.touch(key);
+                true
             }
-            None => None,
+            _ => false,
         }
     }
 
-    // expire time in milliseconds
-    pub async fn set(
+    // Removes the expiry of a key. Returns whether the key had one.
+    pub async fn persist(&self, key: &Message) -> bool {
+        let mut map = self.storage.write().await;
+        match map.get_mut(key) {
+            Some((_, current @ Some(_))) if !is_expired(current) => {
+                *current = None;
+                self.touch(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub async fn ttl_millis(&self, key: &Message) -> TtlResult {
+        let map = self.storage.read().await;
+        match map.get(key) {
+            Some((_, expire_date)) if is_expired(expire_date) => TtlResult::Missing,
+            Some((_, Some(date))) => TtlResult::Remaining((*date - Utc::now()).num_milliseconds()),
+            Some((_, None)) => TtlResult::NoExpiry,
+            None => TtlResult::Missing,
+        }
+    }
+
+    // Sets the value if the condition holds. Returns whether the value was
+    // written and the previous string value of the key. With get the
+    // previous value has to be a string.
+    pub async fn set_with(
         &self,
         key: Message,
         value: Message,
-        expire_milliseconds: Option<i64>,
-    ) -> Result<()> {
+        expiry: Option<SetExpiry>,
+        condition: SetCondition,
+        get: bool,
+    ) -> Result<(bool, Option<Message>)> {
         let mut map = self.storage.write().await;
 
-        let expire_time = match expire_milliseconds {
-            Some(millis) => {
-                let timedelta = TimeDelta::try_milliseconds(millis);
-                if let Some(delta) = timedelta {
-                    Some(Utc::now().add(delta))
-                } else {
-                    bail!("timedelta cannot be constructed");
+        let (exists, old_value, old_expire_date) = match map.get(&key) {
+            Some((value, expire_date)) if !is_expired(expire_date) => match value {
+                DbValue::String(m, _) => (true, Some(m.clone()), *expire_date),
+                _ if get => bail!(DbError::WrongType),
+                _ => (true, None, *expire_date),
+            },
+            _ => (false, None, None),
+        };
+
+        let write = match condition {
+            SetCondition::Always => true,
+            SetCondition::IfNotExists => !exists,
+            SetCondition::IfExists => exists,
+        };
+        if !write {
+            return Ok((false, old_value));
+        }
+
+        let expire_time = match expiry {
+            Some(SetExpiry::Milliseconds(millis)) => match expire_date_from_now(millis) {
+                Ok(date) => Some(date),
+                Err(_) => bail!(DbError::InvalidValue(
+                    "invalid expire time in 'set' command"
+                )),
+            },
+            Some(SetExpiry::UnixMilliseconds(millis)) => {
+                match DateTime::from_timestamp_millis(millis) {
+                    Some(date) => Some(date),
+                    None => bail!(DbError::InvalidValue(
+                        "invalid expire time in 'set' command"
+                    )),
                 }
             }
+            Some(SetExpiry::KeepTtl) => old_expire_date,
             None => None,
         };
 
-        map.insert(key, (value, expire_time));
-        Ok(())
+        self.touch(&key);
+        map.insert(
+            key,
+            (DbValue::String(value, StringEncoding::Compact), expire_time),
+        );
+        Ok((true, old_value))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Adds delta to the integer stored at key, a missing key counts as 0.
+    // The expiry of the key is kept. Returns the new value.
+    pub async fn increment_by(&self, key: &Message, delta: i64) -> Result<i64> {
+        let mut map = self.storage.write().await;
 
-    #[tokio::test]
-    async fn test_expired_entry() {
-        let db = Db::new();
-        let key = Message::SimpleString("key".to_string());
-        let value = Message::SimpleString("value".to_string());
-        db.set(key.clone(), value, Some(-100)).await.unwrap();
+        let (current, expire_date) = match map.get(key) {
+            Some((value, expire_date)) if !is_expired(expire_date) => {
+                (parse_integer(value.as_string()?)?, *expire_date)
+            }
+            _ => (0, None),
+        };
 
-        let val = db.get(&key).await.unwrap();
+        let Some(new_value) = current.checked_add(delta) else {
+            bail!(DbError::InvalidValue(
+                "increment or decrement would overflow"
+            ));
+        };
+
+        map.insert(
+            key.clone(),
+            (
+                DbValue::String(
+                    Message::BulkString(new_value.to_string()),
+                    StringEncoding::Compact,
+                ),
+                expire_date,
+            ),
+        );
+        self.touch(key);
+        Ok(new_value)
+    }
+
+    // Adds delta to the float stored at key, a missing key counts as 0.
+    // Returns the new value as it is stored.
+    pub async fn increment_by_float(&self, key: &Message, delta: f64) -> Result<String> {
+        let mut map = self.storage.write().await;
+
+        let (current, expire_date) = match map.get(key) {
+            Some((value, expire_date)) if !is_expired(expire_date) => {
+                (parse_float(value.as_string()?)?, *expire_date)
+            }
+            _ => (0.0, None),
+        };
+
+        let new_value = current + delta;
+        if !new_value.is_finite() {
+            bail!(DbError::InvalidValue(
+                "increment would produce NaN or Infinity"
+            ));
+        }
+
+        let formatted = format_float(new_value);
+        map.insert(
+            key.clone(),
+            (
+                DbValue::String(
+                    Message::BulkString(formatted.clone()),
+                    StringEncoding::Compact,
+                ),
+                expire_date,
+            ),
+        );
+        self.touch(key);
+        Ok(formatted)
+    }
+
+    // Appends to the string at key or creates it, the expiry is kept.
+    // Returns the length of the new string.
+    pub async fn append(&self, key: &Message, value: &[u8]) -> Result<usize> {
+        let mut map = self.storage.write().await;
+
+        let (mut current, expire_date) = match map.get(key) {
+            Some((value, expire_date)) if !is_expired(expire_date) => (
+                value.as_string()?.as_bytes().unwrap_or_default().to_vec(),
+                *expire_date,
+            ),
+            _ => (vec![], None),
+        };
+
+        current.extend_from_slice(value);
+        let len = current.len();
+        map.insert(
+            key.clone(),
+            (
+                DbValue::String(Message::bulk_from_bytes(current), StringEncoding::Raw),
+                expire_date,
+            ),
+        );
+        self.touch(key);
+        Ok(len)
+    }
+
+    // The bytes from start to end, both included. Negative offsets count
+    // from the end, like GETRANGE the range is clamped to the value.
+    pub async fn get_range(&self, key: &Message, start: i64, end: i64) -> Result<Message> {
+        let value = self.get(key).await?;
+        let content = value
+            .as_ref()
+            .and_then(Message::as_bytes)
+            .unwrap_or_default();
+        let len = content.len() as i64;
+
+        if start < 0 && end < 0 && start > end {
+            return Ok(Message::BulkString(String::new()));
+        }
+        let start = if start < 0 { len + start } else { start }.max(0);
+        let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+        if start > end || len == 0 {
+            return Ok(Message::BulkString(String::new()));
+        }
+        Ok(Message::bulk_from_bytes(
+            content[start as usize..=end as usize].to_vec(),
+        ))
+    }
+
+    // Pushes the values one after the other to the given end of the list,
+    // the list is created if needed. Returns the new length of the list.
+    pub async fn push(&self, key: &Message, values: &[Message], end: ListEnd) -> Result<usize> {
+        let mut map = self.storage.write().await;
+
+        if map
+            .get(key)
+            .is_some_and(|(_, expire_date)| is_expired(expire_date))
+        {
+            map.remove(key);
+        }
+        let (value, _) = map
+            .entry(key.clone())
+            .or_insert_with(|| (DbValue::List(VecDeque::new()), None));
+        let DbValue::List(list) = value else {
+            bail!(DbError::WrongType);
+        };
+
+        for value in values {
+            match end {
+                ListEnd::Left => list.push_front(value.clone()),
+                ListEnd::Right => list.push_back(value.clone()),
+            }
+        }
+        self.touch(key);
+        Ok(list.len())
+    }
+
+    // Returns the elements from start to stop, both inclusive. Negative
+    // indexes count from the end, out of range indexes are clamped.
+    pub async fn range(&self, key: &Message, start: i64, stop: i64) -> Result<Vec<Message>> {
+        let map = self.storage.read().await;
+        let list = match map.get(key) {
+            Some((DbValue::List(list), expire_date)) if !is_expired(expire_date) => list,
+            Some((_, expire_date)) if !is_expired(expire_date) => bail!(DbError::WrongType),
+            _ => return Ok(vec![]),
+        };
+
+        let len = list.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return Ok(vec![]);
+        }
+
+        Ok(list
+            .range(start as usize..=stop as usize)
+            .cloned()
+            .collect())
+    }
+
+    // Removes up to count elements from the given end of the list. An empty
+    // list is removed like in redis.
+    pub async fn pop(&self, key: &Message, end: ListEnd, count: usize) -> Result<Vec<Message>> {
+        let mut map = self.storage.write().await;
+        let list = match map.get_mut(key) {
+            Some((DbValue::List(list), expire_date)) if !is_expired(expire_date) => list,
+            Some((_, expire_date)) if !is_expired(expire_date) => bail!(DbError::WrongType),
+            _ => return Ok(vec![]),
+        };
+
+        let count = count.min(list.len());
+        let popped = match end {
+            ListEnd::Left => list.drain(..count).collect(),
+            ListEnd::Right => list.drain(list.len() - count..).rev().collect(),
+        };
+
+        if list.is_empty() {
+            map.remove(key);
+        }
+        if count > 0 {
+            self.touch(key);
+        }
+        Ok(popped)
+    }
+
+    // Sets the fields of the hash, the hash is created if needed.
+    // Returns the number of new fields.
+    // A listpack hash becomes a hashtable once it grows beyond the limits,
+    // it is never converted back.
+    pub async fn hset(
+        &self,
+        key: &Message,
+        fields: &[(String, Message)],
+        limits: &ListpackLimits,
+    ) -> Result<usize> {
+        let mut map = self.storage.write().await;
+
+        if map
+            .get(key)
+            .is_some_and(|(_, expire_date)| is_expired(expire_date))
+        {
+            map.remove(key);
+        }
+        let (value, _) = map
+            .entry(key.clone())
+            .or_insert_with(|| (DbValue::Hash(HashMap::new(), HashEncoding::Listpack), None));
+        let DbValue::Hash(hash, encoding) = value else {
+            bail!(DbError::WrongType);
+        };
+
+        let mut added = 0;
+        for (field, value) in fields {
+            if field.len() > limits.max_value || value_len(value) > limits.max_value {
+                *encoding = HashEncoding::Hashtable;
+            }
+            if hash.insert(field.clone(), value.clone()).is_none() {
+                added += 1;
+            }
+        }
+        if hash.len() > limits.max_entries {
+            *encoding = HashEncoding::Hashtable;
+        }
+        self.touch(key);
+        Ok(added)
+    }
+
+    pub async fn hget(&self, key: &Message, field: &str) -> Result<Option<Message>> {
+        let map = self.storage.read().await;
+        match map.get(key) {
+            Some((DbValue::Hash(hash, _), expire_date)) if !is_expired(expire_date) => {
+                Ok(hash.get(field).cloned())
+            }
+            Some((_, expire_date)) if !is_expired(expire_date) => bail!(DbError::WrongType),
+            _ => Ok(None),
+        }
+    }
+
+    // Returns all fields and values sorted by field, the order of the
+    // map itself would differ between calls.
+    pub async fn hgetall(&self, key: &Message) -> Result<Vec<(String, Message)>> {
+        let map = self.storage.read().await;
+        let hash = match map.get(key) {
+            Some((DbValue::Hash(hash, _), expire_date)) if !is_expired(expire_date) => hash,
+            Some((_, expire_date)) if !is_expired(expire_date) => bail!(DbError::WrongType),
+            _ => return Ok(vec![]),
+        };
+
+        let mut fields = hash
+            .iter()
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(fields)
+    }
+
+    // Removes the fields, an empty hash is removed. Returns the number of
+    // removed fields.
+    pub async fn hdel(&self, key: &Message, fields: &[String]) -> Result<usize> {
+        let mut map = self.storage.write().await;
+        let hash = match map.get_mut(key) {
+            Some((DbValue::Hash(hash, _), expire_date)) if !is_expired(expire_date) => hash,
+            Some((_, expire_date)) if !is_expired(expire_date) => bail!(DbError::WrongType),
+            _ => return Ok(0),
+        };
+
+        let removed = fields
+            .iter()
+            .filter(|field| hash.remove(*field).is_some())
+            .count();
+        if hash.is_empty() {
+            map.remove(key);
+        }
+        if removed > 0 {
+            self.touch(key);
+        }
+        Ok(removed)
+    }
+
+    // Adds the members to the set, the set is created if needed.
+    // Returns the number of new members.
+    pub async fn sadd(&self, key: &Message, members: &[Message]) -> Result<usize> {
+        let mut map = self.storage.write().await;
+
+        if map
+            .get(key)
+            .is_some_and(|(_, expire_date)| is_expired(expire_date))
+        {
+            map.remove(key);
+        }
+        let (value, _) = map
+            .entry(key.clone())
+            .or_insert_with(|| (DbValue::Set(HashSet::new()), None));
+        let DbValue::Set(set) = value else {
+            bail!(DbError::WrongType);
+        };
+
+        let added = members
+            .iter()
+            .filter(|member| set.insert((*member).clone()))
+            .count();
+        self.touch(key);
+        Ok(added)
+    }
+
+    // Returns the members sorted, the order of the set itself would
+    // differ between calls.
+    pub async fn smembers(&self, key: &Message) -> Result<Vec<Message>> {
+        let map = self.storage.read().await;
+        let set = match map.get(key) {
+            Some((DbValue::Set(set), expire_date)) if !is_expired(expire_date) => set,
+            Some((_, expire_date)) if !is_expired(expire_date) => bail!(DbError::WrongType),
+            _ => return Ok(vec![]),
+        };
+
+        let mut members = set.iter().cloned().collect::<Vec<_>>();
+        members.sort_by(|a, b| a.as_bytes().cmp(&b.as_bytes()));
+        Ok(members)
+    }
+
+    // Stores the combination of the sets at keys in destination, whatever
+    // destination held before is overwritten. Like redis, an empty result
+    // deletes destination instead of storing an empty set.
+    // Returns the size of the result and whether destination changed.
+    pub async fn set_store(
+        &self,
+        destination: &Message,
+        operation: SetOperation,
+        keys: &[Message],
+    ) -> Result<(usize, bool)> {
+        let mut map = self.storage.write().await;
+
+        let result = {
+            let empty = HashSet::new();
+            let mut sets = vec![];
+            for key in keys {
+                sets.push(match map.get(key) {
+                    Some((DbValue::Set(set), expire_date)) if !is_expired(expire_date) => set,
+                    Some((_, expire_date)) if !is_expired(expire_date) => {
+                        bail!(DbError::WrongType)
+                    }
+                    _ => &empty,
+                });
+            }
+
+            let mut sets = sets.into_iter();
+            let mut result = sets.next().cloned().unwrap_or_default();
+            for set in sets {
+                match operation {
+                    SetOperation::Intersection => result.retain(|member| set.contains(member)),
+                    SetOperation::Union => result.extend(set.iter().cloned()),
+                    SetOperation::Difference => result.retain(|member| !set.contains(member)),
+                }
+            }
+            result
+        };
+
+        let len = result.len();
+        let changed = if result.is_empty() {
+            map.remove(destination).is_some()
+        } else {
+            map.insert(destination.clone(), (DbValue::Set(result), None));
+            true
+        };
+        if changed {
+            self.touch(destination);
+        }
+        Ok((len, changed))
+    }
+
+    pub async fn list_len(&self, key: &Message) -> Result<usize> {
+        let map = self.storage.read().await;
+        match map.get(key) {
+            Some((DbValue::List(list), expire_date)) if !is_expired(expire_date) => Ok(list.len()),
+            Some((_, expire_date)) if !is_expired(expire_date) => bail!(DbError::WrongType),
+            _ => Ok(0),
+        }
+    }
+
+    pub async fn clear(&self) {
+        self.storage.write().await.clear();
+        self.touch_all();
+    }
+
+    // The value of the key as DUMP payload.
+    pub async fn dump(&self, key: &Message) -> Option<Vec<u8>> {
+        let map = self.storage.read().await;
+        match map.get(key) {
+            Some((value, expire_date)) if !is_expired(expire_date) => Some(dump::dump(value)),
+            _ => None,
+        }
+    }
+
+    // Stores the value of a RESTORE, an existing key is only overwritten
+    // with replace. A value restored with a past expire date is not stored.
+    pub async fn restore(
+        &self,
+        key: &Message,
+        value: DbValue,
+        expire_date: Option<DateTime<Utc>>,
+        replace: bool,
+    ) -> Result<()> {
+        let mut map = self.storage.write().await;
+        if !replace
+            && map
+                .get(key)
+                .is_some_and(|(_, expire_date)| !is_expired(expire_date))
+        {
+            bail!(DbError::BusyKey);
+        }
+
+        if is_expired(&expire_date) {
+            map.remove(key);
+        } else {
+            map.insert(key.clone(), (value, expire_date));
+        }
+        self.touch(key);
+        Ok(())
+    }
+
+    pub async fn encoding(&self, key: &Message) -> Option<&'static str> {
+        let map = self.storage.read().await;
+        match map.get(key) {
+            Some((value, expire_date)) if !is_expired(expire_date) => Some(value.encoding()),
+            _ => None,
+        }
+    }
+
+    // The number of keys that are not expired yet.
+    pub async fn len_live(&self) -> usize {
+        let map = self.storage.read().await;
+        map.values()
+            .filter(|(_, expire_date)| !is_expired(expire_date))
+            .count()
+    }
+
+    pub async fn keyspace_stats(&self) -> KeyspaceStats {
+        let map = self.storage.read().await;
+        let now = Utc::now();
+        let mut keys = 0;
+        let mut ttls = vec![];
+
+        for (_, expire_date) in map.values() {
+            if is_expired(expire_date) {
+                continue;
+            }
+
+            keys += 1;
+            if let Some(date) = expire_date {
+                ttls.push((*date - now).num_milliseconds());
+            }
+        }
+
+        let avg_ttl = if ttls.is_empty() {
+            0
+        } else {
+            ttls.iter().sum::<i64>() / ttls.len() as i64
+        };
+
+        KeyspaceStats {
+            keys,
+            expires: ttls.len(),
+            avg_ttl,
+        }
+    }
+
+    // Removes the keys, returns how many of them existed.
+    pub async fn delete(&self, keys: &[Message]) -> usize {
+        let mut map = self.storage.write().await;
+        keys.iter()
+            .filter(|key| {
+                let removed = map.remove(*key);
+                if removed.is_some() {
+                    self.touch(key);
+                }
+                removed.is_some_and(|(_, expire_date)| !is_expired(&expire_date))
+            })
+            .count()
+    }
+
+    // Periodically removes expired keys which are never read again. A
+    // replica leaves that to its leader and runs no sweeper.
+    pub fn spawn_expiry_sweeper(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(EXPIRY_SWEEP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                while self.remove_expired_sample().await > EXPIRY_SAMPLE_SIZE / 4 {}
+            }
+        })
+    }
+
+    // Samples keys with an expiry starting at a random position and removes
+    // the expired ones. The sample is taken under the read lock, the write
+    // lock is only held to remove the found keys. Returns the removed count.
+    async fn remove_expired_sample(&self) -> usize {
+        if !self.removes_expired() {
+            return 0;
+        }
+
+        let expired = {
+            let map = self.storage.read().await;
+            if map.is_empty() {
+                return 0;
+            }
+
+            let start = random_index(map.len());
+            map.iter()
+                .skip(start)
+                .chain(map.iter().take(start))
+                .take(EXPIRY_SCAN_LIMIT)
+                .filter(|(_, (_, expire_date))| expire_date.is_some())
+                .take(EXPIRY_SAMPLE_SIZE)
+                .filter(|(_, (_, expire_date))| is_expired(expire_date))
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>()
+        };
+
+        if expired.is_empty() {
+            return 0;
+        }
+
+        let mut map = self.storage.write().await;
+        let mut removed = 0;
+        for key in expired {
+            // check again, the key could have been set in between
+            if map
+                .get(&key)
+                .is_some_and(|(_, expire_date)| is_expired(expire_date))
+            {
+                self.remove_expired(&mut map, &key);
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    // All keys matching the glob pattern, keys that are not bulk strings
+    // never match.
+    pub async fn keys(&self, pattern: &str) -> Vec<Message> {
+        let map = self.storage.read().await;
+        map.iter()
+            .filter(|(_, (_, expire_date))| !is_expired(expire_date))
+            .filter(|(key, _)| match key {
+                Message::BulkString(key) => glob_match(pattern, key),
+                _ => false,
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    // Returns up to `count` keys whose hash is at least `cursor` and the
+    // cursor for the next call, 0 once every key was returned. The cursor
+    // only grows, so a key present for the whole scan is always returned.
+    // Keys with the same hash are never split between two calls.
+    pub async fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&str>,
+    ) -> (u64, Vec<Message>) {
+        let map = self.storage.read().await;
+        let mut hashed = map
+            .iter()
+            .filter(|(_, (_, expire_date))| !is_expired(expire_date))
+            .map(|(key, _)| (self.scan_hasher.hash_one(key), key))
+            .filter(|(hash, _)| *hash >= cursor)
+            .collect::<Vec<_>>();
+
+        // Only the `count` smallest hashes are needed, they are moved to
+        // the front without sorting the rest. Later keys sharing the last
+        // hash are returned with them.
+        let mut next_cursor = 0;
+        if hashed.len() > count {
+            let (_, &mut (last_hash, _), _) =
+                hashed.select_nth_unstable_by_key(count - 1, |(hash, _)| *hash);
+            for (hash, key) in hashed.split_off(count) {
+                if hash == last_hash {
+                    hashed.push((hash, key));
+                } else if next_cursor == 0 || hash < next_cursor {
+                    next_cursor = hash;
+                }
+            }
+        }
+
+        let keys = hashed
+            .into_iter()
+            .map(|(_, key)| key)
+            .filter(|key| match (pattern, key) {
+                (None, _) => true,
+                (Some(pattern), Message::BulkString(key)) => glob_match(pattern, key),
+                _ => false,
+            })
+            .cloned()
+            .collect();
+        (next_cursor, keys)
+    }
+
+    // Returns a random key which is not expired, expired keys hit on the way are removed.
+    pub async fn random_key(&self) -> Option<Message> {
+        let mut map = self.storage.write().await;
+
+        for _ in 0..RANDOM_KEY_TRIES {
+            if map.is_empty() {
+                return None;
+            }
+
+            let key = map.keys().nth(random_index(map.len()))?.clone();
+            if !is_expired(&map[&key].1) {
+                return Some(key);
+            }
+            if self.removes_expired() {
+                self.remove_expired(&mut map, &key);
+            }
+        }
+
+        let expired = map
+            .iter()
+            .filter(|(_, (_, expire_date))| is_expired(expire_date))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        if self.removes_expired() {
+            for key in &expired {
+                self.remove_expired(&mut map, key);
+            }
+        }
+        map.keys().find(|key| !expired.contains(key)).cloned()
+    }
+}
+
+#[cfg(test)]
+impl Db {
+    // A leader db without replicas.
+    pub fn new() -> Self {
+        Self::with_expired_keys(ExpiredKeys::Remove(None))
+    }
+
+    // All stored keys, including the expired ones not removed yet.
+    pub async fn keyspace_len(&self) -> usize {
+        self.storage.read().await.len()
+    }
+
+    // expire time in milliseconds
+    pub async fn set(
+        &self,
+        key: Message,
+        value: Message,
+        expire_milliseconds: Option<i64>,
+    ) -> Result<()> {
+        self.set_with(
+            key,
+            value,
+            expire_milliseconds.map(SetExpiry::Milliseconds),
+            SetCondition::Always,
+            false,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_expired_entry() {
+        let db = Db::new();
+        let key = Message::SimpleString("key".to_string());
+        let value = Message::SimpleString("value".to_string());
+        db.set(key.clone(), value, Some(-100)).await.unwrap();
+
+        let val = db.get(&key).await.unwrap();
+
+        assert_eq!(None, val);
+        assert!(!db.storage.read().await.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn test_writes_bump_version_of_watched_keys() {
+        let db = Db::new();
+        assert_eq!(0, db.watch(&key("key")));
+
+        db.set(key("key"), key("value"), None).await.unwrap();
+        db.set(key("other"), key("value"), None).await.unwrap();
+        assert_eq!(1, db.version_of(&key("key")));
+        db.delete(&[key("key")]).await;
+        assert_eq!(2, db.version_of(&key("key")));
+        db.hdel(&key("key"), &["field".to_string()]).await.unwrap();
+        assert_eq!(2, db.version_of(&key("key")));
+
+        db.unwatch(&key("key"));
+        assert!(db.watched.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_leader_sends_del_for_expired_entry() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel(1);
+        let db = Db::new_leader(tx, Arc::new(AtomicU64::new(0)));
+        db.set(key("key"), key("value"), Some(-100)).await.unwrap();
+
+        assert_eq!(None, db.get(&key("key")).await.unwrap());
+        assert_eq!(
+            Message::Array(vec![Message::BulkString("DEL".to_string()), key("key")]),
+            rx.recv().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_follower_keeps_expired_entry_until_delete() {
+        let db = Db::new_follower();
+        db.set(key("key"), key("value"), Some(-100)).await.unwrap();
+
+        assert_eq!(None, db.get(&key("key")).await.unwrap());
+        assert_eq!(None, db.random_key().await);
+        assert_eq!(0, db.remove_expired_sample().await);
+        assert!(db.storage.read().await.contains_key(&key("key")));
+
+        // an expired key does not count as deleted
+        assert_eq!(0, db.delete(&[key("key")]).await);
+        assert!(db.storage.read().await.is_empty());
+    }
+
+    fn key(name: &str) -> Message {
+        Message::BulkString(name.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_set_with_conditions() {
+        let db = Db::new();
+        let value1 = Message::BulkString("value1".to_string());
+        let value2 = Message::BulkString("value2".to_string());
+
+        let result = db
+            .set_with(
+                key("key"),
+                value1.clone(),
+                None,
+                SetCondition::IfExists,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!((false, None), result);
+        assert_eq!(None, db.get(&key("key")).await.unwrap());
+
+        let result = db
+            .set_with(
+                key("key"),
+                value1.clone(),
+                None,
+                SetCondition::IfNotExists,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!((true, None), result);
+
+        let result = db
+            .set_with(
+                key("key"),
+                value2.clone(),
+                None,
+                SetCondition::IfNotExists,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!((false, Some(value1.clone())), result);
+
+        let result = db
+            .set_with(
+                key("key"),
+                value2.clone(),
+                None,
+                SetCondition::IfExists,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!((true, Some(value1)), result);
+        assert_eq!(Some(value2), db.get(&key("key")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_with_expired_key_counts_as_missing() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("key"), value.clone(), Some(-100)).await.unwrap();
+
+        let result = db
+            .set_with(key("key"), value, None, SetCondition::IfNotExists, false)
+            .await
+            .unwrap();
+        assert_eq!((true, None), result);
+    }
+
+    #[tokio::test]
+    async fn test_set_with_keep_ttl() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("key"), value.clone(), Some(100000))
+            .await
+            .unwrap();
+        db.set_with(
+            key("key"),
+            value.clone(),
+            Some(SetExpiry::KeepTtl),
+            SetCondition::Always,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(1, db.keyspace_stats().await.expires);
+
+        db.set(key("key"), value, None).await.unwrap();
+        assert_eq!(0, db.keyspace_stats().await.expires);
+    }
+
+    #[tokio::test]
+    async fn test_set_with_unix_time() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        let past = Utc::now().timestamp_millis() - 1000;
+        db.set_with(
+            key("key"),
+            value,
+            Some(SetExpiry::UnixMilliseconds(past)),
+            SetCondition::Always,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(None, db.get(&key("key")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_increment_by_creates_key() {
+        let db = Db::new();
+
+        assert_eq!(5, db.increment_by(&key("counter"), 5).await.unwrap());
+        assert_eq!(3, db.increment_by(&key("counter"), -2).await.unwrap());
+        assert_eq!(
+            Some(Message::BulkString("3".to_string())),
+            db.get(&key("counter")).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_increment_by_keeps_expiry() {
+        let db = Db::new();
+        db.set(key("counter"), key("1"), Some(100000))
+            .await
+            .unwrap();
+
+        db.increment_by(&key("counter"), 1).await.unwrap();
+        assert_eq!(1, db.keyspace_stats().await.expires);
+    }
+
+    #[tokio::test]
+    async fn test_increment_by_not_an_integer() {
+        let db = Db::new();
+        db.set(key("text"), key("abc"), None).await.unwrap();
+        db.set(key("float"), key("1.5"), None).await.unwrap();
+
+        for name in ["text", "float"] {
+            let result = db.increment_by(&key(name), 1).await;
+            assert_eq!(
+                "ERR value is not an integer or out of range",
+                result.unwrap_err().to_string()
+            );
+        }
+        assert_eq!(Some(key("abc")), db.get(&key("text")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_increment_by_overflow() {
+        let db = Db::new();
+        db.set(key("max"), key(&i64::MAX.to_string()), None)
+            .await
+            .unwrap();
+        db.set(key("min"), key(&i64::MIN.to_string()), None)
+            .await
+            .unwrap();
+
+        assert!(db.increment_by(&key("max"), 1).await.is_err());
+        assert!(db.increment_by(&key("min"), -1).await.is_err());
+        assert_eq!(
+            Some(key(&i64::MAX.to_string())),
+            db.get(&key("max")).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_increment_by_float() {
+        let db = Db::new();
+        db.set(key("float"), key("10.5"), None).await.unwrap();
+
+        assert_eq!(
+            "10.6",
+            db.increment_by_float(&key("float"), 0.1).await.unwrap()
+        );
+        assert_eq!(
+            "3000",
+            db.increment_by_float(&key("new"), 3.0e3).await.unwrap()
+        );
+        assert_eq!(Some(key("3000")), db.get(&key("new")).await.unwrap());
+
+        db.increment_by_float(&key("sum"), 0.1).await.unwrap();
+        assert_eq!(
+            "0.3",
+            db.increment_by_float(&key("sum"), 0.2).await.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_float() {
+        assert_eq!("0.3", format_float(0.1 + 0.2));
+        assert_eq!("-1.5", format_float(-1.5));
+        assert_eq!("100000000000000000000", format_float(1e20));
+        assert_eq!("0.000001", format_float(1e-6));
+        assert_eq!(f64::MAX, format_float(f64::MAX).parse::<f64>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_increment_by_float_invalid() {
+        let db = Db::new();
+        db.set(key("text"), key("abc"), None).await.unwrap();
+        db.set(key("max"), key(&format_float(f64::MAX)), None)
+            .await
+            .unwrap();
+
+        let result = db.increment_by_float(&key("text"), 1.0).await;
+        assert_eq!(
+            "ERR value is not a valid float",
+            result.unwrap_err().to_string()
+        );
+        let result = db.increment_by_float(&key("max"), f64::MAX).await;
+        assert_eq!(
+            "ERR increment would produce NaN or Infinity",
+            result.unwrap_err().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let db = Db::new();
+
+        assert_eq!(5, db.append(&key("key"), b"Hello").await.unwrap());
+        db.set_with(
+            key("key"),
+            key("Hello"),
+            Some(SetExpiry::Milliseconds(100000)),
+            SetCondition::Always,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(11, db.append(&key("key"), b" World").await.unwrap());
+
+        assert_eq!(Some(key("Hello World")), db.get(&key("key")).await.unwrap());
+        assert_eq!(1, db.keyspace_stats().await.expires);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_millis() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("persistent"), value.clone(), None)
+            .await
+            .unwrap();
+        db.set(key("volatile"), value.clone(), Some(10000))
+            .await
+            .unwrap();
+        db.set(key("expired"), value, Some(-100)).await.unwrap();
+
+        assert_eq!(TtlResult::NoExpiry, db.ttl_millis(&key("persistent")).await);
+        assert_eq!(TtlResult::Missing, db.ttl_millis(&key("missing")).await);
+        assert_eq!(TtlResult::Missing, db.ttl_millis(&key("expired")).await);
+        if let TtlResult::Remaining(millis) = db.ttl_millis(&key("volatile")).await {
+            assert!(millis > 9000 && millis <= 10000);
+        } else {
+            panic!("volatile key should have a remaining ttl");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_expiry_and_persist() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("key"), value, None).await.unwrap();
+
+        assert!(!db.persist(&key("key")).await);
+        let date = expire_date_from_now(10000).unwrap();
+        assert!(db.set_expiry(&key("key"), Some(date)).await);
+        assert!(matches!(
+            db.ttl_millis(&key("key")).await,
+            TtlResult::Remaining(_)
+        ));
+
+        assert!(db.persist(&key("key")).await);
+        assert_eq!(TtlResult::NoExpiry, db.ttl_millis(&key("key")).await);
+    }
+
+    #[tokio::test]
+    async fn test_set_expiry_missing_key() {
+        let db = Db::new();
+        let date = expire_date_from_now(10000).unwrap();
+
+        assert!(!db.set_expiry(&key("missing"), Some(date)).await);
+        assert!(!db.persist(&key("missing")).await);
+        assert!(!db.exists(&key("missing")).await);
+    }
+
+    #[tokio::test]
+    async fn test_value_type() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("string"), value.clone(), None).await.unwrap();
+        db.set(key("expired"), value, Some(-100)).await.unwrap();
+
+        assert_eq!(Some("string"), db.value_type(&key("string")).await);
+        assert_eq!(None, db.value_type(&key("expired")).await);
+        assert_eq!(None, db.value_type(&key("missing")).await);
+    }
+
+    fn values(names: &[&str]) -> Vec<Message> {
+        names.iter().map(|name| key(name)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_push_creates_list() {
+        let db = Db::new();
+
+        assert_eq!(
+            2,
+            db.push(&key("list"), &values(&["a", "b"]), ListEnd::Right)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            4,
+            db.push(&key("list"), &values(&["c", "d"]), ListEnd::Left)
+                .await
+                .unwrap()
+        );
+
+        assert_eq!(
+            values(&["d", "c", "a", "b"]),
+            db.range(&key("list"), 0, -1).await.unwrap()
+        );
+        assert_eq!(4, db.list_len(&key("list")).await.unwrap());
+        assert_eq!(0, db.list_len(&key("missing")).await.unwrap());
+        assert_eq!(Some("list"), db.value_type(&key("list")).await);
+    }
+
+    #[tokio::test]
+    async fn test_range_indexes() {
+        let db = Db::new();
+        db.push(&key("list"), &values(&["a", "b", "c", "d"]), ListEnd::Right)
+            .await
+            .unwrap();
+        let list = key("list");
+        let range = |start, stop| db.range(&list, start, stop);
+
+        assert_eq!(values(&["b", "c"]), range(1, 2).await.unwrap());
+        assert_eq!(values(&["c", "d"]), range(-2, -1).await.unwrap());
+        assert_eq!(
+            values(&["a", "b", "c", "d"]),
+            range(-100, 100).await.unwrap()
+        );
+        assert!(range(3, 1).await.unwrap().is_empty());
+        assert!(range(5, 10).await.unwrap().is_empty());
+        assert!(range(0, -5).await.unwrap().is_empty());
+        assert!(db.range(&key("missing"), 0, -1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pop() {
+        let db = Db::new();
+        db.push(&key("list"), &values(&["a", "b", "c", "d"]), ListEnd::Right)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            values(&["a"]),
+            db.pop(&key("list"), ListEnd::Left, 1).await.unwrap()
+        );
+        assert_eq!(
+            values(&["d", "c"]),
+            db.pop(&key("list"), ListEnd::Right, 2).await.unwrap()
+        );
+        assert_eq!(
+            values(&["b"]),
+            db.pop(&key("list"), ListEnd::Left, 10).await.unwrap()
+        );
+
+        assert!(!db.exists(&key("list")).await);
+        assert!(db
+            .pop(&key("list"), ListEnd::Left, 1)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, Message)> {
+        pairs
+            .iter()
+            .map(|(field, value)| (field.to_string(), key(value)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_hash_fields() {
+        let db = Db::new();
+
+        assert_eq!(
+            2,
+            db.hset(
+                &key("hash"),
+                &fields(&[("b", "1"), ("a", "2")]),
+                &ListpackLimits::default()
+            )
+            .await
+            .unwrap()
+        );
+        assert_eq!(
+            1,
+            db.hset(
+                &key("hash"),
+                &fields(&[("a", "3"), ("c", "4")]),
+                &ListpackLimits::default()
+            )
+            .await
+            .unwrap()
+        );
+
+        assert_eq!(Some(key("3")), db.hget(&key("hash"), "a").await.unwrap());
+        assert_eq!(None, db.hget(&key("hash"), "missing").await.unwrap());
+        assert_eq!(
+            fields(&[("a", "3"), ("b", "1"), ("c", "4")]),
+            db.hgetall(&key("hash")).await.unwrap()
+        );
+        assert_eq!(Some("hash"), db.value_type(&key("hash")).await);
+    }
+
+    #[tokio::test]
+    async fn test_hash_encoding_converts_once() {
+        let db = Db::new();
+        let limits = ListpackLimits {
+            max_entries: 2,
+            max_value: 4,
+        };
+
+        db.hset(&key("small"), &fields(&[("a", "1"), ("b", "2")]), &limits)
+            .await
+            .unwrap();
+        assert_eq!(Some("listpack"), db.encoding(&key("small")).await);
+
+        db.hset(
+            &key("many"),
+            &fields(&[("a", "1"), ("b", "2"), ("c", "3")]),
+            &limits,
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some("hashtable"), db.encoding(&key("many")).await);
+        // shrinking does not convert it back
+        db.hdel(&key("many"), &["a".to_string(), "b".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(Some("hashtable"), db.encoding(&key("many")).await);
+
+        db.hset(&key("large"), &fields(&[("a", "12345")]), &limits)
+            .await
+            .unwrap();
+        assert_eq!(Some("hashtable"), db.encoding(&key("large")).await);
+    }
+
+    #[tokio::test]
+    async fn test_string_encoding() {
+        let db = Db::new();
+        for (value, encoding) in [
+            ("12345", "int"),
+            ("-12345", "int"),
+            ("+5", "embstr"),
+            ("007", "embstr"),
+            ("-0", "embstr"),
+            ("value", "embstr"),
+            ("123456789012345678901", "embstr"),
+            (&"x".repeat(45) as &str, "raw"),
+        ] {
+            db.set(key("key"), key(value), None).await.unwrap();
+            assert_eq!(Some(encoding), db.encoding(&key("key")).await);
+        }
+        assert_eq!(None, db.encoding(&key("missing")).await);
+    }
+
+    #[tokio::test]
+    async fn test_appended_string_is_raw() {
+        let db = Db::new();
+        db.append(&key("key"), b"ab").await.unwrap();
+        assert_eq!(Some("raw"), db.encoding(&key("key")).await);
+        db.append(&key("number"), b"12").await.unwrap();
+        assert_eq!(Some("raw"), db.encoding(&key("number")).await);
+
+        // a new value is encoded by its content again
+        db.set(key("key"), key("ab"), None).await.unwrap();
+        assert_eq!(Some("embstr"), db.encoding(&key("key")).await);
+        db.increment_by(&key("number"), 1).await.unwrap();
+        assert_eq!(Some("int"), db.encoding(&key("number")).await);
+    }
+
+    #[tokio::test]
+    async fn test_hdel_removes_empty_hash() {
+        let db = Db::new();
+        db.hset(
+            &key("hash"),
+            &fields(&[("a", "1"), ("b", "2")]),
+            &ListpackLimits::default(),
+        )
+        .await
+        .unwrap();
+
+        let to_delete = vec!["a".to_string(), "missing".to_string()];
+        assert_eq!(1, db.hdel(&key("hash"), &to_delete).await.unwrap());
+        assert!(db.exists(&key("hash")).await);
+        assert_eq!(1, db.hdel(&key("hash"), &["b".to_string()]).await.unwrap());
+        assert!(!db.exists(&key("hash")).await);
+        assert_eq!(0, db.hdel(&key("hash"), &["b".to_string()]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_store_overwrites_destination() {
+        let db = Db::new();
+        db.sadd(&key("a"), &values(&["1", "2"])).await.unwrap();
+        db.set(key("dest"), key("value"), Some(1000)).await.unwrap();
+
+        let stored = db
+            .set_store(
+                &key("dest"),
+                SetOperation::Union,
+                &[key("a"), key("missing")],
+            )
+            .await
+            .unwrap();
+        assert_eq!((2, true), stored);
+        assert_eq!(
+            values(&["1", "2"]),
+            db.smembers(&key("dest")).await.unwrap()
+        );
+        assert_eq!(Some("set"), db.value_type(&key("dest")).await);
+        assert_eq!(TtlResult::NoExpiry, db.ttl_millis(&key("dest")).await);
+
+        db.set(key("string"), key("1"), None).await.unwrap();
+        assert_wrong_type(
+            db.set_store(
+                &key("dest"),
+                SetOperation::Intersection,
+                &[key("a"), key("string")],
+            )
+            .await,
+        );
+        assert_wrong_type(db.sadd(&key("string"), &values(&["1"])).await);
+    }
+
+    fn assert_wrong_type<T: std::fmt::Debug>(result: Result<T>) {
+        assert_eq!(
+            Some(&DbError::WrongType),
+            result.unwrap_err().downcast_ref::<DbError>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_type() {
+        let db = Db::new();
+        db.push(&key("list"), &values(&["a"]), ListEnd::Right)
+            .await
+            .unwrap();
+        db.set(key("string"), key("1"), None).await.unwrap();
+
+        assert_wrong_type(db.get(&key("list")).await);
+        assert_wrong_type(db.increment_by(&key("list"), 1).await);
+        assert_wrong_type(db.increment_by_float(&key("list"), 1.0).await);
+        assert_wrong_type(db.append(&key("list"), b"a").await);
+        assert_wrong_type(
+            db.set_with(key("list"), key("a"), None, SetCondition::Always, true)
+                .await,
+        );
+        assert_wrong_type(
+            db.push(&key("string"), &values(&["a"]), ListEnd::Left)
+                .await,
+        );
+        assert_wrong_type(db.range(&key("string"), 0, -1).await);
+        assert_wrong_type(db.list_len(&key("string")).await);
+        assert_wrong_type(db.pop(&key("string"), ListEnd::Right, 1).await);
+        assert_wrong_type(
+            db.hset(
+                &key("list"),
+                &fields(&[("a", "1")]),
+                &ListpackLimits::default(),
+            )
+            .await,
+        );
+        assert_wrong_type(db.hget(&key("list"), "a").await);
+        assert_wrong_type(db.hgetall(&key("string")).await);
+        assert_wrong_type(db.hdel(&key("string"), &["a".to_string()]).await);
+        assert_eq!(Some(key("1")), db.get(&key("string")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_replaces_list() {
+        let db = Db::new();
+        db.push(&key("key"), &values(&["a"]), ListEnd::Right)
+            .await
+            .unwrap();
+
+        db.set(key("key"), key("value"), None).await.unwrap();
+        assert_eq!(Some(key("value")), db.get(&key("key")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_exists() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("live"), value.clone(), None).await.unwrap();
+        db.set(key("expired"), value, Some(-100)).await.unwrap();
+
+        assert!(db.exists(&key("live")).await);
+        assert!(!db.exists(&key("expired")).await);
+        assert!(!db.exists(&key("missing")).await);
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_stats_skip_expired() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("key1"), value.clone(), None).await.unwrap();
+        db.set(key("key2"), value.clone(), Some(100000))
+            .await
+            .unwrap();
+        db.set(key("expired"), value, Some(-100)).await.unwrap();
+
+        let stats = db.keyspace_stats().await;
+        assert_eq!(2, stats.keys);
+        assert_eq!(1, stats.expires);
+        assert!(stats.avg_ttl > 90000 && stats.avg_ttl <= 100000);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_sweeper_removes_unread_key() {
+        let db = Arc::new(Db::new());
+        let value = Message::BulkString("value".to_string());
+        db.set(key("short"), value.clone(), Some(10)).await.unwrap();
+        db.set(key("persistent"), value, None).await.unwrap();
+
+        let sweeper = db.clone().spawn_expiry_sweeper();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        sweeper.abort();
+
+        let map = db.storage.read().await;
+        assert!(!map.contains_key(&key("short")));
+        assert!(map.contains_key(&key("persistent")));
+    }
+
+    #[tokio::test]
+    async fn test_remove_expired_sample_is_bounded() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        for i in 0..100 {
+            db.set(key(&format!("key{}", i)), value.clone(), Some(-100))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(EXPIRY_SAMPLE_SIZE, db.remove_expired_sample().await);
+        assert_eq!(80, db.storage.read().await.len());
+    }
+
+    #[tokio::test]
+    async fn test_random_key_skips_expired() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("live1"), value.clone(), None).await.unwrap();
+        db.set(key("live2"), value.clone(), Some(10000))
+            .await
+            .unwrap();
+        db.set(key("expired1"), value.clone(), Some(-100))
+            .await
+            .unwrap();
+        db.set(key("expired2"), value, Some(-100)).await.unwrap();
+
+        for _ in 0..50 {
+            let random = db.random_key().await.unwrap();
+            assert!(random == key("live1") || random == key("live2"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_len_live_and_clear() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("key1"), value.clone(), None).await.unwrap();
+        db.set(key("key2"), value.clone(), Some(10000))
+            .await
+            .unwrap();
+        db.set(key("expired"), value, Some(-100)).await.unwrap();
+
+        assert_eq!(2, db.len_live().await);
+        db.clear().await;
+        assert_eq!(0, db.len_live().await);
+        assert!(!db.exists(&key("key1")).await);
+    }
+
+    #[tokio::test]
+    async fn test_keys_skips_expired() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("user:1"), value.clone(), None).await.unwrap();
+        db.set(key("user:2"), value.clone(), None).await.unwrap();
+        db.set(key("user:3"), value.clone(), Some(-100))
+            .await
+            .unwrap();
+        db.set(key("other"), value, None).await.unwrap();
+
+        let mut keys = db.keys("user:*").await;
+        keys.sort_by_key(|key| key.to_string());
+        assert_eq!(vec![key("user:1"), key("user:2")], keys);
+        assert_eq!(3, db.keys("*").await.len());
+    }
+
+    #[tokio::test]
+    async fn test_scan_visits_every_key() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        for i in 0..25 {
+            db.set(key(&format!("key{}", i)), value.clone(), None)
+                .await
+                .unwrap();
+        }
+
+        let mut seen = vec![];
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next_cursor, keys) = db.scan(cursor, 3, None).await;
+            assert!(keys.len() <= 3);
+            seen.extend(keys);
+            calls += 1;
+
+            // changes during the scan don't hide the other keys
+            db.set(key(&format!("new{}", calls)), value.clone(), None)
+                .await
+                .unwrap();
+            if calls == 2 {
+                db.set(key("key0"), value.clone(), Some(-100))
+                    .await
+                    .unwrap();
+            }
+
+            if next_cursor == 0 {
+                break;
+            }
+            cursor = next_cursor;
+        }
+
+        assert!(calls > 1);
+        for i in 1..25 {
+            assert!(seen.contains(&key(&format!("key{}", i))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_returns_each_key_once() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        for i in 0..100 {
+            db.set(key(&format!("key{}", i)), value.clone(), None)
+                .await
+                .unwrap();
+        }
+
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            let (next_cursor, keys) = db.scan(cursor, 10, None).await;
+            assert_eq!(10, keys.len());
+            for key in keys {
+                assert!(seen.insert(key));
+            }
+            if next_cursor == 0 {
+                break;
+            }
+            assert!(next_cursor > cursor);
+            cursor = next_cursor;
+        }
+        assert_eq!(100, seen.len());
+    }
+
+    #[tokio::test]
+    async fn test_scan_with_pattern() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("user:1"), value.clone(), None).await.unwrap();
+        db.set(key("other"), value, None).await.unwrap();
+
+        assert_eq!(
+            (0, vec![key("user:1")]),
+            db.scan(0, 10, Some("user:*")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_random_key_only_expired() {
+        let db = Db::new();
+        let value = Message::BulkString("value".to_string());
+        db.set(key("expired1"), value.clone(), Some(-100))
+            .await
+            .unwrap();
+        db.set(key("expired2"), value, Some(-100)).await.unwrap();
 
-        assert_eq!(Message::NullBulkString, val);
+        assert_eq!(None, db.random_key().await);
+        assert!(db.storage.read().await.is_empty());
     }
 }
@@ -0,0 +1,619 @@
+// The payload of DUMP and RESTORE in the format of redis: the value in rdb
+// encoding, the rdb version as two bytes and a CRC64 of both, all little
+// endian. Strings, lists, hashes and sets are supported, they are written
+// in the plain rdb types every redis version loads. Besides those, the
+// intset, listpack and quicklist types redis 7 writes are read.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    db::{DbError, DbValue, HashEncoding, ListpackLimits, StringEncoding},
+    message::Message,
+};
+
+// The version of the rdb file sent to replicas, payloads of newer
+// versions are rejected like redis does.
+const RDB_VERSION: u16 = 11;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+const LZF_MAX_EXPANSION: usize = 88;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+pub fn dump(value: &DbValue) -> Vec<u8> {
+    let mut payload = vec![];
+    match value {
+        DbValue::String(value, _) => {
+            payload.push(RDB_TYPE_STRING);
+            write_string(&mut payload, &message_bytes(value));
+        }
+        DbValue::List(list) => {
+            payload.push(RDB_TYPE_LIST);
+            write_len(&mut payload, list.len() as u64);
+            for value in list {
+                write_string(&mut payload, &message_bytes(value));
+            }
+        }
+        DbValue::Set(set) => {
+            payload.push(RDB_TYPE_SET);
+            write_len(&mut payload, set.len() as u64);
+            for member in set {
+                write_string(&mut payload, &message_bytes(member));
+            }
+        }
+        DbValue::Hash(hash, _) => {
+            payload.push(RDB_TYPE_HASH);
+            write_len(&mut payload, hash.len() as u64);
+            for (field, value) in hash {
+                write_string(&mut payload, field.as_bytes());
+                write_string(&mut payload, &message_bytes(value));
+            }
+        }
+    }
+
+    payload.extend(RDB_VERSION.to_le_bytes());
+    payload.extend(crc64(&payload).to_le_bytes());
+    payload
+}
+
+pub fn restore(payload: &[u8], limits: &ListpackLimits) -> Result<DbValue> {
+    if payload.len() < 10 {
+        bail!(DbError::InvalidValue(
+            "DUMP payload version or checksum are wrong"
+        ));
+    }
+    let (body, crc) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes([body[body.len() - 2], body[body.len() - 1]]);
+    if version > RDB_VERSION || crc64(body).to_le_bytes() != crc {
+        bail!(DbError::InvalidValue(
+            "DUMP payload version or checksum are wrong"
+        ));
+    }
+
+    match read_value(&mut Reader::new(&body[..body.len() - 2]), limits) {
+        Some(value) => Ok(value),
+        None => bail!(DbError::InvalidValue("Bad data format")),
+    }
+}
+
+fn message_bytes(message: &Message) -> Vec<u8> {
+    match message.as_bytes() {
+        Some(content) => content.to_vec(),
+        None => message.to_data(),
+    }
+}
+
+// The crc-64-jones checksum of redis.
+fn crc64(data: &[u8]) -> u64 {
+    let mut crc = 0u64;
+    for byte in data {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn write_len(payload: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        payload.push(len as u8);
+    } else if len < 1 << 14 {
+        payload.push(0x40 | (len >> 8) as u8);
+        payload.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        payload.push(0x80);
+        payload.extend((len as u32).to_be_bytes());
+    } else {
+        payload.push(0x81);
+        payload.extend(len.to_be_bytes());
+    }
+}
+
+// Like redis, short strings holding an integer are stored as one.
+fn write_string(payload: &mut Vec<u8>, content: &[u8]) {
+    if content.len() <= 11 {
+        let integer = std::str::from_utf8(content)
+            .ok()
+            .and_then(|text| text.parse::<i64>().ok().map(|integer| (text, integer)));
+        if let Some((text, integer)) = integer {
+            // only if the integer prints as the same text, "01" stays a string
+            if integer.to_string() == text {
+                if let Ok(integer) = i8::try_from(integer) {
+                    payload.push(0xc0 | RDB_ENC_INT8);
+                    payload.extend(integer.to_le_bytes());
+                    return;
+                } else if let Ok(integer) = i16::try_from(integer) {
+                    payload.push(0xc0 | RDB_ENC_INT16);
+                    payload.extend(integer.to_le_bytes());
+                    return;
+                } else if let Ok(integer) = i32::try_from(integer) {
+                    payload.push(0xc0 | RDB_ENC_INT32);
+                    payload.extend(integer.to_le_bytes());
+                    return;
+                }
+            }
+        }
+    }
+
+    write_len(payload, content.len() as u64);
+    payload.extend_from_slice(content);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let taken = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(taken)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|taken| taken[0])
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+enum Len {
+    Plain(u64),
+    // the low six bits of a length byte starting with 11
+    Encoded(u8),
+}
+
+fn read_len(reader: &mut Reader) -> Option<Len> {
+    let first = reader.byte()?;
+    match first >> 6 {
+        0 => Some(Len::Plain((first & 0x3f) as u64)),
+        1 => Some(Len::Plain(
+            ((first & 0x3f) as u64) << 8 | reader.byte()? as u64,
+        )),
+        2 if first == 0x80 => Some(Len::Plain(
+            u32::from_be_bytes(reader.take(4)?.try_into().ok()?) as u64,
+        )),
+        2 if first == 0x81 => Some(Len::Plain(u64::from_be_bytes(
+            reader.take(8)?.try_into().ok()?,
+        ))),
+        3 => Some(Len::Encoded(first & 0x3f)),
+        _ => None,
+    }
+}
+
+fn read_plain_len(reader: &mut Reader) -> Option<usize> {
+    match read_len(reader)? {
+        Len::Plain(len) => usize::try_from(len).ok(),
+        Len::Encoded(_) => None,
+    }
+}
+
+fn read_string(reader: &mut Reader) -> Option<Vec<u8>> {
+    let integer = match read_len(reader)? {
+        Len::Plain(len) => return Some(reader.take(usize::try_from(len).ok()?)?.to_vec()),
+        Len::Encoded(RDB_ENC_INT8) => i8::from_le_bytes(reader.take(1)?.try_into().ok()?) as i64,
+        Len::Encoded(RDB_ENC_INT16) => i16::from_le_bytes(reader.take(2)?.try_into().ok()?) as i64,
+        Len::Encoded(RDB_ENC_INT32) => i32::from_le_bytes(reader.take(4)?.try_into().ok()?) as i64,
+        Len::Encoded(RDB_ENC_LZF) => {
+            let compressed_len = read_plain_len(reader)?;
+            let len = read_plain_len(reader)?;
+            return lzf_decompress(reader.take(compressed_len)?, len);
+        }
+        Len::Encoded(_) => return None,
+    };
+    Some(integer.to_string().into_bytes())
+}
+
+// The length comes from the payload, it is checked against what the input
+// can expand to before anything is allocated: a back reference of three
+// bytes produces at most 264 bytes.
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    if len > input.len().saturating_mul(LZF_MAX_EXPANSION) {
+        return None;
+    }
+    let mut output = Vec::with_capacity(len);
+    let mut reader = Reader::new(input);
+    while !reader.is_empty() {
+        let ctrl = reader.byte()? as usize;
+        if ctrl < 32 {
+            output.extend_from_slice(reader.take(ctrl + 1)?);
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += reader.byte()? as usize;
+            }
+            let back = ((ctrl & 0x1f) << 8) + reader.byte()? as usize + 1;
+            let start = output.len().checked_sub(back)?;
+            // the reference can overlap the bytes it produces
+            for i in 0..run + 2 {
+                output.push(output[start + i]);
+            }
+        }
+        if output.len() > len {
+            return None;
+        }
+    }
+    (output.len() == len).then_some(output)
+}
+
+// The entries of a listpack, integers as their decimal text.
+fn read_listpack(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut reader = Reader::new(data);
+    let total = u32::from_le_bytes(reader.take(4)?.try_into().ok()?) as usize;
+    if total != data.len() {
+        return None;
+    }
+    reader.take(2)?;
+
+    let mut entries = vec![];
+    loop {
+        let start = reader.pos;
+        let encoding = reader.byte()?;
+        let entry = if encoding == 0xff {
+            return reader.is_empty().then_some(entries);
+        } else if encoding & 0x80 == 0 {
+            (encoding as i64).to_string().into_bytes()
+        } else if encoding & 0xc0 == 0x80 {
+            reader.take((encoding & 0x3f) as usize)?.to_vec()
+        } else if encoding & 0xe0 == 0xc0 {
+            let integer = ((encoding & 0x1f) as i64) << 8 | reader.byte()? as i64;
+            // 13 bit two's complement
+            let integer = if integer >= 1 << 12 {
+                integer - (1 << 13)
+            } else {
+                integer
+            };
+            integer.to_string().into_bytes()
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = ((encoding & 0x0f) as usize) << 8 | reader.byte()? as usize;
+            reader.take(len)?.to_vec()
+        } else {
+            match encoding {
+                0xf0 => {
+                    let len = u32::from_le_bytes(reader.take(4)?.try_into().ok()?) as usize;
+                    reader.take(len)?.to_vec()
+                }
+                0xf1 => read_listpack_int(&mut reader, 2)?,
+                0xf2 => read_listpack_int(&mut reader, 3)?,
+                0xf3 => read_listpack_int(&mut reader, 4)?,
+                0xf4 => read_listpack_int(&mut reader, 8)?,
+                _ => return None,
+            }
+        };
+
+        // every entry ends with its length for reading backwards
+        let entry_len = reader.pos - start;
+        let backlen_len = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        reader.take(backlen_len)?;
+        entries.push(entry);
+    }
+}
+
+// The integers of an intset: their size, their count and the integers,
+// all little endian.
+fn read_intset(data: &[u8]) -> Option<HashSet<Message>> {
+    let mut reader = Reader::new(data);
+    let size = u32::from_le_bytes(reader.take(4)?.try_into().ok()?) as usize;
+    let len = u32::from_le_bytes(reader.take(4)?.try_into().ok()?) as usize;
+    if ![2, 4, 8].contains(&size) {
+        return None;
+    }
+
+    let mut set = HashSet::new();
+    for _ in 0..len {
+        let integer = read_listpack_int(&mut reader, size)?;
+        set.insert(Message::bulk_from_bytes(integer));
+    }
+    reader.is_empty().then_some(set)
+}
+
+fn read_listpack_int(reader: &mut Reader, size: usize) -> Option<Vec<u8>> {
+    let mut bytes = [0; 8];
+    bytes[..size].copy_from_slice(reader.take(size)?);
+    // sign extend to 64 bit
+    let shift = 64 - size * 8;
+    let integer = (i64::from_le_bytes(bytes) << shift) >> shift;
+    Some(integer.to_string().into_bytes())
+}
+
+fn read_value(reader: &mut Reader, limits: &ListpackLimits) -> Option<DbValue> {
+    match reader.byte()? {
+        RDB_TYPE_STRING => Some(DbValue::String(
+            Message::bulk_from_bytes(read_string(reader)?),
+            StringEncoding::Compact,
+        )),
+        RDB_TYPE_LIST => {
+            let len = read_plain_len(reader)?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                list.push_back(Message::bulk_from_bytes(read_string(reader)?));
+            }
+            Some(DbValue::List(list))
+        }
+        RDB_TYPE_LIST_QUICKLIST_2 => {
+            let nodes = read_plain_len(reader)?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                let container = read_len(reader)?;
+                let node = read_string(reader)?;
+                match container {
+                    Len::Plain(QUICKLIST_NODE_PLAIN) => {
+                        list.push_back(Message::bulk_from_bytes(node))
+                    }
+                    Len::Plain(QUICKLIST_NODE_PACKED) => list.extend(
+                        read_listpack(&node)?
+                            .into_iter()
+                            .map(Message::bulk_from_bytes),
+                    ),
+                    _ => return None,
+                }
+            }
+            Some(DbValue::List(list))
+        }
+        RDB_TYPE_HASH => {
+            let len = read_plain_len(reader)?;
+            let mut fields = vec![];
+            for _ in 0..len {
+                fields.push((read_string(reader)?, read_string(reader)?));
+            }
+            hash_from_fields(fields, limits)
+        }
+        RDB_TYPE_HASH_LISTPACK => {
+            let entries = read_listpack(&read_string(reader)?)?;
+            if entries.len() % 2 != 0 {
+                return None;
+            }
+            let mut entries = entries.into_iter();
+            let mut fields = vec![];
+            while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+                fields.push((field, value));
+            }
+            hash_from_fields(fields, limits)
+        }
+        RDB_TYPE_SET => {
+            let len = read_plain_len(reader)?;
+            let mut set = HashSet::new();
+            for _ in 0..len {
+                set.insert(Message::bulk_from_bytes(read_string(reader)?));
+            }
+            Some(DbValue::Set(set))
+        }
+        RDB_TYPE_SET_INTSET => read_intset(&read_string(reader)?).map(DbValue::Set),
+        RDB_TYPE_SET_LISTPACK => Some(DbValue::Set(
+            read_listpack(&read_string(reader)?)?
+                .into_iter()
+                .map(Message::bulk_from_bytes)
+                .collect(),
+        )),
+        // sorted sets and streams are not supported
+        _ => None,
+    }
+}
+
+// Fields of the hashes are text, a binary field is bad data here.
+fn hash_from_fields(fields: Vec<(Vec<u8>, Vec<u8>)>, limits: &ListpackLimits) -> Option<DbValue> {
+    let mut encoding = HashEncoding::Listpack;
+    if fields.len() > limits.max_entries {
+        encoding = HashEncoding::Hashtable;
+    }
+
+    let mut hash = HashMap::new();
+    for (field, value) in fields {
+        if field.len() > limits.max_value || value.len() > limits.max_value {
+            encoding = HashEncoding::Hashtable;
+        }
+        hash.insert(
+            String::from_utf8(field).ok()?,
+            Message::bulk_from_bytes(value),
+        );
+    }
+    Some(DbValue::Hash(hash, encoding))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn restore_default(payload: &[u8]) -> Result<DbValue> {
+        restore(payload, &ListpackLimits::default())
+    }
+
+    // Adds the rdb version and checksum to a value.
+    fn with_footer(mut payload: Vec<u8>, version: u16) -> Vec<u8> {
+        payload.extend(version.to_le_bytes());
+        payload.extend(crc64(&payload).to_le_bytes());
+        payload
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(0xe9c6_d914_c4b8_d9ca, crc64(b"123456789"));
+    }
+
+    #[test]
+    fn test_restore_payload_of_redis() {
+        // `SET mykey 10` and `DUMP mykey` on redis, from its documentation
+        let payload = b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n";
+
+        assert_eq!(
+            DbValue::String(
+                Message::BulkString("10".to_string()),
+                StringEncoding::Compact
+            ),
+            restore_default(payload).unwrap()
+        );
+        assert_eq!(
+            with_footer(b"\x00\xc0\n".to_vec(), RDB_VERSION),
+            dump(&restore_default(payload).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_restore_quicklist_and_listpack() {
+        // `RPUSH list a 1 -100 5000` as redis 7 writes it
+        let listpack = b"\x13\x00\x00\x00\x04\x00\x81a\x02\x01\x01\xdf\x9c\x02\xf1\x88\x13\x03\xff";
+        let mut payload = vec![RDB_TYPE_LIST_QUICKLIST_2, 1, QUICKLIST_NODE_PACKED as u8];
+        payload.push(listpack.len() as u8);
+        payload.extend(listpack);
+
+        let list = ["a", "1", "-100", "5000"]
+            .iter()
+            .map(|value| Message::BulkString(value.to_string()))
+            .collect();
+        assert_eq!(
+            DbValue::List(list),
+            restore_default(&with_footer(payload, RDB_VERSION)).unwrap()
+        );
+
+        // `HSET hash field 300`
+        let listpack = b"\x11\x00\x00\x00\x02\x00\x85field\x06\xc1\x2c\x02\xff";
+        let mut payload = vec![RDB_TYPE_HASH_LISTPACK, listpack.len() as u8];
+        payload.extend(listpack);
+
+        let hash = HashMap::from([("field".to_string(), Message::BulkString("300".to_string()))]);
+        assert_eq!(
+            DbValue::Hash(hash, HashEncoding::Listpack),
+            restore_default(&with_footer(payload, RDB_VERSION)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_restore_intset() {
+        // `SADD set 1 -2`, an intset of 16 bit integers
+        let intset = b"\x02\x00\x00\x00\x02\x00\x00\x00\xfe\xff\x01\x00";
+        let mut payload = vec![RDB_TYPE_SET_INTSET, intset.len() as u8];
+        payload.extend(intset);
+
+        let set = HashSet::from([
+            Message::BulkString("1".to_string()),
+            Message::BulkString("-2".to_string()),
+        ]);
+        assert_eq!(
+            DbValue::Set(set),
+            restore_default(&with_footer(payload, RDB_VERSION)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_restore_lzf_string() {
+        // "aaaaaaaaaa": the literal `a`, then 9 bytes from one byte back
+        let compressed = b"\x00a\xe0\x00\x00";
+        let mut payload = vec![RDB_TYPE_STRING, 0xc0 | RDB_ENC_LZF, 5, 10];
+        payload.extend(compressed);
+
+        assert_eq!(
+            DbValue::String(Message::BulkString("a".repeat(10)), StringEncoding::Compact),
+            restore_default(&with_footer(payload, RDB_VERSION)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_restore_rejects_forged_lzf_length() {
+        let compressed = b"\x00a\xe0\x00\x00";
+        let mut payload = vec![RDB_TYPE_STRING, 0xc0 | RDB_ENC_LZF, 5, 0x81];
+        payload.extend((1u64 << 50).to_be_bytes());
+        payload.extend(compressed);
+        assert_eq!(
+            "ERR Bad data format",
+            restore_default(&with_footer(payload, RDB_VERSION))
+                .unwrap_err()
+                .to_string()
+        );
+
+        // the data expands to more than the declared length
+        let mut payload = vec![RDB_TYPE_STRING, 0xc0 | RDB_ENC_LZF, 5, 4];
+        payload.extend(compressed);
+        assert!(restore_default(&with_footer(payload, RDB_VERSION)).is_err());
+    }
+
+    #[test]
+    fn test_dump_and_restore_round_trip() {
+        let values = [
+            DbValue::String(
+                Message::BulkBytes(vec![0, 0xff, b'x']),
+                StringEncoding::Compact,
+            ),
+            DbValue::String(
+                Message::BulkString("x".repeat(20000)),
+                StringEncoding::Compact,
+            ),
+            DbValue::List(VecDeque::from([
+                Message::BulkString("-70000".to_string()),
+                Message::BulkString("01".to_string()),
+            ])),
+            DbValue::Hash(
+                HashMap::from([("f".to_string(), Message::BulkString("v".to_string()))]),
+                HashEncoding::Listpack,
+            ),
+            DbValue::Set(HashSet::from([
+                Message::BulkString("a".to_string()),
+                Message::BulkString("100000".to_string()),
+            ])),
+        ];
+
+        for value in values {
+            assert_eq!(value, restore_default(&dump(&value)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_restore_rejects_bad_payloads() {
+        let mut payload = dump(&DbValue::String(
+            Message::BulkString("v".to_string()),
+            StringEncoding::Compact,
+        ));
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert_eq!(
+            "ERR DUMP payload version or checksum are wrong",
+            restore_default(&payload).unwrap_err().to_string()
+        );
+
+        let newer = with_footer(vec![RDB_TYPE_STRING, 1, b'v'], RDB_VERSION + 1);
+        assert!(restore_default(&newer).is_err());
+
+        // a sorted set
+        let zset = with_footer(vec![3, 1, 1, b'v'], RDB_VERSION);
+        assert_eq!(
+            "ERR Bad data format",
+            restore_default(&zset).unwrap_err().to_string()
+        );
+    }
+}
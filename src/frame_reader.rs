@@ -0,0 +1,99 @@
+use anyhow::{bail, Result};
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    message::Message,
+    parser::{parse_messages, parse_rdb_file, ParseLimits},
+};
+
+// Reads complete messages from a stream. Bytes of a message split across
+// several reads are kept in the buffer until the rest arrives. Messages
+// are parsed one at a time, the caller decides how the next one is read.
+pub struct FrameReader<R> {
+    reader: R,
+    buffer: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: BytesMut::with_capacity(1024),
+        }
+    }
+
+    pub async fn read_message(&mut self) -> Result<Message> {
+        loop {
+            let parsed = parse_messages(&mut self.buffer, 1, &ParseLimits::UNLIMITED)?;
+            if let Some(message) = parsed.into_iter().next() {
+                return Ok(message);
+            }
+            self.read_more().await?;
+        }
+    }
+
+    // The rdb file the leader sends after FULLRESYNC.
+    pub async fn read_rdb_file(&mut self) -> Result<Message> {
+        loop {
+            if let Some(rdb_file) = parse_rdb_file(&mut self.buffer)? {
+                return Ok(rdb_file);
+            }
+            self.read_more().await?;
+        }
+    }
+
+    async fn read_more(&mut self) -> Result<()> {
+        let n = self.reader.read_buf(&mut self.buffer).await?;
+        if n == 0 {
+            bail!("connection closed by peer");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_messages_split_in_small_chunks() {
+        let first = Message::Array(vec![
+            Message::BulkString("SET".to_string()),
+            Message::BulkString("key".to_string()),
+            Message::BulkString("a longer value".to_string()),
+        ]);
+        let second = Message::SimpleString("OK".to_string());
+        let mut data = first.to_data();
+        data.extend(second.to_data());
+
+        // the duplex buffer only holds 3 bytes, so each read gets at most 3
+        let (mut writer, reader) = duplex(3);
+        tokio::spawn(async move {
+            writer.write_all(&data).await.unwrap();
+        });
+
+        let mut frame_reader = FrameReader::new(reader);
+        assert_eq!(first, frame_reader.read_message().await.unwrap());
+        assert_eq!(second, frame_reader.read_message().await.unwrap());
+        assert!(frame_reader.read_message().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_several_messages_in_one_read() {
+        let mut data = Message::SimpleString("one".to_string()).to_data();
+        data.extend(Message::Integer(2).to_data());
+
+        let mut frame_reader = FrameReader::new(&data[..]);
+        assert_eq!(
+            Message::SimpleString("one".to_string()),
+            frame_reader.read_message().await.unwrap()
+        );
+        assert_eq!(
+            Message::Integer(2),
+            frame_reader.read_message().await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,153 @@
+// Glob-style pattern matching as used by KEYS, supports `*`, `?`,
+// `[...]` character classes (with `^` negation and `a-z` ranges) and `\`
+// to escape a special character.
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    match_from(&pattern, &text)
+}
+
+// Like redis, a single loop which only remembers the last star: when the
+// pattern after it fails, the star takes one more character. Earlier stars
+// never have to be revisited, so matching stays linear per star.
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // the pattern after the last star and the text it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            backtrack = Some((p, t));
+            continue;
+        }
+        if let Some(next) = match_single(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+            continue;
+        }
+        match backtrack {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                backtrack = Some((star_p, t));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Matches one character of text at pattern[p], returns the position after
+// the matched part of the pattern.
+fn match_single(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    let rest = pattern.get(p + 1..)?;
+    let (matched, next) = match pattern[p] {
+        '?' => (true, p + 1),
+        '[' => {
+            let (matched, rest) = match_class(rest, c);
+            (matched, pattern.len() - rest.len())
+        }
+        '\\' if !rest.is_empty() => (rest[0] == c, p + 2),
+        other => (other == c, p + 1),
+    };
+    matched.then_some(next)
+}
+
+// Matches `c` against the class after the opening `[`, returns the result
+// and the pattern after the closing `]`. An unclosed class runs to the end
+// of the pattern.
+fn match_class(pattern: &[char], c: char) -> (bool, &[char]) {
+    let (negate, mut pattern) = match pattern.first() {
+        Some('^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => break,
+            [']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            ['\\', escaped, rest @ ..] => {
+                matched |= *escaped == c;
+                pattern = rest;
+            }
+            [start, '-', end, rest @ ..] if *end != ']' => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= low <= c && c <= high;
+                pattern = rest;
+            }
+            [other, rest @ ..] => {
+                matched |= *other == c;
+                pattern = rest;
+            }
+        }
+    }
+
+    (matched != negate, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_star() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("a*c", "abbbc"));
+        assert!(glob_match("a*c", "ac"));
+        assert!(!glob_match("a*c", "abd"));
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert!(glob_match("user:*", "user:1"));
+        assert!(glob_match("user:*", "user:"));
+        assert!(!glob_match("user:*", "users:1"));
+    }
+
+    #[test]
+    fn test_question_mark() {
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(!glob_match("h?llo", "heello"));
+    }
+
+    #[test]
+    fn test_character_class() {
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hbllo"));
+        assert!(!glob_match("h[a-b]llo", "hcllo"));
+    }
+
+    #[test]
+    fn test_many_stars_are_fast() {
+        let text = "a".repeat(10000);
+        let start = std::time::Instant::now();
+        assert!(!glob_match("*a*a*a*a*a*a*a*a*a*a*a*a*b", &text));
+        assert!(glob_match("*a*a*a*a*a*a*a*a*a*a*a*a", &text));
+        assert!(glob_match(&"?".repeat(10000), &text));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+    }
+}
@@ -1,3 +1,5 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use tokio::sync::broadcast::Sender;
 
 use crate::message::Message;
@@ -5,7 +7,10 @@ use crate::message::Message;
 pub mod client_server;
 pub mod replication;
 
-pub fn distribute_message(sender: &Sender<Message>, message: &Message) {
+// The replication offset grows by the size of every propagated write,
+// replicas acknowledge the offset they processed.
+pub fn distribute_message(sender: &Sender<Message>, offset: &AtomicU64, message: &Message) {
+    offset.fetch_add(message.to_data().len() as u64, Ordering::SeqCst);
     // A SendError may be returned when no receivers exist.
     // As they are only created when replication is running, this is no problem.
     _ = sender.send(message.clone());
@@ -1,68 +1,380 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    future::{pending, poll_fn, Future},
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Result};
-use tokio::sync::broadcast::Sender;
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver, Sender},
+    time::sleep,
+};
 
 use crate::{
-    command_parser::{parse_command, Command},
-    db::Db,
+    acl::{CATEGORIES, DEFAULT_USER},
+    command_parser::{
+        parse_command, Command, ConfigSubcommand, LatencySubcommand, SlowlogSubcommand,
+    },
+    command_table::{find_command, COMMANDS},
+    db::{
+        expire_date_from_now, restore_expire_date, Db, DbError, ListEnd, SetCondition, SetExpiry,
+        TtlResult,
+    },
+    dump,
+    latency::COMMAND_EVENT,
     message::Message,
+    pubsub::Publication,
     ServerConfig, ServerRole,
 };
 
 use super::distribute_message;
 
+// The version reported to clients, they use it to detect supported features.
+const REDIS_VERSION: &str = "7.2.0";
+
 // Use this struct for handling messages between a client and a server.
 pub struct MessageHandler {
     db: Arc<Db>,
     state: Arc<ServerConfig>,
     sender: Sender<Message>,
     replication_client_ack: bool,
+    username: String,
+    // unique per connection, reported by HELLO
+    id: u64,
+    // negotiated by HELLO, decides which null is replied
+    protocol: u8,
+    client_addr: String,
+    subscriptions: HashMap<String, Receiver<Publication>>,
+    pattern_subscriptions: HashMap<String, Receiver<Publication>>,
+    // the subscription next_push polls first, it rotates so a busy
+    // channel doesn't starve the others
+    next_push_start: usize,
+    // the commands queued since MULTI
+    transaction: Option<Vec<Command>>,
+    // a queued command was invalid, EXEC only discards the transaction
+    transaction_failed: bool,
+    // the keys of WATCH with their version and whether they existed at
+    // that time, an expiry changes the key without a write
+    watched_keys: Vec<(Message, u64, bool)>,
+    // set by a write command that changed nothing, it is not replicated
+    unchanged: bool,
+    // the writes of a running EXEC, sent to the replicas once it is done
+    exec_writes: Option<Vec<Message>>,
+}
+
+impl Drop for MessageHandler {
+    fn drop(&mut self) {
+        self.unwatch_all();
+        self.release_subscriptions();
+    }
 }
 
 impl MessageHandler {
     pub fn new(db: Arc<Db>, state: Arc<ServerConfig>, sender: Sender<Message>) -> Self {
         Self {
             db,
+            id: state.new_client_id(),
             state,
             sender,
             replication_client_ack: false,
+            username: DEFAULT_USER.to_string(),
+            protocol: 2,
+            client_addr: String::new(),
+            subscriptions: HashMap::new(),
+            pattern_subscriptions: HashMap::new(),
+            next_push_start: 0,
+            transaction: None,
+            transaction_failed: false,
+            watched_keys: vec![],
+            unchanged: false,
+            exec_writes: None,
         }
     }
 
+    pub fn set_client_addr(&mut self, client_addr: String) {
+        self.client_addr = client_addr;
+    }
+
+    pub fn client_addr(&self) -> &str {
+        &self.client_addr
+    }
+
     pub fn replication_client_acknowleged(&self) -> bool {
         self.replication_client_ack
     }
 
+    // Channels and patterns this connection is subscribed to.
+    fn subscription_count(&self) -> usize {
+        self.subscriptions.len() + self.pattern_subscriptions.len()
+    }
+
+    // Under RESP3 the confirmations of (un)subscribing are pushes.
+    fn subscription_reply(&self, kind: &str, channel: Message) -> Message {
+        Message::push_for(
+            self.protocol,
+            vec![
+                Message::BulkString(kind.to_string()),
+                channel,
+                Message::Integer(self.subscription_count() as i64),
+            ],
+        )
+    }
+
+    // Waits for the next message of a subscribed channel or pattern, never
+    // returns without subscriptions. The message is already encoded. None
+    // if messages were lost because the connection is too slow.
+    pub async fn next_push(&mut self) -> Option<Bytes> {
+        if self.subscription_count() == 0 {
+            return pending().await;
+        }
+
+        let mut receives = self
+            .subscriptions
+            .values_mut()
+            .chain(self.pattern_subscriptions.values_mut())
+            .map(|receiver| Box::pin(receiver.recv()))
+            .collect::<Vec<_>>();
+        let start = self.next_push_start % receives.len();
+        let (index, result) = poll_fn(|cx| {
+            for offset in 0..receives.len() {
+                let index = (start + offset) % receives.len();
+                if let Poll::Ready(result) = receives[index].as_mut().poll(cx) {
+                    return Poll::Ready((index, result));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        drop(receives);
+        self.next_push_start = index + 1;
+
+        match result {
+            Ok(push) => Some(push.encoded_for(self.protocol)),
+            Err(RecvError::Lagged(skipped)) => {
+                println!("subscriber lagged, {} messages skipped", skipped);
+                None
+            }
+            // the registry keeps the sender while receivers exist
+            Err(RecvError::Closed) => None,
+        }
+    }
+
     // Handle incoming message and return the answer(s) to it.
     pub async fn handle(&mut self, message: &Message) -> Result<Vec<Message>> {
-        let command = parse_command(&message)?;
+        let command = match parse_command(message) {
+            Ok(command) => command,
+            Err(err) => {
+                if self.transaction.is_some() {
+                    self.transaction_failed = true;
+                }
+                return Ok(vec![Message::Error(format!("ERR {}", err))]);
+            }
+        };
+
+        // Inside MULTI, commands are only run by EXEC.
+        if let Some(queued) = &mut self.transaction {
+            if !matches!(
+                command,
+                Command::Multi | Command::Exec | Command::Discard | Command::Watch { .. }
+            ) {
+                queued.push(command);
+                return Ok(vec![Message::SimpleString("QUEUED".to_string())]);
+            }
+        }
+
+        // A RESP2 connection in subscribe mode can only manage its
+        // subscriptions or switch to RESP3, other replies would mix with
+        // the pushed messages.
+        if self.subscription_count() > 0
+            && self.protocol == 2
+            && !matches!(
+                command,
+                Command::Subscribe { .. }
+                    | Command::Unsubscribe { .. }
+                    | Command::PSubscribe { .. }
+                    | Command::PUnsubscribe { .. }
+                    | Command::Ping
+                    | Command::Hello { .. }
+            )
+        {
+            let name = match message {
+                Message::Array(messages) => match messages.first() {
+                    Some(Message::BulkString(name)) => name.to_lowercase(),
+                    _ => String::new(),
+                },
+                _ => String::new(),
+            };
+            return Ok(vec![Message::Error(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                name
+            ))]);
+        }
+
+        let start = Instant::now();
+        let result = self.handle_command(command).await;
+        let elapsed = start.elapsed();
+        if let Message::Array(args) = message {
+            self.state
+                .record_slow_command(elapsed.as_micros() as u64, args, &self.client_addr)
+                .await;
+        }
+        self.state
+            .record_latency(COMMAND_EVENT, elapsed.as_millis() as u64)
+            .await;
+
+        // Errors of the stored data are replied, others end the connection.
+        let replies = match result {
+            Err(err) => match err.downcast_ref::<DbError>() {
+                Some(db_error) => vec![Message::Error(db_error.to_string())],
+                None => return Err(err),
+            },
+            Ok(replies) => replies,
+        };
+        Ok(replies
+            .into_iter()
+            .map(|reply| reply.with_nulls_for(self.protocol))
+            .collect())
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<Vec<Message>> {
+        if command == Command::Exec {
+            return self.exec().await;
+        }
+
+        // Every command runs under the read lock, a global DEBUG SLEEP takes
+        // the write lock to stall all other connections like real redis.
+        let state = self.state.clone();
+        let command_guard = state.command_lock.read().await;
+        self.run_and_propagate(command, command_guard).await
+    }
+
+    // Runs the command, a write that changed the keyspace is sent to the
+    // replicas afterwards.
+    async fn run_and_propagate<G: Send>(
+        &mut self,
+        command: Command,
+        command_guard: G,
+    ) -> Result<Vec<Message>> {
+        let write = command.is_write().then(|| command.clone());
+        self.unchanged = false;
+        let replies = self.run_command(command, command_guard).await?;
+
+        if let Some(write) = write {
+            if !self.unchanged && !matches!(replies.first(), Some(Message::Error(_))) {
+                let message = replication_message(write, &replies);
+                match &mut self.exec_writes {
+                    Some(writes) => writes.push(message),
+                    None => {
+                        distribute_message(&self.sender, &self.state.master_repl_offset, &message)
+                    }
+                }
+            }
+        }
+        Ok(replies)
+    }
+
+    // Runs the command, the guard is held until it is done unless the
+    // command does not touch the keyspace.
+    async fn run_command<G: Send>(
+        &mut self,
+        command: Command,
+        command_guard: G,
+    ) -> Result<Vec<Message>> {
         match command {
+            Command::Ping if self.subscription_count() > 0 => Ok(vec![Message::Array(vec![
+                Message::BulkString("pong".to_string()),
+                Message::BulkString(String::new()),
+            ])]),
             Command::Ping => Ok(vec![Message::BulkString("PONG".to_string())]),
+            Command::Subscribe { channels } => {
+                drop(command_guard);
+                Ok(self.subscribe(channels).await)
+            }
+            Command::Unsubscribe { channels } => {
+                drop(command_guard);
+                Ok(self.unsubscribe(channels).await)
+            }
+            Command::PSubscribe { patterns } => {
+                drop(command_guard);
+                Ok(self.psubscribe(patterns).await)
+            }
+            Command::PUnsubscribe { patterns } => {
+                drop(command_guard);
+                Ok(self.punsubscribe(patterns).await)
+            }
+            Command::Publish { channel, message } => {
+                let receivers = self.state.pubsub.publish(&channel, message).await;
+                Ok(vec![Message::Integer(receivers as i64)])
+            }
             Command::Echo(message) => Ok(vec![message]),
-            Command::Get { key } => match self.db.get(&key).await {
+            Command::Hello { protover, auth } => {
+                // only changes the connection, not the keyspace
+                drop(command_guard);
+                Ok(vec![self.hello(protover, auth)])
+            }
+            Command::Get { key } => match self.db.get(&key).await? {
                 Some(value) => Ok(vec![value.clone()]),
                 None => Ok(vec![Message::NullBulkString]),
             },
+            Command::GetRange { key, start, end } => {
+                Ok(vec![self.db.get_range(&key, start, end).await?])
+            }
             Command::Set {
                 ref key,
                 ref value,
-                expire_time,
+                expiry,
+                condition,
+                get,
             } => {
-                self.db.set(key.clone(), value.clone(), expire_time).await?;
-                let message = Message::SimpleString("OK".to_string());
-                distribute_message(&self.sender, &command.clone().to_message());
+                let (written, old_value) = self
+                    .db
+                    .set_with(key.clone(), value.clone(), expiry, condition, get)
+                    .await?;
+                self.unchanged = !written;
+
+                let message = if get {
+                    old_value.unwrap_or(Message::NullBulkString)
+                } else if written {
+                    Message::SimpleString("OK".to_string())
+                } else {
+                    Message::NullBulkString
+                };
                 Ok(vec![message])
             }
-            Command::Info { sections } => {
-                if sections.len() != 1
-                    || sections[0] != Message::BulkString("replication".to_string())
-                {
-                    bail!("unknown section type {:?}", sections);
-                }
-
-                self.build_replication_info()
+            Command::SetNx { ref key, ref value } => {
+                let (written, _) = self
+                    .db
+                    .set_with(
+                        key.clone(),
+                        value.clone(),
+                        None,
+                        SetCondition::IfNotExists,
+                        false,
+                    )
+                    .await?;
+                self.unchanged = !written;
+                Ok(vec![Message::Integer(written as i64)])
+            }
+            Command::SetEx {
+                ref key,
+                seconds,
+                ref value,
+            } => {
+                self.db
+                    .set_with(
+                        key.clone(),
+                        value.clone(),
+                        Some(SetExpiry::Milliseconds(seconds * 1000)),
+                        SetCondition::Always,
+                        false,
+                    )
+                    .await?;
+                Ok(vec![Message::SimpleString("OK".to_string())])
             }
+            Command::Info { sections } => self.build_info(&sections).await,
             Command::Replconf { .. } =>
             // for now just respond with okay
             {
@@ -71,26 +383,723 @@ impl MessageHandler {
             Command::Psync => {
                 self.replication_client_ack = true;
                 Ok(vec![
-                    Message::SimpleString(format!("FULLRESYNC {} 0", self.state.master_replid)),
+                    // the replica continues from the offset of the writes so far
+                    Message::SimpleString(format!(
+                        "FULLRESYNC {} {}",
+                        self.state.master_replid,
+                        self.state.master_repl_offset()
+                    )),
                     Self::get_rdb_file(),
                 ])
             }
-            Command::Wait => Ok(vec![Message::Integer(
-                self.state.active_replication_clients().await as i64,
+            Command::Wait {
+                numreplicas,
+                timeout,
+            } => {
+                // the writes of other clients go on while this one waits
+                drop(command_guard);
+                // pings sent while waiting do not move the target
+                let offset = self.state.master_repl_offset();
+                if offset > 0 && self.state.acknowledged_replicas(offset) < numreplicas {
+                    // the replicas reply once they processed everything before
+                    distribute_message(
+                        &self.sender,
+                        &self.state.master_repl_offset,
+                        &Command::get_replconf_command("GETACK", "*"),
+                    );
+                }
+                let acknowledged = self
+                    .state
+                    .wait_for_replicas(numreplicas, offset, timeout)
+                    .await;
+                Ok(vec![Message::Integer(acknowledged as i64)])
+            }
+            Command::Docs { names } => Ok(vec![Self::build_command_docs(&names)]),
+            Command::Cluster { subcommand } => Ok(vec![self.build_cluster_reply(&subcommand)]),
+            Command::Acl { subcommand } => Ok(vec![self.build_acl_reply(&subcommand)]),
+            Command::Latency { subcommand } => Ok(vec![match subcommand {
+                LatencySubcommand::History { event } => {
+                    self.state.latency.read().await.history(&event)
+                }
+                LatencySubcommand::Latest => self.state.latency.read().await.latest(),
+                LatencySubcommand::Reset { events } => {
+                    Message::Integer(self.state.latency.write().await.reset(&events) as i64)
+                }
+            }]),
+            Command::Slowlog { subcommand } => Ok(vec![match subcommand {
+                SlowlogSubcommand::Get { count } => self
+                    .state
+                    .slowlog
+                    .read()
+                    .await
+                    .get(count.unwrap_or(usize::MAX)),
+                SlowlogSubcommand::Len => {
+                    Message::Integer(self.state.slowlog.read().await.len() as i64)
+                }
+                SlowlogSubcommand::Reset => {
+                    self.state.slowlog.write().await.reset();
+                    Message::SimpleString("OK".to_string())
+                }
+            }]),
+            Command::Config { subcommand } => Ok(vec![self.build_config_reply(subcommand).await]),
+            Command::Function { subcommand } => Ok(vec![Self::build_function_reply(&subcommand)]),
+            Command::Keys { pattern } => Ok(vec![Message::Array(self.db.keys(&pattern).await)]),
+            Command::Scan {
+                cursor,
+                pattern,
+                count,
+            } => {
+                let (next_cursor, keys) = self.db.scan(cursor, count, pattern.as_deref()).await;
+                Ok(vec![Message::Array(vec![
+                    Message::BulkString(next_cursor.to_string()),
+                    Message::Array(keys),
+                ])])
+            }
+            Command::DbSize => Ok(vec![Message::Integer(self.db.len_live().await as i64)]),
+            Command::Multi => {
+                if self.transaction.is_some() {
+                    return Ok(vec![Message::Error(
+                        "ERR MULTI calls can not be nested".to_string(),
+                    )]);
+                }
+                self.transaction = Some(vec![]);
+                Ok(vec![Message::SimpleString("OK".to_string())])
+            }
+            Command::Exec => unreachable!("EXEC is run by handle_command"),
+            Command::Discard => {
+                if self.transaction.take().is_none() {
+                    return Ok(vec![Message::Error(
+                        "ERR DISCARD without MULTI".to_string(),
+                    )]);
+                }
+                self.transaction_failed = false;
+                self.unwatch_all();
+                Ok(vec![Message::SimpleString("OK".to_string())])
+            }
+            Command::Watch { keys } => {
+                if self.transaction.is_some() {
+                    return Ok(vec![Message::Error(
+                        "ERR WATCH inside MULTI is not allowed".to_string(),
+                    )]);
+                }
+                for key in keys {
+                    if !self
+                        .watched_keys
+                        .iter()
+                        .any(|(watched, ..)| *watched == key)
+                    {
+                        let version = self.db.watch(&key);
+                        let existed = self.db.exists(&key).await;
+                        self.watched_keys.push((key, version, existed));
+                    }
+                }
+                Ok(vec![Message::SimpleString("OK".to_string())])
+            }
+            Command::Unwatch => {
+                self.unwatch_all();
+                Ok(vec![Message::SimpleString("OK".to_string())])
+            }
+            Command::FlushAll => {
+                self.db.clear().await;
+                Ok(vec![Message::SimpleString("OK".to_string())])
+            }
+            Command::RandomKey => match self.db.random_key().await {
+                Some(key) => Ok(vec![key]),
+                None => Ok(vec![Message::NullBulkString]),
+            },
+            Command::DebugSleep { duration, global } => {
+                drop(command_guard);
+                if global {
+                    let _global_guard = self.state.command_lock.write().await;
+                    sleep(duration).await;
+                } else {
+                    sleep(duration).await;
+                }
+                Ok(vec![Message::SimpleString("OK".to_string())])
+            }
+            Command::Exists { keys } => {
+                let mut count = 0;
+                for key in &keys {
+                    if self.db.exists(key).await {
+                        count += 1;
+                    }
+                }
+                Ok(vec![Message::Integer(count)])
+            }
+            Command::Incr { key } => self.increment(&key, 1).await,
+            Command::Decr { key } => self.increment(&key, -1).await,
+            Command::IncrBy { key, increment } => self.increment(&key, increment).await,
+            Command::DecrBy { key, decrement } => self.increment(&key, -decrement).await,
+            Command::IncrByFloat { key, increment } => {
+                let value = self.db.increment_by_float(&key, increment).await?;
+                Ok(vec![Message::BulkString(value)])
+            }
+            Command::Ttl { key } => {
+                let ttl = match self.db.ttl_millis(&key).await {
+                    TtlResult::Missing => -2,
+                    TtlResult::NoExpiry => -1,
+                    TtlResult::Remaining(millis) => ttl_seconds(millis),
+                };
+                Ok(vec![Message::Integer(ttl)])
+            }
+            Command::Pttl { key } => {
+                let ttl = match self.db.ttl_millis(&key).await {
+                    TtlResult::Missing => -2,
+                    TtlResult::NoExpiry => -1,
+                    TtlResult::Remaining(millis) => millis,
+                };
+                Ok(vec![Message::Integer(ttl)])
+            }
+            Command::Dump { key } => match self.db.dump(&key).await {
+                Some(payload) => Ok(vec![Message::BulkBytes(payload)]),
+                None => Ok(vec![Message::NullBulkString]),
+            },
+            Command::Restore {
+                ref key,
+                ttl,
+                ref payload,
+                replace,
+                absttl,
+            } => {
+                self.restore(key, ttl, payload, replace, absttl).await?;
+                Ok(vec![Message::SimpleString("OK".to_string())])
+            }
+            Command::ObjectEncoding { key } => match self.db.encoding(&key).await {
+                Some(encoding) => Ok(vec![Message::BulkString(encoding.to_string())]),
+                None => Ok(vec![Message::NullBulkString]),
+            },
+            Command::Type { key } => {
+                let type_name = self.db.value_type(&key).await.unwrap_or("none");
+                Ok(vec![Message::SimpleString(type_name.to_string())])
+            }
+            Command::Expire {
+                ref key,
+                milliseconds,
+            } => {
+                let expire_date = match expire_date_from_now(milliseconds) {
+                    Ok(date) => date,
+                    Err(_) => {
+                        return Ok(vec![Message::Error(
+                            "ERR invalid expire time in 'expire' command".to_string(),
+                        )])
+                    }
+                };
+                let exists = self.db.set_expiry(key, Some(expire_date)).await;
+                self.unchanged = !exists;
+                Ok(vec![Message::Integer(exists as i64)])
+            }
+            Command::Del { ref keys } => {
+                let deleted = self.db.delete(keys).await;
+                self.unchanged = deleted == 0;
+                Ok(vec![Message::Integer(deleted as i64)])
+            }
+            Command::Persist { ref key } => {
+                let persisted = self.db.persist(key).await;
+                self.unchanged = !persisted;
+                Ok(vec![Message::Integer(persisted as i64)])
+            }
+            Command::Append { ref key, ref value } => {
+                let len = self
+                    .db
+                    .append(key, value.as_bytes().unwrap_or_default())
+                    .await?;
+                Ok(vec![Message::Integer(len as i64)])
+            }
+            Command::LPush {
+                ref key,
+                ref values,
+            } => {
+                let len = self.db.push(key, values, ListEnd::Left).await?;
+                Ok(vec![Message::Integer(len as i64)])
+            }
+            Command::RPush {
+                ref key,
+                ref values,
+            } => {
+                let len = self.db.push(key, values, ListEnd::Right).await?;
+                Ok(vec![Message::Integer(len as i64)])
+            }
+            Command::LRange { key, start, stop } => Ok(vec![Message::Array(
+                self.db.range(&key, start, stop).await?,
+            )]),
+            Command::LPop { key, count } => self.pop(&key, ListEnd::Left, count).await,
+            Command::RPop { key, count } => self.pop(&key, ListEnd::Right, count).await,
+            Command::HSet {
+                ref key,
+                ref fields,
+            } => {
+                let limits = self.state.listpack_limits().await;
+                let added = self.db.hset(key, fields, &limits).await?;
+                Ok(vec![Message::Integer(added as i64)])
+            }
+            Command::HGet { key, field } => match self.db.hget(&key, &field).await? {
+                Some(value) => Ok(vec![value]),
+                None => Ok(vec![Message::NullBulkString]),
+            },
+            Command::HGetAll { key } => Ok(vec![Message::map_for(
+                self.protocol,
+                self.db
+                    .hgetall(&key)
+                    .await?
+                    .into_iter()
+                    .map(|(field, value)| (Message::BulkString(field), value))
+                    .collect(),
             )]),
+            Command::HDel {
+                ref key,
+                ref fields,
+            } => {
+                let removed = self.db.hdel(key, fields).await?;
+                self.unchanged = removed == 0;
+                Ok(vec![Message::Integer(removed as i64)])
+            }
+            Command::LLen { key } => {
+                Ok(vec![Message::Integer(self.db.list_len(&key).await? as i64)])
+            }
+            Command::SAdd {
+                ref key,
+                ref members,
+            } => {
+                let added = self.db.sadd(key, members).await?;
+                self.unchanged = added == 0;
+                Ok(vec![Message::Integer(added as i64)])
+            }
+            Command::SMembers { key } => Ok(vec![Message::Array(self.db.smembers(&key).await?)]),
+            Command::SetStore {
+                operation,
+                ref destination,
+                ref keys,
+            } => {
+                let (len, changed) = self.db.set_store(destination, operation, keys).await?;
+                self.unchanged = !changed;
+                Ok(vec![Message::Integer(len as i64)])
+            }
+        }
+    }
+
+    async fn restore(
+        &self,
+        key: &Message,
+        ttl: i64,
+        payload: &Message,
+        replace: bool,
+        absttl: bool,
+    ) -> Result<()> {
+        let limits = self.state.listpack_limits().await;
+        let value = dump::restore(payload.as_bytes().unwrap_or_default(), &limits)?;
+        let expire_date = restore_expire_date(ttl, absttl)?;
+        self.db.restore(key, value, expire_date, replace).await
+    }
+
+    async fn increment(&self, key: &Message, delta: i64) -> Result<Vec<Message>> {
+        let value = self.db.increment_by(key, delta).await?;
+        Ok(vec![Message::Integer(value)])
+    }
+
+    // Without a count a single element is returned, with a count an array.
+    async fn pop(
+        &mut self,
+        key: &Message,
+        end: ListEnd,
+        count: Option<usize>,
+    ) -> Result<Vec<Message>> {
+        let mut popped = self.db.pop(key, end, count.unwrap_or(1)).await?;
+        self.unchanged = popped.is_empty();
+
+        let message = match count {
+            _ if popped.is_empty() => Message::NullBulkString,
+            Some(_) => Message::Array(popped),
+            None => popped.remove(0),
+        };
+        Ok(vec![message])
+    }
+
+    async fn build_config_reply(&self, subcommand: ConfigSubcommand) -> Message {
+        match subcommand {
+            ConfigSubcommand::Get { patterns } => {
+                let mut parameters = vec![];
+                for pattern in patterns {
+                    for parameter in self.state.get_parameters(&pattern).await {
+                        if !parameters.contains(&parameter) {
+                            parameters.push(parameter);
+                        }
+                    }
+                }
+                Message::Array(
+                    parameters
+                        .into_iter()
+                        .flat_map(|(name, value)| {
+                            [Message::BulkString(name), Message::BulkString(value)]
+                        })
+                        .collect(),
+                )
+            }
+            ConfigSubcommand::Set { name, value } => {
+                match self.state.set_parameter(&name, &value).await {
+                    Ok(()) => Message::SimpleString("OK".to_string()),
+                    Err(err) => Message::Error(format!("ERR {}", err)),
+                }
+            }
+        }
+    }
+
+    fn build_acl_reply(&self, subcommand: &str) -> Message {
+        match subcommand {
+            "WHOAMI" => Message::BulkString(self.username.clone()),
+            "LIST" => Message::Array(
+                self.state
+                    .users
+                    .iter()
+                    .map(|user| Message::BulkString(user.describe()))
+                    .collect(),
+            ),
+            "CAT" => Message::Array(
+                CATEGORIES
+                    .iter()
+                    .map(|category| Message::BulkString(category.to_string()))
+                    .collect(),
+            ),
+            _ => Message::Error(format!(
+                "ERR unknown subcommand '{}'. Try ACL HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    // There is no scripting engine, these replies only answer the probes of clients.
+    fn build_function_reply(subcommand: &str) -> Message {
+        match subcommand {
+            "LIST" => Message::Array(vec![]),
+            "DUMP" => Message::NullBulkString,
+            "STATS" => Message::Array(vec![
+                Message::BulkString("running_script".to_string()),
+                Message::NullBulkString,
+                Message::BulkString("engines".to_string()),
+                Message::Array(vec![]),
+            ]),
+            "FLUSH" => Message::SimpleString("OK".to_string()),
+            "LOAD" => {
+                Message::Error("ERR Engine not found, scripting is not supported".to_string())
+            }
+            _ => Message::Error(format!(
+                "ERR unknown subcommand '{}'. Try FUNCTION HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    // Cluster mode is not supported, these replies only keep cluster aware clients happy.
+    fn build_cluster_reply(&self, subcommand: &str) -> Message {
+        match subcommand {
+            "INFO" => Message::BulkString(
+                "cluster_enabled:0\r\ncluster_state:ok\r\ncluster_slots_assigned:0\r\ncluster_known_nodes:1\r\ncluster_size:0\r\n"
+                    .to_string(),
+            ),
+            "MYID" => Message::BulkString(self.state.node_id.clone()),
+            "SLOTS" | "SHARDS" => Message::Array(vec![]),
+            _ => Message::Error("ERR This instance has cluster support disabled".to_string()),
+        }
+    }
+
+    fn build_command_docs(names: &[String]) -> Message {
+        let docs = if names.is_empty() {
+            COMMANDS.iter().collect::<Vec<_>>()
+        } else {
+            names.iter().filter_map(|name| find_command(name)).collect()
+        };
+
+        Message::Array(
+            docs.into_iter()
+                .flat_map(|doc| {
+                    [
+                        Message::BulkString(doc.name.to_string()),
+                        doc.docs_message(),
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    // Runs the queued commands while no other connection runs any, so the
+    // transaction is atomic. Nothing is run if a watched key was written.
+    async fn exec(&mut self) -> Result<Vec<Message>> {
+        let Some(queued) = self.transaction.take() else {
+            return Ok(vec![Message::Error("ERR EXEC without MULTI".to_string())]);
+        };
+        let failed = std::mem::take(&mut self.transaction_failed);
+
+        let state = self.state.clone();
+        let _exec_guard = state.command_lock.write().await;
+        let mut watched_changed = false;
+        for (key, version, existed) in &self.watched_keys {
+            if self.db.version_of(key) != *version || *existed && !self.db.exists(key).await {
+                watched_changed = true;
+                break;
+            }
+        }
+        self.unwatch_all();
+        if failed {
+            return Ok(vec![Message::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            )]);
+        }
+        if watched_changed {
+            return Ok(vec![Message::Null]);
+        }
+
+        let mut replies = vec![];
+        self.exec_writes = Some(vec![]);
+        for command in queued {
+            // the write lock is already held, a global sleep would wait for itself
+            let command = match command {
+                Command::DebugSleep { duration, .. } => Command::DebugSleep {
+                    duration,
+                    global: false,
+                },
+                // like in redis, a WAIT in a transaction does not block
+                Command::Wait { numreplicas, .. } => Command::Wait {
+                    numreplicas,
+                    timeout: Some(Duration::ZERO),
+                },
+                command => command,
+            };
+            // a failed command is its error in the replies, the others still run
+            match self.run_and_propagate(command, ()).await {
+                Ok(command_replies) => replies.extend(command_replies),
+                Err(err) => match err.downcast_ref::<DbError>() {
+                    Some(db_error) => replies.push(Message::Error(db_error.to_string())),
+                    None => replies.push(Message::Error(format!("ERR {}", err))),
+                },
+            }
+        }
+        self.propagate_exec_writes();
+        Ok(vec![Message::Array(replies)])
+    }
+
+    // Like redis, several writes reach the replicas as a transaction too.
+    fn propagate_exec_writes(&mut self) {
+        let mut writes = self.exec_writes.take().unwrap_or_default();
+        if writes.len() > 1 {
+            writes.insert(0, Command::Multi.to_message());
+            writes.push(Command::Exec.to_message());
+        }
+        for write in writes {
+            distribute_message(&self.sender, &self.state.master_repl_offset, &write);
+        }
+    }
+
+    fn unwatch_all(&mut self) {
+        for (key, ..) in self.watched_keys.drain(..) {
+            self.db.unwatch(&key);
+        }
+    }
+
+    // Drops the receivers of a closed connection. The registry is locked
+    // asynchronously, so its channels and patterns nobody listens to
+    // anymore are removed by a spawned task.
+    fn release_subscriptions(&mut self) {
+        let channels = self
+            .subscriptions
+            .drain()
+            .map(|(channel, _)| channel)
+            .collect::<Vec<_>>();
+        let patterns = self
+            .pattern_subscriptions
+            .drain()
+            .map(|(pattern, _)| pattern)
+            .collect::<Vec<_>>();
+        if channels.is_empty() && patterns.is_empty() {
+            return;
+        }
+
+        let state = self.state.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                for channel in channels {
+                    state.pubsub.unsubscribed(&channel).await;
+                }
+                for pattern in patterns {
+                    state.pubsub.punsubscribed(&pattern).await;
+                }
+            });
+        }
+    }
+
+    async fn subscribe(&mut self, channels: Vec<String>) -> Vec<Message> {
+        let mut replies = vec![];
+        for channel in channels {
+            if !self.subscriptions.contains_key(&channel) {
+                let receiver = self.state.pubsub.subscribe(&channel).await;
+                self.subscriptions.insert(channel.clone(), receiver);
+            }
+            replies.push(self.subscription_reply("subscribe", Message::BulkString(channel)));
         }
+        replies
     }
 
-    fn build_replication_info(&self) -> Result<Vec<Message>> {
+    async fn unsubscribe(&mut self, channels: Vec<String>) -> Vec<Message> {
+        let channels = if channels.is_empty() {
+            let mut channels = self.subscriptions.keys().cloned().collect::<Vec<_>>();
+            channels.sort();
+            channels
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            return vec![self.subscription_reply("unsubscribe", Message::NullBulkString)];
+        }
+
+        let mut replies = vec![];
+        for channel in channels {
+            if self.subscriptions.remove(&channel).is_some() {
+                self.state.pubsub.unsubscribed(&channel).await;
+            }
+            replies.push(self.subscription_reply("unsubscribe", Message::BulkString(channel)));
+        }
+        replies
+    }
+
+    async fn psubscribe(&mut self, patterns: Vec<String>) -> Vec<Message> {
+        let mut replies = vec![];
+        for pattern in patterns {
+            if !self.pattern_subscriptions.contains_key(&pattern) {
+                let receiver = self.state.pubsub.psubscribe(&pattern).await;
+                self.pattern_subscriptions.insert(pattern.clone(), receiver);
+            }
+            replies.push(self.subscription_reply("psubscribe", Message::BulkString(pattern)));
+        }
+        replies
+    }
+
+    async fn punsubscribe(&mut self, patterns: Vec<String>) -> Vec<Message> {
+        let patterns = if patterns.is_empty() {
+            let mut patterns = self
+                .pattern_subscriptions
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            patterns.sort();
+            patterns
+        } else {
+            patterns
+        };
+        if patterns.is_empty() {
+            return vec![self.subscription_reply("punsubscribe", Message::NullBulkString)];
+        }
+
+        let mut replies = vec![];
+        for pattern in patterns {
+            if self.pattern_subscriptions.remove(&pattern).is_some() {
+                self.state.pubsub.punsubscribed(&pattern).await;
+            }
+            replies.push(self.subscription_reply("punsubscribe", Message::BulkString(pattern)));
+        }
+        replies
+    }
+
+    fn hello(&mut self, protover: Option<u8>, auth: Option<(String, String)>) -> Message {
+        let protocol = protover.unwrap_or(self.protocol);
+        if !(2..=3).contains(&protocol) {
+            return Message::Error("NOPROTO unsupported protocol version".to_string());
+        }
+        if let Some((username, _)) = auth {
+            // every existing user is nopass, so only the name is checked
+            match self.state.users.iter().find(|user| user.name == username) {
+                Some(user) if user.enabled => self.username = username,
+                _ => {
+                    return Message::Error(
+                        "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+                    )
+                }
+            }
+        }
+        self.protocol = protocol;
+
+        let role = match self.state.role {
+            ServerRole::Leader => "master",
+            ServerRole::Follower => "replica",
+        };
+        let field = |name: &str| Message::BulkString(name.to_string());
+        Message::map_for(
+            self.protocol,
+            vec![
+                (field("server"), field("redis")),
+                (field("version"), field(REDIS_VERSION)),
+                (field("proto"), Message::Integer(self.protocol as i64)),
+                (field("id"), Message::Integer(self.id as i64)),
+                (field("mode"), field("standalone")),
+                (field("role"), field(role)),
+                (field("modules"), Message::Array(vec![])),
+            ],
+        )
+    }
+
+    // The requested sections, every supported one without a section or
+    // with default or all. Unknown sections are left out like in redis.
+    async fn build_info(&self, sections: &[Message]) -> Result<Vec<Message>> {
+        let mut replication = sections.is_empty();
+        let mut keyspace = sections.is_empty();
+        for section in sections {
+            let Message::BulkString(section) = section else {
+                continue;
+            };
+            match section.to_lowercase().as_str() {
+                "default" | "all" | "everything" => {
+                    replication = true;
+                    keyspace = true;
+                }
+                "replication" => replication = true,
+                "keyspace" => keyspace = true,
+                _ => {}
+            }
+        }
+
+        let mut info = vec![];
+        if replication {
+            info.push(self.build_replication_info());
+        }
+        if keyspace {
+            info.push(self.build_keyspace_info().await);
+        }
+        Ok(vec![Message::BulkString(info.join("\n\n"))])
+    }
+
+    fn build_replication_info(&self) -> String {
         let role = match self.state.role {
             ServerRole::Leader => "master",
             ServerRole::Follower => "slave",
         };
 
-        Ok(vec![Message::BulkString(format!(
-            "role:{}\nmaster_replid:{}\nmaster_repl_offset:{}",
-            role, self.state.master_replid, self.state.master_repl_offset
-        ))])
+        let mut info = format!(
+            "# Replication\nrole:{}\nmaster_replid:{}\nmaster_repl_offset:{}",
+            role,
+            self.state.master_replid,
+            self.state.master_repl_offset()
+        );
+        if self.state.role == ServerRole::Follower {
+            let status = if self.state.master_link_up() {
+                "up"
+            } else {
+                "down"
+            };
+            info.push_str(&format!("\nmaster_link_status:{}", status));
+        }
+
+        info
+    }
+
+    async fn build_keyspace_info(&self) -> String {
+        let mut info = "# Keyspace".to_string();
+
+        let stats = self.db.keyspace_stats().await;
+        if stats.keys > 0 {
+            info.push_str(&format!(
+                "\ndb0:keys={},expires={},avg_ttl={}",
+                stats.keys, stats.expires, stats.avg_ttl
+            ));
+        }
+
+        info
     }
 
     fn get_rdb_file() -> Message {
@@ -100,8 +1109,35 @@ impl MessageHandler {
     }
 }
 
+// The remaining whole seconds, rounded up so a key about to expire
+// still reports 1 and not 0.
+fn ttl_seconds(millis: i64) -> i64 {
+    (millis + 999) / 1000
+}
+
+// The message replicas get for a write. INCRBYFLOAT is sent as a SET of
+// the result, float arithmetic could differ there.
+fn replication_message(command: Command, replies: &[Message]) -> Message {
+    match (command, replies.first()) {
+        (Command::IncrByFloat { key, .. }, Some(value)) => Command::Set {
+            key,
+            value: value.clone(),
+            expiry: Some(SetExpiry::KeepTtl),
+            condition: SetCondition::Always,
+            get: false,
+        }
+        .to_message(),
+        (command, _) => command.to_message(),
+    }
+}
+
+// The confirmation of (P)SUBSCRIBE and (P)UNSUBSCRIBE with the number of
+// remaining subscriptions.
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use tokio::sync::broadcast::{self, Receiver};
 
     use crate::handler::test_functions::get_set_command;
@@ -122,7 +1158,7 @@ mod tests {
         let db = Arc::new(Db::new());
         let state = Arc::new(ServerConfig::new(ServerRole::Leader, 1234));
 
-        let (tx, rx) = broadcast::channel(1);
+        let (tx, rx) = broadcast::channel(16);
 
         let handler = MessageHandler::new(db, state.clone(), tx);
         (handler, rx, state)
@@ -184,16 +1220,275 @@ mod tests {
         assert_eq!(value, result_get[0]);
     }
 
-    #[tokio::test]
-    async fn test_info_replication() {
-        let mut handler = create_handler();
-        let messages = vec![
-            Message::BulkString("INFO".to_string()),
-            Message::BulkString("replication".to_string()),
+    fn set_with_options(key: &str, value: &str, options: &[&str]) -> Message {
+        let mut messages = vec![
+            Message::BulkString("SET".to_string()),
+            Message::BulkString(key.to_string()),
+            Message::BulkString(value.to_string()),
         ];
+        messages.extend(
+            options
+                .iter()
+                .map(|option| Message::BulkString(option.to_string())),
+        );
+        Message::Array(messages)
+    }
 
-        if let Message::BulkString(result) =
-            handler.handle(&Message::Array(messages)).await.unwrap()[0].clone()
+    async fn get_value(handler: &mut MessageHandler, key: &str) -> Message {
+        let message_get = Message::Array(vec![
+            Message::BulkString("GET".to_string()),
+            Message::BulkString(key.to_string()),
+        ]);
+        handler.handle(&message_get).await.unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn test_only_writes_are_propagated() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let (key, _, message_set) = get_set_command("key", "value");
+
+        handler.handle(&message_set).await.unwrap();
+        assert_eq!(message_set, rx.recv().await.unwrap());
+
+        let get = Command::Get { key }.to_message();
+        handler.handle(&get).await.unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_nx() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let ok = Message::SimpleString("OK".to_string());
+
+        let set_nx = set_with_options("key", "first", &["NX"]);
+        assert_eq!(ok, handler.handle(&set_nx).await.unwrap()[0]);
+        assert_eq!(set_nx, rx.recv().await.unwrap());
+
+        let set_nx = set_with_options("key", "second", &["NX"]);
+        assert_eq!(
+            Message::NullBulkString,
+            handler.handle(&set_nx).await.unwrap()[0]
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            Message::BulkString("first".to_string()),
+            get_value(&mut handler, "key").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setnx() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+
+        let setnx = list_command(&["SETNX", "key", "first"]);
+        assert_eq!(
+            Message::Integer(1),
+            handler.handle(&setnx).await.unwrap()[0]
+        );
+        assert_eq!(setnx, rx.recv().await.unwrap());
+
+        let setnx = list_command(&["SETNX", "key", "second"]);
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&setnx).await.unwrap()[0]
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            Message::BulkString("first".to_string()),
+            get_value(&mut handler, "key").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setex() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+
+        let setex = list_command(&["SETEX", "key", "100", "value"]);
+        assert_eq!(
+            Message::SimpleString("OK".to_string()),
+            handler.handle(&setex).await.unwrap()[0]
+        );
+        assert_eq!(setex, rx.recv().await.unwrap());
+        assert_eq!(
+            Message::Integer(100),
+            handler
+                .handle(&list_command(&["TTL", "key"]))
+                .await
+                .unwrap()[0]
+        );
+
+        for seconds in ["0", "9223372036854775"] {
+            let result = handler
+                .handle(&list_command(&["SETEX", "key", seconds, "value"]))
+                .await
+                .unwrap();
+            assert_eq!(
+                Message::Error("ERR invalid expire time in 'setex' command".to_string()),
+                result[0]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_expire_time_out_of_range() {
+        let mut handler = create_handler();
+
+        for unit in ["PX", "PXAT"] {
+            let set = list_command(&["SET", "key", "value", unit, &i64::MAX.to_string()]);
+            assert_eq!(
+                Message::Error("ERR invalid expire time in 'set' command".to_string()),
+                handler.handle(&set).await.unwrap()[0]
+            );
+        }
+        assert_eq!(
+            Message::NullBulkString,
+            get_value(&mut handler, "key").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_xx() {
+        let mut handler = create_handler();
+
+        let set_xx = set_with_options("key", "value", &["XX"]);
+        assert_eq!(
+            Message::NullBulkString,
+            handler.handle(&set_xx).await.unwrap()[0]
+        );
+        assert_eq!(
+            Message::NullBulkString,
+            get_value(&mut handler, "key").await
+        );
+
+        let (_, _, message_set) = get_set_command("key", "first");
+        handler.handle(&message_set).await.unwrap();
+        assert_eq!(
+            Message::SimpleString("OK".to_string()),
+            handler.handle(&set_xx).await.unwrap()[0]
+        );
+        assert_eq!(
+            Message::BulkString("value".to_string()),
+            get_value(&mut handler, "key").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_get() {
+        let mut handler = create_handler();
+
+        let set_get = set_with_options("key", "first", &["GET"]);
+        assert_eq!(
+            Message::NullBulkString,
+            handler.handle(&set_get).await.unwrap()[0]
+        );
+
+        let set_get = set_with_options("key", "second", &["GET"]);
+        assert_eq!(
+            Message::BulkString("first".to_string()),
+            handler.handle(&set_get).await.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_exat_in_the_past_expires() {
+        let mut handler = create_handler();
+
+        let set_exat = set_with_options("key", "value", &["EXAT", "1"]);
+        handler.handle(&set_exat).await.unwrap();
+
+        assert_eq!(
+            Message::NullBulkString,
+            get_value(&mut handler, "key").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_keepttl() {
+        let mut handler = create_handler();
+        handler
+            .handle(&set_with_options("key", "first", &["PX", "100000"]))
+            .await
+            .unwrap();
+        handler
+            .handle(&set_with_options("key", "second", &["KEEPTTL"]))
+            .await
+            .unwrap();
+
+        assert_eq!(1, handler.db.keyspace_stats().await.expires);
+    }
+
+    #[tokio::test]
+    async fn test_set_invalid_options_reply_error() {
+        let mut handler = create_handler();
+
+        let result = handler
+            .handle(&set_with_options("key", "value", &["NX", "XX"]))
+            .await
+            .unwrap();
+        assert_eq!(Message::Error("ERR syntax error".to_string()), result[0]);
+
+        let result = handler
+            .handle(&set_with_options("key", "value", &["PX"]))
+            .await
+            .unwrap();
+        assert_eq!(Message::Error("ERR syntax error".to_string()), result[0]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_error() {
+        let mut handler = create_handler();
+
+        let result = handler
+            .handle(&list_command(&["FooBar", "a", "b c"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::Error(
+                "ERR unknown command 'FooBar', with args beginning with: 'a' 'b c' ".to_string()
+            ),
+            result[0]
+        );
+
+        let result = handler.handle(&list_command(&["nope"])).await.unwrap();
+        assert_eq!(
+            Message::Error("ERR unknown command 'nope', with args beginning with: ".to_string()),
+            result[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_number_of_arguments_error() {
+        let mut handler = create_handler();
+
+        for (command, name) in [
+            (list_command(&["GET"]), "get"),
+            (list_command(&["get", "a", "b"]), "get"),
+            (list_command(&["ECHO"]), "echo"),
+            (list_command(&["SET", "foo"]), "set"),
+            (list_command(&["LLEN"]), "llen"),
+            (list_command(&["REPLCONF", "capa"]), "replconf"),
+        ] {
+            let result = handler.handle(&command).await.unwrap();
+            assert_eq!(
+                Message::Error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    name
+                )),
+                result[0]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_info_replication() {
+        let mut handler = create_handler();
+        let messages = vec![
+            Message::BulkString("INFO".to_string()),
+            Message::BulkString("replication".to_string()),
+        ];
+
+        if let Message::BulkString(result) =
+            handler.handle(&Message::Array(messages)).await.unwrap()[0].clone()
         {
             assert!(result.contains("master_replid"));
         } else {
@@ -201,6 +1496,125 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_propagated_writes_advance_the_offset() {
+        let (mut handler, _rx, state) = create_handler_recx_and_state();
+        let (_, _, first) = get_set_command("key", "value");
+        let (_, _, second) = get_set_command("other", "value");
+
+        handler.handle(&first).await.unwrap();
+        handler.handle(&second).await.unwrap();
+        // reads are not propagated
+        handler
+            .handle(&list_command(&["GET", "key"]))
+            .await
+            .unwrap();
+
+        let expected = first.to_data().len() + second.to_data().len();
+        assert_eq!(expected as u64, state.master_repl_offset());
+
+        let info = list_command(&["INFO", "replication"]);
+        if let Message::BulkString(result) = handler.handle(&info).await.unwrap()[0].clone() {
+            assert!(result.contains(&format!("master_repl_offset:{}", expected)));
+        } else {
+            panic!("Info command should return a bulk string");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_psync_replies_current_offset() {
+        let mut handler = create_handler();
+        let (_, _, set) = get_set_command("key", "value");
+        handler.handle(&set).await.unwrap();
+
+        let psync = Command::get_psync_command("?", -1);
+        let replies = handler.handle(&psync).await.unwrap();
+        assert_eq!(
+            Message::SimpleString(format!(
+                "FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb {}",
+                set.to_data().len()
+            )),
+            replies[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_info_replication_link_status() {
+        let state = Arc::new(ServerConfig::new(ServerRole::Follower, 1234));
+        let (tx, _) = broadcast::channel(1);
+        let mut handler = MessageHandler::new(Arc::new(Db::new()), state.clone(), tx);
+        let info = Message::Array(vec![
+            Message::BulkString("INFO".to_string()),
+            Message::BulkString("replication".to_string()),
+        ]);
+
+        for (up, status) in [(false, "down"), (true, "up")] {
+            state.set_master_link_up(up);
+            if let Message::BulkString(result) = handler.handle(&info).await.unwrap()[0].clone() {
+                assert!(result.contains(&format!("master_link_status:{}", status)));
+            } else {
+                panic!("Info command should return a bulk string");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_info_sections() {
+        let mut handler = create_handler();
+        for sections in [&["INFO"][..], &["INFO", "default"], &["INFO", "ALL"]] {
+            let reply = handler.handle(&list_command(sections)).await.unwrap();
+            if let Message::BulkString(result) = &reply[0] {
+                assert!(result.contains("# Replication\nrole:master"));
+                assert!(result.contains("# Keyspace"));
+            } else {
+                panic!("Info command should return a bulk string");
+            }
+        }
+
+        let reply = handler
+            .handle(&list_command(&["INFO", "keyspace", "unknown"]))
+            .await
+            .unwrap();
+        assert_eq!(Message::BulkString("# Keyspace".to_string()), reply[0]);
+        let reply = handler
+            .handle(&list_command(&["INFO", "unknown"]))
+            .await
+            .unwrap();
+        assert_eq!(Message::BulkString(String::new()), reply[0]);
+    }
+
+    #[tokio::test]
+    async fn test_info_keyspace() {
+        let mut handler = create_handler();
+        let info_keyspace = Message::Array(vec![
+            Message::BulkString("INFO".to_string()),
+            Message::BulkString("keyspace".to_string()),
+        ]);
+
+        let result = handler.handle(&info_keyspace).await.unwrap();
+        assert_eq!(Message::BulkString("# Keyspace".to_string()), result[0]);
+
+        let (_, _, message_set) = get_set_command("key1", "value1");
+        handler.handle(&message_set).await.unwrap();
+        let message_set_px = Command::Set {
+            key: Message::BulkString("key2".to_string()),
+            value: Message::BulkString("value2".to_string()),
+            expiry: Some(SetExpiry::Milliseconds(100000)),
+            condition: SetCondition::Always,
+            get: false,
+        }
+        .to_message();
+        handler.handle(&message_set_px).await.unwrap();
+
+        if let Message::BulkString(result) =
+            handler.handle(&info_keyspace).await.unwrap()[0].clone()
+        {
+            assert!(result.contains("db0:keys=2,expires=1,avg_ttl="));
+        } else {
+            panic!("Info command should return a bulk string");
+        }
+    }
+
     #[tokio::test]
     async fn test_handle_psync() {
         let mut handler = create_handler();
@@ -233,19 +1647,1679 @@ mod tests {
         assert_eq!(set_command, message_recv);
     }
 
+    async fn handle_cluster(subcommand: &str) -> Message {
+        let message = Message::Array(vec![
+            Message::BulkString("CLUSTER".to_string()),
+            Message::BulkString(subcommand.to_string()),
+        ]);
+        handle_test(message).await
+    }
+
     #[tokio::test]
-    async fn test_wait_reply() -> Result<()> {
-        let (mut handler, _, state) = create_handler_recx_and_state();
-        let wait_command = Command::Wait.to_message();
+    async fn test_cluster_info() {
+        if let Message::BulkString(info) = handle_cluster("info").await {
+            assert!(info.contains("cluster_enabled:0"));
+        } else {
+            panic!("CLUSTER INFO should return a bulk string");
+        }
+    }
 
-        let result = handler.handle(&wait_command).await?;
-        assert_eq!(Message::Integer(0), result[0]);
+    #[tokio::test]
+    async fn test_cluster_myid() {
+        if let Message::BulkString(id) = handle_cluster("MYID").await {
+            assert_eq!(40, id.len());
+            assert_eq!(Message::BulkString(id), handle_cluster("MYID").await);
+        } else {
+            panic!("CLUSTER MYID should return a bulk string");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cluster_mutation_is_rejected() {
+        assert_eq!(
+            Message::Error("ERR This instance has cluster support disabled".to_string()),
+            handle_cluster("ADDSLOTS").await
+        );
+        assert_eq!(Message::Array(vec![]), handle_cluster("SLOTS").await);
+    }
 
-        state.add_replication_client().await;
+    #[tokio::test]
+    async fn test_exists_counts_duplicates_and_skips_expired() {
+        let mut handler = create_handler();
+        let (key, _, message_set) = get_set_command("key1", "value1");
+        handler.handle(&message_set).await.unwrap();
+        let expired = Message::BulkString("expired".to_string());
+        handler
+            .db
+            .set(
+                expired.clone(),
+                Message::BulkString("value".to_string()),
+                Some(-100),
+            )
+            .await
+            .unwrap();
 
-        let result = handler.handle(&wait_command).await?;
-        assert_eq!(Message::Integer(1), result[0]);
+        let exists = Command::Exists {
+            keys: vec![
+                key.clone(),
+                key,
+                expired,
+                Message::BulkString("missing".to_string()),
+            ],
+        }
+        .to_message();
 
-        Ok(())
+        let result = handler.handle(&exists).await.unwrap();
+        assert_eq!(Message::Integer(2), result[0]);
+    }
+
+    fn debug_sleep(millis: u64, global: bool) -> Message {
+        Command::DebugSleep {
+            duration: Duration::from_millis(millis),
+            global,
+        }
+        .to_message()
+    }
+
+    async fn time_ping_during_sleep(global: bool) -> Duration {
+        let (mut sleeping, _, state) = create_handler_recx_and_state();
+        let (tx, _) = broadcast::channel(1);
+        let mut other = MessageHandler::new(Arc::new(Db::new()), state, tx);
+
+        let sleep_task = tokio::spawn(async move {
+            sleeping.handle(&debug_sleep(200, global)).await.unwrap();
+        });
+        sleep(Duration::from_millis(20)).await;
+
+        let start = Instant::now();
+        other.handle(&Command::get_ping_command()).await.unwrap();
+        let elapsed = start.elapsed();
+
+        sleep_task.await.unwrap();
+        elapsed
+    }
+
+    #[tokio::test]
+    async fn test_debug_sleep_global_blocks_other_connections() {
+        assert!(time_ping_during_sleep(true).await >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_debug_sleep_only_blocks_own_connection() {
+        assert!(time_ping_during_sleep(false).await < Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_keys() {
+        let mut handler = create_handler();
+        for key in ["user:1", "user:2", "other"] {
+            let (_, _, message_set) = get_set_command(key, "value");
+            handler.handle(&message_set).await.unwrap();
+        }
+
+        let result = handler
+            .handle(&list_command(&["KEYS", "user:?"]))
+            .await
+            .unwrap();
+        if let Message::Array(mut keys) = result[0].clone() {
+            keys.sort_by_key(|key| key.to_string());
+            assert_eq!(bulk_strings(&["user:1", "user:2"]), Message::Array(keys));
+        } else {
+            panic!("expected an array, got {}", result[0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_until_cursor_zero() {
+        let mut handler = create_handler();
+        let mut expected = vec![];
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            let (_, _, message_set) = get_set_command(&key, "value");
+            handler.handle(&message_set).await.unwrap();
+            expected.push(Message::BulkString(key));
+        }
+
+        let mut seen = vec![];
+        let mut cursor = "0".to_string();
+        loop {
+            let result = handler
+                .handle(&list_command(&["SCAN", &cursor, "COUNT", "3"]))
+                .await
+                .unwrap();
+            if let Message::Array(reply) = &result[0] {
+                if let [Message::BulkString(next_cursor), Message::Array(keys)] = reply.as_slice() {
+                    seen.extend(keys.iter().cloned());
+                    cursor = next_cursor.clone();
+                } else {
+                    panic!("unexpected scan reply {}", result[0]);
+                }
+            } else {
+                panic!("expected an array, got {}", result[0]);
+            }
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        seen.sort_by_key(|key| key.to_string());
+        assert_eq!(expected, seen);
+    }
+
+    #[tokio::test]
+    async fn test_flushall_clears_keys() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        for key in ["key1", "key2", "key3"] {
+            let (_, _, message_set) = get_set_command(key, "value");
+            handler.handle(&message_set).await.unwrap();
+            rx.recv().await.unwrap();
+        }
+
+        let dbsize = Command::DbSize.to_message();
+        assert_eq!(
+            Message::Integer(3),
+            handler.handle(&dbsize).await.unwrap()[0]
+        );
+
+        let flushall = Command::FlushAll.to_message();
+        assert_eq!(
+            Message::SimpleString("OK".to_string()),
+            handler.handle(&flushall).await.unwrap()[0]
+        );
+        assert_eq!(flushall, rx.recv().await.unwrap());
+
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&dbsize).await.unwrap()[0]
+        );
+        assert_eq!(
+            Message::NullBulkString,
+            handler
+                .handle(&list_command(&["GET", "key1"]))
+                .await
+                .unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_random_key() {
+        let mut handler = create_handler();
+        let random_key = Command::RandomKey.to_message();
+
+        let result = handler.handle(&random_key).await.unwrap();
+        assert_eq!(Message::NullBulkString, result[0]);
+
+        let (key, _, message_set) = get_set_command("key1", "value1");
+        handler.handle(&message_set).await.unwrap();
+
+        let result = handler.handle(&random_key).await.unwrap();
+        assert_eq!(key, result[0]);
+    }
+
+    async fn handle_acl(subcommand: &str) -> Message {
+        let message = Message::Array(vec![
+            Message::BulkString("ACL".to_string()),
+            Message::BulkString(subcommand.to_string()),
+        ]);
+        handle_test(message).await
+    }
+
+    fn hello_field(reply: &Message, name: &str) -> Message {
+        let name = Message::BulkString(name.to_string());
+        match reply {
+            Message::Array(fields) => {
+                let index = fields
+                    .iter()
+                    .position(|field| *field == name)
+                    .unwrap_or_else(|| panic!("field {} missing in {}", name, reply));
+                fields[index + 1].clone()
+            }
+            Message::Map(pairs) => pairs
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_else(|| panic!("field {} missing in {}", name, reply)),
+            _ => panic!("expected an array or a map, got {}", reply),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hello_reports_role_and_protocol() {
+        let (mut handler, _, state) = create_handler_recx_and_state();
+        assert!(state.role == ServerRole::Leader);
+
+        let reply = handler.handle(&list_command(&["HELLO"])).await.unwrap()[0].clone();
+        assert_eq!(
+            Message::BulkString("master".to_string()),
+            hello_field(&reply, "role")
+        );
+        assert_eq!(Message::Integer(2), hello_field(&reply, "proto"));
+        assert!(reply.to_data().starts_with(b"*14\r\n"));
+
+        let reply = handler
+            .handle(&list_command(&["HELLO", "3", "AUTH", "default", "any"]))
+            .await
+            .unwrap()[0]
+            .clone();
+        assert_eq!(Message::Integer(3), hello_field(&reply, "proto"));
+        assert!(reply.to_data().starts_with(b"%7\r\n"));
+        let reply = handler.handle(&list_command(&["HELLO"])).await.unwrap()[0].clone();
+        assert_eq!(Message::Integer(3), hello_field(&reply, "proto"));
+    }
+
+    #[tokio::test]
+    async fn test_null_reply_follows_protocol() {
+        let mut handler = create_handler();
+        let get = list_command(&["GET", "missing"]);
+
+        let reply = handler.handle(&get).await.unwrap();
+        assert_eq!(b"$-1\r\n".to_vec(), reply[0].to_data());
+
+        handler
+            .handle(&list_command(&["HELLO", "3"]))
+            .await
+            .unwrap();
+        let reply = handler.handle(&get).await.unwrap();
+        assert_eq!(b"_\r\n".to_vec(), reply[0].to_data());
+    }
+
+    #[tokio::test]
+    async fn test_hello_errors() {
+        let mut handler = create_handler();
+
+        let reply = handler
+            .handle(&list_command(&["HELLO", "4"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::Error("NOPROTO unsupported protocol version".to_string()),
+            reply[0]
+        );
+        let reply = handler
+            .handle(&list_command(&["HELLO", "2", "AUTH", "nobody", "pass"]))
+            .await
+            .unwrap();
+        assert!(matches!(&reply[0], Message::Error(error) if error.starts_with("WRONGPASS")));
+    }
+
+    #[tokio::test]
+    async fn test_config_get_after_set() {
+        let mut handler = create_handler();
+
+        let set = list_command(&["CONFIG", "SET", "maxmemory", "1024"]);
+        assert_eq!(
+            Message::SimpleString("OK".to_string()),
+            handler.handle(&set).await.unwrap()[0]
+        );
+        let get = list_command(&["CONFIG", "GET", "maxmemory"]);
+        assert_eq!(
+            bulk_strings(&["maxmemory", "1024"]),
+            handler.handle(&get).await.unwrap()[0]
+        );
+
+        let get = list_command(&["CONFIG", "GET", "*"]);
+        assert_eq!(
+            bulk_strings(&[
+                "appendonly",
+                "no",
+                "hash-max-listpack-entries",
+                "128",
+                "hash-max-listpack-value",
+                "64",
+                "latency-monitor-threshold",
+                "0",
+                "maxmemory",
+                "1024",
+                "proto-max-bulk-len",
+                "536870912",
+                "save",
+                "3600 1 300 100 60 10000",
+                "slowlog-log-slower-than",
+                "10000",
+                "slowlog-max-len",
+                "128"
+            ]),
+            handler.handle(&get).await.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slowlog_records_slow_commands() {
+        let mut handler = create_handler();
+        handler
+            .handle(&list_command(&[
+                "CONFIG",
+                "SET",
+                "slowlog-log-slower-than",
+                "0",
+            ]))
+            .await
+            .unwrap();
+
+        let set = list_command(&["SET", "key", "value"]);
+        handler.handle(&set).await.unwrap();
+        let reply = handler
+            .handle(&list_command(&["SLOWLOG", "GET", "1"]))
+            .await
+            .unwrap();
+        if let Message::Array(entries) = &reply[0] {
+            assert_eq!(1, entries.len());
+            if let Message::Array(fields) = &entries[0] {
+                assert_eq!(6, fields.len());
+                assert_eq!(set, fields[3]);
+            } else {
+                panic!("expected an entry array, got {}", entries[0]);
+            }
+        } else {
+            panic!("expected an array, got {}", reply[0]);
+        }
+
+        handler
+            .handle(&list_command(&[
+                "CONFIG",
+                "SET",
+                "slowlog-log-slower-than",
+                "-1",
+            ]))
+            .await
+            .unwrap();
+        let reset = list_command(&["SLOWLOG", "RESET"]);
+        assert_eq!(
+            Message::SimpleString("OK".to_string()),
+            handler.handle(&reset).await.unwrap()[0]
+        );
+        let len = list_command(&["SLOWLOG", "LEN"]);
+        assert_eq!(Message::Integer(0), handler.handle(&len).await.unwrap()[0]);
+    }
+
+    #[tokio::test]
+    async fn test_latency_records_slow_command() {
+        let mut handler = create_handler();
+        let latest = list_command(&["LATENCY", "LATEST"]);
+        assert_eq!(bulk_strings(&[]), handler.handle(&latest).await.unwrap()[0]);
+
+        handler
+            .handle(&list_command(&[
+                "CONFIG",
+                "SET",
+                "latency-monitor-threshold",
+                "5",
+            ]))
+            .await
+            .unwrap();
+        handler
+            .handle(&list_command(&["DEBUG", "SLEEP", "0.01"]))
+            .await
+            .unwrap();
+
+        let reply = handler.handle(&latest).await.unwrap();
+        if let Message::Array(events) = &reply[0] {
+            assert_eq!(1, events.len());
+            if let Message::Array(fields) = &events[0] {
+                assert_eq!(Message::BulkString("command".to_string()), fields[0]);
+                assert!(matches!(fields[2], Message::Integer(millis) if millis >= 10));
+            } else {
+                panic!("expected an event array, got {}", events[0]);
+            }
+        } else {
+            panic!("expected an array, got {}", reply[0]);
+        }
+
+        let reset = list_command(&["LATENCY", "RESET"]);
+        assert_eq!(
+            Message::Integer(1),
+            handler.handle(&reset).await.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_reaches_subscriber() {
+        let (mut subscriber, _, state) = create_handler_recx_and_state();
+        let (tx, _) = broadcast::channel(1);
+        let mut publisher = MessageHandler::new(Arc::new(Db::new()), state, tx);
+
+        let replies = subscriber
+            .handle(&list_command(&["SUBSCRIBE", "a", "b"]))
+            .await
+            .unwrap();
+        assert_eq!(2, replies.len());
+        assert_eq!(
+            Message::Array(vec![
+                Message::BulkString("subscribe".to_string()),
+                Message::BulkString("b".to_string()),
+                Message::Integer(2),
+            ]),
+            replies[1]
+        );
+
+        let publish = list_command(&["PUBLISH", "b", "hello"]);
+        assert_eq!(
+            Message::Integer(1),
+            publisher.handle(&publish).await.unwrap()[0]
+        );
+        assert_eq!(
+            Some(Bytes::from(
+                bulk_strings(&["message", "b", "hello"]).to_data()
+            )),
+            subscriber.next_push().await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_busy_channel_does_not_starve_others() {
+        let (mut subscriber, _, state) = create_handler_recx_and_state();
+        let (tx, _) = broadcast::channel(1);
+        let mut publisher = MessageHandler::new(Arc::new(Db::new()), state, tx);
+        subscriber
+            .handle(&list_command(&["SUBSCRIBE", "a", "b"]))
+            .await
+            .unwrap();
+
+        for channel in ["a", "a", "a", "b", "b", "b"] {
+            let publish = list_command(&["PUBLISH", channel, "hello"]);
+            publisher.handle(&publish).await.unwrap();
+        }
+        let mut channels = vec![];
+        for _ in 0..4 {
+            let push = subscriber.next_push().await.unwrap();
+            channels.push(
+                if push == bulk_strings(&["message", "a", "hello"]).to_data() {
+                    "a"
+                } else {
+                    "b"
+                },
+            );
+        }
+        assert_eq!(
+            2,
+            channels.iter().filter(|channel| **channel == "a").count()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_switching_to_resp3_gets_pushes() {
+        let (mut subscriber, _, state) = create_handler_recx_and_state();
+        let (tx, _) = broadcast::channel(1);
+        let mut publisher = MessageHandler::new(Arc::new(Db::new()), state, tx);
+        subscriber
+            .handle(&list_command(&["SUBSCRIBE", "news"]))
+            .await
+            .unwrap();
+
+        let reply = subscriber
+            .handle(&list_command(&["HELLO", "3"]))
+            .await
+            .unwrap()
+            .remove(0);
+        assert!(matches!(reply, Message::Map(_)));
+
+        let publish = list_command(&["PUBLISH", "news", "hello"]);
+        assert_eq!(
+            Message::Integer(1),
+            publisher.handle(&publish).await.unwrap()[0]
+        );
+        assert_eq!(
+            Some(Bytes::from(
+                Message::push_for(
+                    3,
+                    vec![
+                        Message::BulkString("message".to_string()),
+                        Message::BulkString("news".to_string()),
+                        Message::BulkString("hello".to_string()),
+                    ]
+                )
+                .to_data()
+            )),
+            subscriber.next_push().await
+        );
+
+        // the subscription is kept, commands are allowed besides the pushes
+        let get = list_command(&["GET", "missing"]);
+        assert_eq!(Message::Null, subscriber.handle(&get).await.unwrap()[0]);
+        assert_eq!(
+            Message::Push(vec![
+                Message::BulkString("unsubscribe".to_string()),
+                Message::BulkString("news".to_string()),
+                Message::Integer(0),
+            ]),
+            subscriber
+                .handle(&list_command(&["UNSUBSCRIBE"]))
+                .await
+                .unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disconnected_subscriber_is_removed_from_registry() {
+        let (mut subscriber, _, state) = create_handler_recx_and_state();
+        subscriber
+            .handle(&list_command(&["SUBSCRIBE", "a", "b"]))
+            .await
+            .unwrap();
+        subscriber
+            .handle(&list_command(&["PSUBSCRIBE", "news.*"]))
+            .await
+            .unwrap();
+        assert_eq!(3, state.pubsub.registered().await);
+
+        drop(subscriber);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while state.pubsub.registered().await > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("registry not cleaned up");
+    }
+
+    #[tokio::test]
+    async fn test_psubscribe_receives_matching_publish() {
+        let (mut subscriber, _, state) = create_handler_recx_and_state();
+        let (tx, _) = broadcast::channel(1);
+        let mut publisher = MessageHandler::new(Arc::new(Db::new()), state, tx);
+
+        subscriber
+            .handle(&list_command(&["SUBSCRIBE", "other"]))
+            .await
+            .unwrap();
+        let replies = subscriber
+            .handle(&list_command(&["PSUBSCRIBE", "news.*"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::Array(vec![
+                Message::BulkString("psubscribe".to_string()),
+                Message::BulkString("news.*".to_string()),
+                Message::Integer(2),
+            ]),
+            replies[0]
+        );
+
+        let publish = list_command(&["PUBLISH", "news.tech", "hello"]);
+        assert_eq!(
+            Message::Integer(1),
+            publisher.handle(&publish).await.unwrap()[0]
+        );
+        assert_eq!(
+            Some(Bytes::from(
+                bulk_strings(&["pmessage", "news.*", "news.tech", "hello"]).to_data()
+            )),
+            subscriber.next_push().await
+        );
+
+        let replies = subscriber
+            .handle(&list_command(&["PUNSUBSCRIBE"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::Array(vec![
+                Message::BulkString("punsubscribe".to_string()),
+                Message::BulkString("news.*".to_string()),
+                Message::Integer(1),
+            ]),
+            replies[0]
+        );
+        assert_eq!(
+            Message::Integer(0),
+            publisher.handle(&publish).await.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multi_exec_runs_queued_commands() {
+        let mut handler = create_handler();
+
+        let reply = handler.handle(&list_command(&["MULTI"])).await.unwrap();
+        assert_eq!(Message::SimpleString("OK".to_string()), reply[0]);
+        for command in [
+            list_command(&["SET", "key", "1"]),
+            list_command(&["INCR", "key"]),
+            list_command(&["LPUSH", "key", "x"]),
+        ] {
+            let reply = handler.handle(&command).await.unwrap();
+            assert_eq!(Message::SimpleString("QUEUED".to_string()), reply[0]);
+        }
+
+        let reply = handler.handle(&list_command(&["EXEC"])).await.unwrap();
+        assert_eq!(
+            Message::Array(vec![
+                Message::SimpleString("OK".to_string()),
+                Message::Integer(2),
+                Message::Error(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string()
+                ),
+            ]),
+            reply[0]
+        );
+        let reply = handler.handle(&list_command(&["EXEC"])).await.unwrap();
+        assert_eq!(
+            Message::Error("ERR EXEC without MULTI".to_string()),
+            reply[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_propagates_writes_as_transaction() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+
+        handler.handle(&list_command(&["MULTI"])).await.unwrap();
+        let set_a = list_command(&["SET", "a", "1"]);
+        let set_b = list_command(&["SET", "b", "2"]);
+        for command in [&set_a, &list_command(&["GET", "a"]), &set_b] {
+            handler.handle(command).await.unwrap();
+        }
+        assert!(rx.try_recv().is_err());
+
+        handler.handle(&list_command(&["EXEC"])).await.unwrap();
+        for expected in [
+            list_command(&["MULTI"]),
+            set_a,
+            set_b,
+            list_command(&["EXEC"]),
+        ] {
+            assert_eq!(expected, rx.recv().await.unwrap());
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_after_invalid_command_aborts() {
+        let mut handler = create_handler();
+
+        handler.handle(&list_command(&["MULTI"])).await.unwrap();
+        handler
+            .handle(&list_command(&["SET", "key", "1"]))
+            .await
+            .unwrap();
+        handler.handle(&list_command(&["GET"])).await.unwrap();
+
+        let reply = handler.handle(&list_command(&["EXEC"])).await.unwrap();
+        assert!(matches!(&reply[0], Message::Error(error) if error.starts_with("EXECABORT")));
+        let reply = handler
+            .handle(&list_command(&["GET", "key"]))
+            .await
+            .unwrap();
+        assert_eq!(Message::NullBulkString, reply[0]);
+    }
+
+    #[tokio::test]
+    async fn test_exec_replies_error_of_failed_command() {
+        let mut handler = create_handler();
+        handler.handle(&list_command(&["MULTI"])).await.unwrap();
+        for command in [
+            &["SET", "key", "value"][..],
+            &["INCR", "key"],
+            &["SET", "other", "value"],
+        ] {
+            handler.handle(&list_command(command)).await.unwrap();
+        }
+
+        let reply = handler.handle(&list_command(&["EXEC"])).await.unwrap();
+        let ok = Message::SimpleString("OK".to_string());
+        assert_eq!(
+            Message::Array(vec![
+                ok.clone(),
+                Message::Error("ERR value is not an integer or out of range".to_string()),
+                ok,
+            ]),
+            reply[0]
+        );
+        assert_eq!(
+            Message::BulkString("value".to_string()),
+            get_value(&mut handler, "other").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_aborts_after_watched_key_expired() {
+        let mut handler = create_handler();
+        handler
+            .handle(&list_command(&["SET", "key", "value", "PX", "20"]))
+            .await
+            .unwrap();
+        handler
+            .handle(&list_command(&["WATCH", "key"]))
+            .await
+            .unwrap();
+        handler.handle(&list_command(&["MULTI"])).await.unwrap();
+        handler
+            .handle(&list_command(&["SET", "other", "value"]))
+            .await
+            .unwrap();
+
+        // nothing reads the key, it expires without being removed
+        sleep(Duration::from_millis(30)).await;
+
+        let reply = handler.handle(&list_command(&["EXEC"])).await.unwrap();
+        assert_eq!(Message::NullBulkString, reply[0]);
+        assert_eq!(
+            Message::NullBulkString,
+            get_value(&mut handler, "other").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_aborts_after_concurrent_write_to_watched_key() {
+        let (mut handler, _, state) = create_handler_recx_and_state();
+        let db = handler.db.clone();
+        let (tx, _) = broadcast::channel(1);
+        let mut other = MessageHandler::new(db.clone(), state, tx);
+
+        handler
+            .handle(&list_command(&["WATCH", "key"]))
+            .await
+            .unwrap();
+        handler.handle(&list_command(&["MULTI"])).await.unwrap();
+        handler
+            .handle(&list_command(&["SET", "key", "mine"]))
+            .await
+            .unwrap();
+
+        other
+            .handle(&list_command(&["SET", "key", "theirs"]))
+            .await
+            .unwrap();
+
+        let reply = handler.handle(&list_command(&["EXEC"])).await.unwrap();
+        assert_eq!(Message::NullBulkString, reply[0]);
+        let reply = handler
+            .handle(&list_command(&["GET", "key"]))
+            .await
+            .unwrap();
+        assert_eq!(Message::BulkString("theirs".to_string()), reply[0]);
+        assert_eq!(0, db.version_of(&Message::BulkString("key".to_string())));
+
+        // EXEC forgot the watched keys, the next transaction runs
+        handler.handle(&list_command(&["MULTI"])).await.unwrap();
+        handler
+            .handle(&list_command(&["SET", "key", "mine"]))
+            .await
+            .unwrap();
+        let reply = handler.handle(&list_command(&["EXEC"])).await.unwrap();
+        assert_eq!(
+            Message::Array(vec![Message::SimpleString("OK".to_string())]),
+            reply[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_mode_restricts_commands() {
+        let mut handler = create_handler();
+        handler
+            .handle(&list_command(&["SUBSCRIBE", "a"]))
+            .await
+            .unwrap();
+
+        let reply = handler
+            .handle(&list_command(&["GET", "key"]))
+            .await
+            .unwrap();
+        assert!(
+            matches!(&reply[0], Message::Error(error) if error.starts_with("ERR Can't execute 'get'"))
+        );
+        let reply = handler.handle(&list_command(&["PING"])).await.unwrap();
+        assert_eq!(bulk_strings(&["pong", ""]), reply[0]);
+
+        handler
+            .handle(&list_command(&["UNSUBSCRIBE", "a"]))
+            .await
+            .unwrap();
+        let reply = handler
+            .handle(&list_command(&["GET", "key"]))
+            .await
+            .unwrap();
+        assert_eq!(Message::NullBulkString, reply[0]);
+        let reply = handler
+            .handle(&list_command(&["UNSUBSCRIBE"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::Array(vec![
+                Message::BulkString("unsubscribe".to_string()),
+                Message::NullBulkString,
+                Message::Integer(0),
+            ]),
+            reply[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_unknown_parameter() {
+        let mut handler = create_handler();
+
+        let get = list_command(&["CONFIG", "GET", "unknown"]);
+        assert_eq!(bulk_strings(&[]), handler.handle(&get).await.unwrap()[0]);
+
+        let set = list_command(&["CONFIG", "SET", "unknown", "1"]);
+        assert_eq!(
+            Message::Error(
+                "ERR Unknown option or number of arguments for CONFIG SET - 'unknown'".to_string()
+            ),
+            handler.handle(&set).await.unwrap()[0]
+        );
+        let set = list_command(&["CONFIG", "SET", "appendonly", "maybe"]);
+        assert!(matches!(
+            handler.handle(&set).await.unwrap()[0],
+            Message::Error(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_acl_whoami() {
+        assert_eq!(
+            Message::BulkString("default".to_string()),
+            handle_acl("whoami").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_acl_list() {
+        assert_eq!(
+            Message::Array(vec![Message::BulkString(
+                "user default on nopass ~* &* +@all".to_string()
+            )]),
+            handle_acl("LIST").await
+        );
+    }
+
+    async fn handle_function(subcommand: &str) -> Message {
+        let message = Command::Function {
+            subcommand: subcommand.to_string(),
+        }
+        .to_message();
+        handle_test(message).await
+    }
+
+    #[tokio::test]
+    async fn test_function_list_is_empty() {
+        assert_eq!(Message::Array(vec![]), handle_function("list").await);
+    }
+
+    #[tokio::test]
+    async fn test_function_stats() {
+        let result = handle_function("STATS").await;
+        let stats = expect_array(&result);
+        assert_eq!(Message::BulkString("running_script".to_string()), stats[0]);
+        assert_eq!(Message::NullBulkString, stats[1]);
+        assert_eq!(Message::BulkString("engines".to_string()), stats[2]);
+        assert_eq!(Message::Array(vec![]), stats[3]);
+    }
+
+    #[tokio::test]
+    async fn test_function_load_without_engine() {
+        assert!(matches!(handle_function("LOAD").await, Message::Error(_)));
+    }
+
+    fn expect_array(message: &Message) -> &Vec<Message> {
+        if let Message::Array(messages) = message {
+            messages
+        } else {
+            panic!("expected an array, got {}", message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_docs_set() {
+        let message = Command::Docs {
+            names: vec!["SET".to_string()],
+        }
+        .to_message();
+
+        let result = handle_test(message).await;
+        let reply = expect_array(&result);
+        assert_eq!(Message::BulkString("set".to_string()), reply[0]);
+
+        let docs = expect_array(&reply[1]);
+        let position = docs
+            .iter()
+            .position(|m| *m == Message::BulkString("arguments".to_string()))
+            .expect("arguments are documented");
+        let arguments = expect_array(&docs[position + 1]);
+        let names = arguments
+            .iter()
+            .map(|argument| expect_array(argument)[1].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Message::BulkString("key".to_string()),
+                Message::BulkString("value".to_string()),
+                Message::BulkString("seconds".to_string()),
+                Message::BulkString("milliseconds".to_string()),
+            ],
+            names[..4]
+        );
+        assert_eq!(
+            Message::BulkString("EX".to_string()),
+            expect_array(&arguments[2])[5]
+        );
+        assert_eq!(
+            Message::BulkString("PX".to_string()),
+            expect_array(&arguments[3])[5]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_incr_and_decr() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let key = Message::BulkString("counter".to_string());
+        let incr = Command::Incr { key: key.clone() }.to_message();
+        let decr = Command::Decr { key }.to_message();
+
+        assert_eq!(Message::Integer(1), handler.handle(&incr).await.unwrap()[0]);
+        assert_eq!(incr, rx.recv().await.unwrap());
+        assert_eq!(Message::Integer(2), handler.handle(&incr).await.unwrap()[0]);
+        assert_eq!(Message::Integer(1), handler.handle(&decr).await.unwrap()[0]);
+        assert_eq!(
+            Message::BulkString("1".to_string()),
+            get_value(&mut handler, "counter").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_incrby_and_decrby() {
+        let mut handler = create_handler();
+        let key = Message::BulkString("counter".to_string());
+
+        let incrby = Command::IncrBy {
+            key: key.clone(),
+            increment: 10,
+        };
+        assert_eq!(
+            Message::Integer(10),
+            handler.handle(&incrby.to_message()).await.unwrap()[0]
+        );
+        let decrby = Command::DecrBy { key, decrement: 15 };
+        assert_eq!(
+            Message::Integer(-5),
+            handler.handle(&decrby.to_message()).await.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_incrbyfloat_replicates_as_set() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let key = Message::BulkString("float".to_string());
+        let incrbyfloat = Message::Array(vec![
+            Message::BulkString("INCRBYFLOAT".to_string()),
+            key.clone(),
+            Message::BulkString("3.0e3".to_string()),
+        ]);
+
+        let result = handler.handle(&incrbyfloat).await.unwrap();
+        assert_eq!(Message::BulkString("3000".to_string()), result[0]);
+
+        let expected = Command::Set {
+            key,
+            value: Message::BulkString("3000".to_string()),
+            expiry: Some(SetExpiry::KeepTtl),
+            condition: SetCondition::Always,
+            get: false,
+        };
+        assert_eq!(expected.to_message(), rx.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let append = |value: &str| {
+            Command::Append {
+                key: Message::BulkString("key".to_string()),
+                value: Message::BulkString(value.to_string()),
+            }
+            .to_message()
+        };
+
+        let result = handler.handle(&append("Hello")).await.unwrap();
+        assert_eq!(Message::Integer(5), result[0]);
+        assert_eq!(append("Hello"), rx.recv().await.unwrap());
+
+        let result = handler.handle(&append(" World")).await.unwrap();
+        assert_eq!(Message::Integer(11), result[0]);
+        assert_eq!(
+            Message::BulkString("Hello World".to_string()),
+            get_value(&mut handler, "key").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_binary_append_and_getrange() {
+        let mut handler = create_handler();
+        let append = Command::Append {
+            key: Message::BulkString("key".to_string()),
+            value: Message::BulkBytes(vec![b'a', 0, 0xff]),
+        };
+        handler.handle(&append.to_message()).await.unwrap();
+        let result = handler.handle(&append.to_message()).await.unwrap();
+        assert_eq!(Message::Integer(6), result[0]);
+
+        let getrange = |start: &str, end: &str| list_command(&["GETRANGE", "key", start, end]);
+        assert_eq!(
+            Message::BulkBytes(vec![0, 0xff, b'a']),
+            handler.handle(&getrange("1", "3")).await.unwrap()[0]
+        );
+        assert_eq!(
+            Message::BulkBytes(vec![0xff]),
+            handler.handle(&getrange("-1", "100")).await.unwrap()[0]
+        );
+        assert_eq!(
+            Message::BulkString("a".to_string()),
+            handler.handle(&getrange("-100", "0")).await.unwrap()[0]
+        );
+        assert_eq!(
+            Message::BulkString(String::new()),
+            handler.handle(&getrange("-1", "-2")).await.unwrap()[0]
+        );
+        assert_eq!(
+            Message::BulkString(String::new()),
+            handler
+                .handle(&list_command(&["GETRANGE", "missing", "0", "-1"]))
+                .await
+                .unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ttl_and_pttl() {
+        let mut handler = create_handler();
+        let key = |name: &str| Message::BulkString(name.to_string());
+        let value = Message::BulkString("value".to_string());
+        handler
+            .db
+            .set(key("persistent"), value.clone(), None)
+            .await
+            .unwrap();
+        handler
+            .db
+            .set(key("volatile"), value, Some(1500))
+            .await
+            .unwrap();
+
+        for (name, ttl) in [("missing", -2), ("persistent", -1), ("volatile", 2)] {
+            let result = handler
+                .handle(&Command::Ttl { key: key(name) }.to_message())
+                .await
+                .unwrap();
+            assert_eq!(Message::Integer(ttl), result[0]);
+        }
+
+        let result = handler
+            .handle(
+                &Command::Pttl {
+                    key: key("volatile"),
+                }
+                .to_message(),
+            )
+            .await
+            .unwrap();
+        if let Message::Integer(millis) = result[0] {
+            assert!(millis > 1400 && millis <= 1500);
+        } else {
+            panic!("PTTL should return an integer");
+        }
+    }
+
+    #[test]
+    fn test_ttl_seconds_round_up() {
+        for (millis, seconds) in [(1, 1), (999, 1), (1000, 1), (1001, 2), (1500, 2)] {
+            assert_eq!(seconds, ttl_seconds(millis));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expire_and_persist() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let (key, _, message_set) = get_set_command("key", "value");
+        handler.handle(&message_set).await.unwrap();
+        rx.recv().await.unwrap();
+
+        let expire = Command::Expire {
+            key: key.clone(),
+            milliseconds: 100000,
+        }
+        .to_message();
+        assert_eq!(
+            Message::Integer(1),
+            handler.handle(&expire).await.unwrap()[0]
+        );
+        assert_eq!(expire, rx.recv().await.unwrap());
+        assert_eq!(1, handler.db.keyspace_stats().await.expires);
+
+        let persist = Command::Persist { key }.to_message();
+        assert_eq!(
+            Message::Integer(1),
+            handler.handle(&persist).await.unwrap()[0]
+        );
+        assert_eq!(persist, rx.recv().await.unwrap());
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&persist).await.unwrap()[0]
+        );
+        assert_eq!(0, handler.db.keyspace_stats().await.expires);
+    }
+
+    #[tokio::test]
+    async fn test_expire_missing_key() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let expire = Command::Expire {
+            key: Message::BulkString("missing".to_string()),
+            milliseconds: 100000,
+        }
+        .to_message();
+
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&expire).await.unwrap()[0]
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            Message::NullBulkString,
+            get_value(&mut handler, "missing").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pexpire_out_of_range() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let (_, _, message_set) = get_set_command("key", "value");
+        handler.handle(&message_set).await.unwrap();
+        rx.recv().await.unwrap();
+
+        let pexpire = list_command(&["PEXPIRE", "key", &i64::MAX.to_string()]);
+        assert_eq!(
+            Message::Error("ERR invalid expire time in 'expire' command".to_string()),
+            handler.handle(&pexpire).await.unwrap()[0]
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            Message::BulkString("value".to_string()),
+            get_value(&mut handler, "key").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_type() {
+        let mut handler = create_handler();
+        let (key, _, message_set) = get_set_command("key", "value");
+        handler.handle(&message_set).await.unwrap();
+
+        let result = handler
+            .handle(&Command::Type { key }.to_message())
+            .await
+            .unwrap();
+        assert_eq!(Message::SimpleString("string".to_string()), result[0]);
+
+        let missing = Command::Type {
+            key: Message::BulkString("missing".to_string()),
+        };
+        let result = handler.handle(&missing.to_message()).await.unwrap();
+        assert_eq!(Message::SimpleString("none".to_string()), result[0]);
+    }
+
+    #[tokio::test]
+    async fn test_dump_and_restore() {
+        let mut handler = create_handler();
+        // `SET mykey 10` and `DUMP mykey` on redis
+        let payload = Message::BulkBytes(b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n".to_vec());
+        let restore = |key: &str, replace: bool| Command::Restore {
+            key: Message::BulkString(key.to_string()),
+            ttl: 0,
+            payload: payload.clone(),
+            replace,
+            absttl: false,
+        };
+
+        assert_eq!(
+            Message::SimpleString("OK".to_string()),
+            handler
+                .handle(&restore("key", false).to_message())
+                .await
+                .unwrap()[0]
+        );
+        assert_eq!(
+            Message::BulkString("10".to_string()),
+            get_value(&mut handler, "key").await
+        );
+        assert_eq!(
+            Message::Error("BUSYKEY Target key name already exists.".to_string()),
+            handler
+                .handle(&restore("key", false).to_message())
+                .await
+                .unwrap()[0]
+        );
+        assert_eq!(
+            Message::SimpleString("OK".to_string()),
+            handler
+                .handle(&restore("key", true).to_message())
+                .await
+                .unwrap()[0]
+        );
+
+        let rpush = list_command(&["RPUSH", "list", "a", "b"]);
+        handler.handle(&rpush).await.unwrap();
+        let dump = list_command(&["DUMP", "list"]);
+        let payload = handler.handle(&dump).await.unwrap().remove(0);
+        let restore = Command::Restore {
+            key: Message::BulkString("copy".to_string()),
+            ttl: 0,
+            payload: payload.clone(),
+            replace: false,
+            absttl: false,
+        };
+        handler.handle(&restore.to_message()).await.unwrap();
+        assert_eq!(
+            bulk_strings(&["a", "b"]),
+            handler
+                .handle(&list_command(&["LRANGE", "copy", "0", "-1"]))
+                .await
+                .unwrap()[0]
+        );
+
+        let dump = list_command(&["DUMP", "missing"]);
+        assert_eq!(
+            Message::NullBulkString,
+            handler.handle(&dump).await.unwrap()[0]
+        );
+
+        for absttl in [false, true] {
+            let restore = Command::Restore {
+                key: Message::BulkString("expiring".to_string()),
+                ttl: i64::MAX,
+                payload: payload.clone(),
+                replace: false,
+                absttl,
+            };
+            assert_eq!(
+                Message::Error("ERR Invalid TTL value, out of range".to_string()),
+                handler.handle(&restore.to_message()).await.unwrap()[0]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_encoding() {
+        let mut handler = create_handler();
+        let encoding = list_command(&["OBJECT", "ENCODING", "hash"]);
+        assert_eq!(
+            Message::NullBulkString,
+            handler.handle(&encoding).await.unwrap()[0]
+        );
+
+        let set = list_command(&["CONFIG", "SET", "hash-max-listpack-entries", "2"]);
+        handler.handle(&set).await.unwrap();
+        let hset = list_command(&["HSET", "hash", "a", "1", "b", "2"]);
+        handler.handle(&hset).await.unwrap();
+        assert_eq!(
+            Message::BulkString("listpack".to_string()),
+            handler.handle(&encoding).await.unwrap()[0]
+        );
+
+        let hset = list_command(&["HSET", "hash", "c", "3"]);
+        handler.handle(&hset).await.unwrap();
+        assert_eq!(
+            Message::BulkString("hashtable".to_string()),
+            handler.handle(&encoding).await.unwrap()[0]
+        );
+
+        let set = list_command(&["SET", "number", "12345"]);
+        handler.handle(&set).await.unwrap();
+        let encoding = list_command(&["OBJECT", "ENCODING", "number"]);
+        assert_eq!(
+            Message::BulkString("int".to_string()),
+            handler.handle(&encoding).await.unwrap()[0]
+        );
+    }
+
+    fn list_command(parts: &[&str]) -> Message {
+        Message::Array(
+            parts
+                .iter()
+                .map(|part| Message::BulkString(part.to_string()))
+                .collect(),
+        )
+    }
+
+    fn bulk_strings(values: &[&str]) -> Message {
+        Message::Array(
+            values
+                .iter()
+                .map(|value| Message::BulkString(value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_lists() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+
+        let rpush = list_command(&["RPUSH", "list", "b", "c"]);
+        assert_eq!(
+            Message::Integer(2),
+            handler.handle(&rpush).await.unwrap()[0]
+        );
+        assert_eq!(rpush, rx.recv().await.unwrap());
+        let lpush = list_command(&["LPUSH", "list", "a"]);
+        assert_eq!(
+            Message::Integer(3),
+            handler.handle(&lpush).await.unwrap()[0]
+        );
+        assert_eq!(lpush, rx.recv().await.unwrap());
+
+        let lrange = list_command(&["LRANGE", "list", "0", "-1"]);
+        assert_eq!(
+            bulk_strings(&["a", "b", "c"]),
+            handler.handle(&lrange).await.unwrap()[0]
+        );
+        let lrange = list_command(&["LRANGE", "list", "-2", "10"]);
+        assert_eq!(
+            bulk_strings(&["b", "c"]),
+            handler.handle(&lrange).await.unwrap()[0]
+        );
+        let lrange = list_command(&["LRANGE", "list", "2", "1"]);
+        assert_eq!(bulk_strings(&[]), handler.handle(&lrange).await.unwrap()[0]);
+
+        let llen = list_command(&["LLEN", "list"]);
+        assert_eq!(Message::Integer(3), handler.handle(&llen).await.unwrap()[0]);
+    }
+
+    #[tokio::test]
+    async fn test_lpop_and_rpop() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        handler
+            .handle(&list_command(&["RPUSH", "list", "a", "b", "c"]))
+            .await
+            .unwrap();
+        rx.recv().await.unwrap();
+
+        let lpop = list_command(&["LPOP", "list"]);
+        assert_eq!(
+            Message::BulkString("a".to_string()),
+            handler.handle(&lpop).await.unwrap()[0]
+        );
+        assert_eq!(lpop, rx.recv().await.unwrap());
+
+        let rpop = list_command(&["RPOP", "list", "5"]);
+        assert_eq!(
+            bulk_strings(&["c", "b"]),
+            handler.handle(&rpop).await.unwrap()[0]
+        );
+        assert_eq!(rpop, rx.recv().await.unwrap());
+
+        let result = handler.handle(&list_command(&["TYPE", "list"])).await;
+        assert_eq!(
+            Message::SimpleString("none".to_string()),
+            result.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pop_missing_key() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+
+        for command in [
+            list_command(&["LPOP", "missing"]),
+            list_command(&["RPOP", "missing", "2"]),
+        ] {
+            assert_eq!(
+                Message::NullBulkString,
+                handler.handle(&command).await.unwrap()[0]
+            );
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hash_overwrite_field() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+
+        let hset = list_command(&["HSET", "hash", "field", "first", "other", "x"]);
+        assert_eq!(Message::Integer(2), handler.handle(&hset).await.unwrap()[0]);
+        assert_eq!(hset, rx.recv().await.unwrap());
+        let hset = list_command(&["HSET", "hash", "field", "second"]);
+        assert_eq!(Message::Integer(0), handler.handle(&hset).await.unwrap()[0]);
+
+        let hget = list_command(&["HGET", "hash", "field"]);
+        assert_eq!(
+            Message::BulkString("second".to_string()),
+            handler.handle(&hget).await.unwrap()[0]
+        );
+        let hget = list_command(&["HGET", "hash", "missing"]);
+        assert_eq!(
+            Message::NullBulkString,
+            handler.handle(&hget).await.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hgetall_and_hdel() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        handler
+            .handle(&list_command(&[
+                "HSET", "hash", "b", "2", "c", "3", "a", "1",
+            ]))
+            .await
+            .unwrap();
+        rx.recv().await.unwrap();
+
+        let hgetall = list_command(&["HGETALL", "hash"]);
+        assert_eq!(
+            bulk_strings(&["a", "1", "b", "2", "c", "3"]),
+            handler.handle(&hgetall).await.unwrap()[0]
+        );
+
+        let hdel = list_command(&["HDEL", "hash", "a", "b", "c"]);
+        assert_eq!(Message::Integer(3), handler.handle(&hdel).await.unwrap()[0]);
+        assert_eq!(hdel, rx.recv().await.unwrap());
+        assert_eq!(
+            bulk_strings(&[]),
+            handler.handle(&hgetall).await.unwrap()[0]
+        );
+        let exists = list_command(&["EXISTS", "hash"]);
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&exists).await.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_store_commands() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        for sadd in [
+            list_command(&["SADD", "a", "1", "2", "3"]),
+            list_command(&["SADD", "b", "2", "3", "4"]),
+        ] {
+            assert_eq!(Message::Integer(3), handler.handle(&sadd).await.unwrap()[0]);
+            assert_eq!(sadd, rx.recv().await.unwrap());
+        }
+
+        for (command, members) in [
+            ("SINTERSTORE", vec!["2", "3"]),
+            ("SUNIONSTORE", vec!["1", "2", "3", "4"]),
+            ("SDIFFSTORE", vec!["1"]),
+        ] {
+            let store = list_command(&[command, "dest", "a", "b"]);
+            assert_eq!(
+                Message::Integer(members.len() as i64),
+                handler.handle(&store).await.unwrap()[0]
+            );
+            assert_eq!(store, rx.recv().await.unwrap());
+            assert_eq!(
+                bulk_strings(&members),
+                handler
+                    .handle(&list_command(&["SMEMBERS", "dest"]))
+                    .await
+                    .unwrap()[0]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_set_store_deletes_destination() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let set = list_command(&["SET", "dest", "value"]);
+        handler.handle(&set).await.unwrap();
+        rx.recv().await.unwrap();
+
+        let store = list_command(&["SINTERSTORE", "dest", "missing", "other"]);
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&store).await.unwrap()[0]
+        );
+        assert_eq!(store, rx.recv().await.unwrap());
+        let exists = list_command(&["EXISTS", "dest"]);
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&exists).await.unwrap()[0]
+        );
+
+        // nothing was deleted, so nothing is propagated
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&store).await.unwrap()[0]
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hgetall_is_a_map_under_resp3() {
+        let mut handler = create_handler();
+        handler
+            .handle(&list_command(&["HSET", "hash", "b", "2", "a", "1"]))
+            .await
+            .unwrap();
+        handler
+            .handle(&list_command(&["HELLO", "3"]))
+            .await
+            .unwrap();
+
+        let hgetall = list_command(&["HGETALL", "hash"]);
+        let reply = handler.handle(&hgetall).await.unwrap().remove(0);
+        assert_eq!(
+            b"%2\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n".to_vec(),
+            reply.to_data()
+        );
+        let hgetall = list_command(&["HGETALL", "missing"]);
+        assert_eq!(
+            Message::Map(vec![]),
+            handler.handle(&hgetall).await.unwrap()[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_string_command_on_list_is_wrong_type() {
+        let mut handler = create_handler();
+        let wrong_type = Message::Error(
+            "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+        );
+        handler
+            .handle(&list_command(&["LPUSH", "list", "a"]))
+            .await
+            .unwrap();
+
+        for command in [
+            list_command(&["GET", "list"]),
+            list_command(&["INCR", "list"]),
+            list_command(&["APPEND", "list", "a"]),
+            list_command(&["SET", "list", "a", "GET"]),
+        ] {
+            assert_eq!(wrong_type, handler.handle(&command).await.unwrap()[0]);
+        }
+
+        let (_, _, message_set) = get_set_command("string", "value");
+        handler.handle(&message_set).await.unwrap();
+        let result = handler
+            .handle(&list_command(&["LPUSH", "string", "a"]))
+            .await
+            .unwrap();
+        assert_eq!(wrong_type, result[0]);
+        assert_eq!(
+            Message::BulkString("value".to_string()),
+            get_value(&mut handler, "string").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_incr_not_an_integer() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let (key, _, message_set) = get_set_command("key", "value");
+        handler.handle(&message_set).await.unwrap();
+        rx.recv().await.unwrap();
+
+        let result = handler
+            .handle(&Command::Incr { key }.to_message())
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::Error("ERR value is not an integer or out of range".to_string()),
+            result[0]
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_reply() -> Result<()> {
+        let (mut handler, _, state) = create_handler_recx_and_state();
+        let wait_command = list_command(&["WAIT", "1", "0"]);
+
+        let result = handler.handle(&wait_command).await?;
+        assert_eq!(Message::Integer(0), result[0]);
+
+        state.add_replication_client("127.0.0.1:6380");
+
+        let result = handler.handle(&wait_command).await?;
+        assert_eq!(Message::Integer(1), result[0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wait_asks_replicas_for_their_offset() {
+        let state = Arc::new(ServerConfig::new(ServerRole::Leader, 1234));
+        let (tx, mut rx) = broadcast::channel(4);
+        let mut handler = MessageHandler::new(Arc::new(Db::new()), state.clone(), tx);
+        state.add_replication_client("127.0.0.1:6380");
+        let (_, _, set) = get_set_command("key", "value");
+        handler.handle(&set).await.unwrap();
+
+        let wait = list_command(&["WAIT", "1", "10"]);
+        assert_eq!(Message::Integer(0), handler.handle(&wait).await.unwrap()[0]);
+        assert_eq!(set, rx.recv().await.unwrap());
+        assert_eq!(
+            Command::get_replconf_command("GETACK", "*"),
+            rx.recv().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_ignores_heartbeats_sent_while_waiting() {
+        let (mut handler, _rx, state) = create_handler_recx_and_state();
+        let replica = "127.0.0.1:6380";
+        state.add_replication_client(replica);
+        let (_, _, set) = get_set_command("key", "value");
+        handler.handle(&set).await.unwrap();
+
+        let wait_for = |timeout: &str| list_command(&["WAIT", "1", timeout]);
+        assert_eq!(
+            Message::Integer(0),
+            handler.handle(&wait_for("10")).await.unwrap()[0]
+        );
+        // the replica acknowledges the offset before the GETACK of WAIT
+        let offset = state.master_repl_offset();
+
+        let wait = tokio::spawn(async move { handler.handle(&wait_for("0")).await });
+        let (heartbeat_tx, _heartbeat_rx) = broadcast::channel(1);
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            state.ping_replicas(&heartbeat_tx);
+        }
+        assert!(state.master_repl_offset() > offset);
+        assert!(!wait.is_finished());
+
+        state.acknowledge_replica(replica, offset);
+        let result = tokio::time::timeout(Duration::from_secs(1), wait)
+            .await
+            .expect("WAIT did not return")
+            .unwrap()
+            .unwrap();
+        assert_eq!(Message::Integer(1), result[0]);
     }
 }
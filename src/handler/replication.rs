@@ -5,59 +5,258 @@ use tokio::sync::broadcast::Sender;
 
 use crate::{
     command_parser::{parse_command, Command},
-    db::Db,
+    db::{expire_date_from_now, restore_expire_date, Db, ListEnd, SetCondition, SetExpiry},
+    dump,
     message::Message,
+    ServerConfig,
 };
 
 use super::distribute_message;
 
 pub struct ReplicationHandler {
     db: Arc<Db>,
+    config: Arc<ServerConfig>,
     sender: Sender<Message>,
     bytes_acknowledged: i64,
 }
 
 impl ReplicationHandler {
-    pub fn new(db: Arc<Db>, sender: Sender<Message>) -> Self {
+    pub fn new(db: Arc<Db>, config: Arc<ServerConfig>, sender: Sender<Message>) -> Self {
         Self {
             db,
+            config,
             sender,
             bytes_acknowledged: 0,
         }
     }
 
+    // A full resync starts at the offset the leader sent.
+    pub fn reset_acknowledged(&mut self, offset: i64) {
+        self.bytes_acknowledged = offset;
+    }
+
     pub async fn handle(&mut self, message: &Message) -> Result<Option<Message>> {
         let previously_acknowledged = self.bytes_acknowledged;
         self.bytes_acknowledged += message.to_data().len() as i64;
 
         let command = parse_command(message)?;
-        match command {
-            Command::Ping => Ok(None),
+        // a replica forwards the writes to its own replicas, transactions
+        // of the leader included
+        let write = (command.is_write() || matches!(command, Command::Multi | Command::Exec))
+            .then(|| command.to_message());
+        let reply = match command {
+            Command::Ping => None,
             Command::Set {
                 ref key,
                 ref value,
-                expire_time,
+                expiry,
+                condition,
+                get,
+            } => {
+                self.db
+                    .set_with(key.clone(), value.clone(), expiry, condition, get)
+                    .await?;
+                None
+            }
+            Command::SetNx { ref key, ref value } => {
+                self.db
+                    .set_with(
+                        key.clone(),
+                        value.clone(),
+                        None,
+                        SetCondition::IfNotExists,
+                        false,
+                    )
+                    .await?;
+                None
+            }
+            Command::SetEx {
+                ref key,
+                seconds,
+                ref value,
+            } => {
+                self.db
+                    .set_with(
+                        key.clone(),
+                        value.clone(),
+                        Some(SetExpiry::Milliseconds(seconds * 1000)),
+                        SetCondition::Always,
+                        false,
+                    )
+                    .await?;
+                None
+            }
+            Command::Incr { ref key } => {
+                self.db.increment_by(key, 1).await?;
+                None
+            }
+            Command::Decr { ref key } => {
+                self.db.increment_by(key, -1).await?;
+                None
+            }
+            Command::IncrBy { ref key, increment } => {
+                self.db.increment_by(key, increment).await?;
+                None
+            }
+            Command::DecrBy { ref key, decrement } => {
+                self.db.increment_by(key, -decrement).await?;
+                None
+            }
+            Command::IncrByFloat { ref key, increment } => {
+                self.db.increment_by_float(key, increment).await?;
+                None
+            }
+            Command::Expire {
+                ref key,
+                milliseconds,
+            } => {
+                self.db
+                    .set_expiry(key, Some(expire_date_from_now(milliseconds)?))
+                    .await;
+                None
+            }
+            Command::FlushAll => {
+                self.db.clear().await;
+                None
+            }
+            Command::Del { ref keys } => {
+                self.db.delete(keys).await;
+                None
+            }
+            Command::Persist { ref key } => {
+                self.db.persist(key).await;
+                None
+            }
+            Command::Restore {
+                ref key,
+                ttl,
+                ref payload,
+                replace,
+                absttl,
             } => {
-                self.db.set(key.clone(), value.clone(), expire_time).await?;
-                distribute_message(&self.sender, &command.clone().to_message());
-                Ok(None)
+                let limits = self.config.listpack_limits().await;
+                let value = dump::restore(payload.as_bytes().unwrap_or_default(), &limits)?;
+                let expire_date = restore_expire_date(ttl, absttl)?;
+                self.db.restore(key, value, expire_date, replace).await?;
+                None
+            }
+            Command::Append { ref key, ref value } => {
+                self.db
+                    .append(key, value.as_bytes().unwrap_or_default())
+                    .await?;
+                None
             }
+            Command::LPush {
+                ref key,
+                ref values,
+            } => {
+                self.db.push(key, values, ListEnd::Left).await?;
+                None
+            }
+            Command::RPush {
+                ref key,
+                ref values,
+            } => {
+                self.db.push(key, values, ListEnd::Right).await?;
+                None
+            }
+            Command::LPop { ref key, count } => {
+                self.db.pop(key, ListEnd::Left, count.unwrap_or(1)).await?;
+                None
+            }
+            Command::RPop { ref key, count } => {
+                self.db.pop(key, ListEnd::Right, count.unwrap_or(1)).await?;
+                None
+            }
+            Command::HSet {
+                ref key,
+                ref fields,
+            } => {
+                let limits = self.config.listpack_limits().await;
+                self.db.hset(key, fields, &limits).await?;
+                None
+            }
+            Command::HDel {
+                ref key,
+                ref fields,
+            } => {
+                self.db.hdel(key, fields).await?;
+                None
+            }
+            Command::SAdd {
+                ref key,
+                ref members,
+            } => {
+                self.db.sadd(key, members).await?;
+                None
+            }
+            Command::SetStore {
+                operation,
+                ref destination,
+                ref keys,
+            } => {
+                self.db.set_store(destination, operation, keys).await?;
+                None
+            }
+            // the writes in between are applied as they come, no other
+            // client runs commands on a replica
+            Command::Multi | Command::Exec => None,
             Command::Replconf { name, value: _ } => {
                 if name.to_uppercase() != "GETACK" {
                     bail!("Only GETACK implemented for repl");
                 }
 
-                Ok(Some(Command::get_replconf_command(
+                Some(Command::get_replconf_command(
                     "ACK",
                     previously_acknowledged,
-                )))
+                ))
             }
             Command::Echo(_)
+            | Command::Hello { .. }
             | Command::Get { .. }
+            | Command::GetRange { .. }
             | Command::Info { .. }
             | Command::Psync
-            | Command::Wait => bail!("wrong command for replication {}", command.to_message()),
+            | Command::Wait { .. }
+            | Command::Docs { .. }
+            | Command::Cluster { .. }
+            | Command::Acl { .. }
+            | Command::Config { .. }
+            | Command::Slowlog { .. }
+            | Command::Latency { .. }
+            | Command::Function { .. }
+            | Command::RandomKey
+            | Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::PSubscribe { .. }
+            | Command::PUnsubscribe { .. }
+            | Command::Publish { .. }
+            | Command::DbSize
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::Exists { .. }
+            | Command::Ttl { .. }
+            | Command::Type { .. }
+            | Command::ObjectEncoding { .. }
+            | Command::Dump { .. }
+            | Command::LRange { .. }
+            | Command::LLen { .. }
+            | Command::HGet { .. }
+            | Command::HGetAll { .. }
+            | Command::SMembers { .. }
+            | Command::Pttl { .. }
+            | Command::DebugSleep { .. } => {
+                bail!("wrong command for replication {}", command.to_message())
+            }
+        };
+
+        if let Some(write) = write {
+            distribute_message(&self.sender, &self.config.master_repl_offset, &write);
         }
+        Ok(reply)
     }
 
     pub fn check_ping_reply(message: &Message) -> Result<()> {
@@ -67,6 +266,7 @@ impl ReplicationHandler {
             {
                 Ok(())
             }
+            Message::Error(error) => bail!("leader replied to ping with error: {}", error),
             _ => bail!("wrong ping reply: {}", message),
         }
     }
@@ -78,13 +278,21 @@ impl ReplicationHandler {
             {
                 Ok(())
             }
+            Message::Error(error) => bail!("leader replied to replconf with error: {}", error),
             _ => bail!("wrong replconf reply: {}", message),
         }
     }
 
-    pub fn check_psync_reply(message: &Message) -> Result<()> {
+    // Returns the offset of the leader from `FULLRESYNC <replid> <offset>`.
+    pub fn check_psync_reply(message: &Message) -> Result<i64> {
         match message {
-            Message::SimpleString(resp) if resp.to_uppercase().starts_with("FULLRESYNC") => Ok(()),
+            Message::SimpleString(resp) if resp.to_uppercase().starts_with("FULLRESYNC") => {
+                match resp.split_whitespace().nth(2).map(str::parse::<i64>) {
+                    Some(Ok(offset)) => Ok(offset),
+                    _ => bail!("wrong offset in psync reply: {}", resp),
+                }
+            }
+            Message::Error(error) => bail!("leader replied to psync with error: {}", error),
             _ => bail!("wrong psync reply: {}", message),
         }
     }
@@ -99,14 +307,15 @@ mod tests {
         time::timeout,
     };
 
-    use crate::handler::test_functions::get_set_command;
+    use crate::{handler::test_functions::get_set_command, ServerRole};
 
     use super::*;
 
     fn create_handler_and_recx() -> (ReplicationHandler, Receiver<Message>) {
         let db = Arc::new(Db::new());
+        let config = Arc::new(ServerConfig::new(ServerRole::Follower, 0));
         let (tx, rx) = broadcast::channel(1);
-        let handler = ReplicationHandler::new(db, tx);
+        let handler = ReplicationHandler::new(db, config, tx);
         (handler, rx)
     }
 
@@ -132,6 +341,47 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_transaction_is_applied_and_forwarded() {
+        let (mut handler, mut rx) = create_handler_and_recx();
+        let multi = Command::Multi.to_message();
+        let (_, _, message_set) = get_set_command("key", "value");
+        let exec = Command::Exec.to_message();
+
+        for message in [&multi, &message_set, &exec] {
+            assert_eq!(None, handler.handle(message).await.unwrap());
+            assert_eq!(*message, rx.recv().await.unwrap());
+        }
+        let key = Message::BulkString("key".into());
+        assert!(handler.db.get(&key).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_expired_key_is_kept_until_leader_deletes_it() {
+        let db = Arc::new(Db::new_follower());
+        let (tx, _rx) = broadcast::channel(1);
+        let config = Arc::new(ServerConfig::new(ServerRole::Follower, 0));
+        let mut handler = ReplicationHandler::new(db.clone(), config, tx);
+        let key = Message::BulkString("key".to_string());
+
+        let set = Command::Set {
+            key: key.clone(),
+            value: Message::BulkString("value".to_string()),
+            expiry: Some(SetExpiry::Milliseconds(1)),
+            condition: SetCondition::Always,
+            get: false,
+        };
+        handler.handle(&set.to_message()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        assert_eq!(None, db.get(&key).await.unwrap());
+        assert_eq!(1, db.keyspace_len().await);
+
+        let del = Command::Del { keys: vec![key] };
+        handler.handle(&del.to_message()).await.unwrap();
+        assert_eq!(0, db.keyspace_len().await);
+    }
+
     #[tokio::test]
     async fn test_getack_returns_message_zero_bytes() -> Result<()> {
         let (mut handler, _rx) = create_handler_and_recx();
@@ -139,6 +389,33 @@ mod tests {
         assert_ack_with_bytes(&mut handler, 0).await
     }
 
+    #[test]
+    fn test_leader_error_is_surfaced() {
+        let error = Message::Error("ERR unknown command 'PSYNC'".to_string());
+
+        let result = ReplicationHandler::check_psync_reply(&error);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("ERR unknown command 'PSYNC'"));
+        assert!(ReplicationHandler::check_ping_reply(&error).is_err());
+        assert!(ReplicationHandler::check_replconf_reply(&error).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_getack_counts_from_fullresync_offset() -> Result<()> {
+        let (mut handler, _rx) = create_handler_and_recx();
+        let fullresync = Message::SimpleString(
+            "FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 100".to_string(),
+        );
+
+        let offset = ReplicationHandler::check_psync_reply(&fullresync)?;
+        assert_eq!(100, offset);
+        handler.reset_acknowledged(offset);
+
+        assert_ack_with_bytes(&mut handler, 100).await
+    }
+
     #[tokio::test]
     async fn test_getack_after_ping_sends_bytes() -> Result<()> {
         let (mut handler, _rx) = create_handler_and_recx();
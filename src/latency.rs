@@ -0,0 +1,163 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::Utc;
+
+use crate::message::Message;
+
+// Samples kept per event, like redis.
+const HISTORY_LEN: usize = 160;
+
+pub const COMMAND_EVENT: &str = "command";
+
+#[derive(Default)]
+struct LatencyEvent {
+    // (unix time in seconds, latency in milliseconds), oldest first
+    samples: VecDeque<(i64, u64)>,
+    max: u64,
+}
+
+// Latency spikes per event, recorded when they exceed
+// latency-monitor-threshold.
+#[derive(Default)]
+pub struct LatencyMonitor {
+    events: HashMap<String, LatencyEvent>,
+}
+
+impl LatencyMonitor {
+    pub fn record(&mut self, event: &str, millis: u64) {
+        self.record_at(event, millis, Utc::now().timestamp());
+    }
+
+    fn record_at(&mut self, event: &str, millis: u64, timestamp: i64) {
+        let event = self.events.entry(event.to_string()).or_default();
+        event.max = event.max.max(millis);
+
+        // one sample per second, the highest latency wins
+        if let Some(last) = event.samples.back_mut() {
+            if last.0 == timestamp {
+                last.1 = last.1.max(millis);
+                return;
+            }
+        }
+        event.samples.push_back((timestamp, millis));
+        if event.samples.len() > HISTORY_LEN {
+            event.samples.pop_front();
+        }
+    }
+
+    // Resets the given events or all if none are given, returns the number
+    // of events that were reset.
+    pub fn reset(&mut self, events: &[String]) -> usize {
+        if events.is_empty() {
+            let count = self.events.len();
+            self.events.clear();
+            return count;
+        }
+
+        events
+            .iter()
+            .filter(|event| self.events.remove(event.as_str()).is_some())
+            .count()
+    }
+
+    // The samples of the event as returned by LATENCY HISTORY.
+    pub fn history(&self, event: &str) -> Message {
+        let samples = match self.events.get(event) {
+            Some(event) => event
+                .samples
+                .iter()
+                .map(|(timestamp, millis)| {
+                    Message::Array(vec![
+                        Message::Integer(*timestamp),
+                        Message::Integer(*millis as i64),
+                    ])
+                })
+                .collect(),
+            None => vec![],
+        };
+        Message::Array(samples)
+    }
+
+    // The last sample and the maximum of every event as returned by
+    // LATENCY LATEST, sorted by event name.
+    pub fn latest(&self) -> Message {
+        let mut names = self.events.keys().collect::<Vec<_>>();
+        names.sort();
+
+        Message::Array(
+            names
+                .into_iter()
+                .filter_map(|name| {
+                    let event = &self.events[name];
+                    let (timestamp, millis) = event.samples.back()?;
+                    Some(Message::Array(vec![
+                        Message::BulkString(name.clone()),
+                        Message::Integer(*timestamp),
+                        Message::Integer(*millis as i64),
+                        Message::Integer(event.max as i64),
+                    ]))
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: i64, millis: i64) -> Message {
+        Message::Array(vec![Message::Integer(timestamp), Message::Integer(millis)])
+    }
+
+    #[test]
+    fn test_one_sample_per_second() {
+        let mut monitor = LatencyMonitor::default();
+        monitor.record_at(COMMAND_EVENT, 5, 100);
+        monitor.record_at(COMMAND_EVENT, 7, 100);
+        monitor.record_at(COMMAND_EVENT, 3, 101);
+
+        assert_eq!(
+            Message::Array(vec![sample(100, 7), sample(101, 3)]),
+            monitor.history(COMMAND_EVENT)
+        );
+        assert_eq!(
+            Message::Array(vec![Message::Array(vec![
+                Message::BulkString(COMMAND_EVENT.to_string()),
+                Message::Integer(101),
+                Message::Integer(3),
+                Message::Integer(7),
+            ])]),
+            monitor.latest()
+        );
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut monitor = LatencyMonitor::default();
+        for timestamp in 0..HISTORY_LEN as i64 + 10 {
+            monitor.record_at(COMMAND_EVENT, 1, timestamp);
+        }
+
+        if let Message::Array(samples) = monitor.history(COMMAND_EVENT) {
+            assert_eq!(HISTORY_LEN, samples.len());
+            assert_eq!(sample(10, 1), samples[0]);
+        } else {
+            panic!("history is not an array");
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut monitor = LatencyMonitor::default();
+        monitor.record(COMMAND_EVENT, 1);
+        monitor.record("other", 1);
+
+        assert_eq!(
+            1,
+            monitor.reset(&["other".to_string(), "missing".to_string()])
+        );
+        assert_eq!(1, monitor.reset(&[]));
+        assert_eq!(Message::Array(vec![]), monitor.latest());
+    }
+}
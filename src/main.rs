@@ -1,22 +1,53 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::{bail, Result};
 use clap::Parser;
-use db::Db;
+use db::{Db, ListpackLimits};
 use tokio::{
     net::ToSocketAddrs,
-    sync::{broadcast, RwLock},
+    sync::{
+        broadcast::{self, Sender},
+        watch, RwLock,
+    },
+    task::JoinHandle,
+    time::{interval, timeout_at, Instant},
 };
 
-use crate::handler::replication::ReplicationHandler;
+use crate::{
+    acl::AclUser,
+    command_parser::Command,
+    glob::glob_match,
+    handler::{distribute_message, replication::ReplicationHandler},
+    latency::LatencyMonitor,
+    message::Message,
+    parser::{ParseLimits, PROTO_INLINE_MAX_SIZE},
+    pubsub::PubSub,
+    slowlog::Slowlog,
+};
 
+mod acl;
+mod admin;
 mod command_parser;
+mod command_table;
 mod db;
+mod dump;
+mod frame_reader;
+mod glob;
 mod handler;
+mod latency;
 mod message;
 mod parser;
+mod pubsub;
 mod replication_client;
 mod server;
+mod slowlog;
 
 /// A redis server implementation
 #[derive(Parser, Debug)]
@@ -27,10 +58,14 @@ struct Args {
 
     #[arg(long)]
     replicaof: Option<String>,
+
+    /// Port for the http health endpoint, disabled if not set
+    #[arg(long)]
+    admin_port: Option<u16>,
 }
 
 impl Args {
-    fn get_leader_addr(&self) -> Result<impl ToSocketAddrs> {
+    fn get_leader_addr(&self) -> Result<impl ToSocketAddrs + Clone> {
         match self.replicaof.clone() {
             Some(addr_and_port) => {
                 let parts = addr_and_port.split(' ').collect::<Vec<_>>();
@@ -58,37 +93,289 @@ enum ServerRole {
 struct ServerConfig {
     role: ServerRole,
     master_replid: String,
-    master_repl_offset: u32,
+    // shared with the db, it propagates the DEL of expired keys
+    master_repl_offset: Arc<AtomicU64>,
+    node_id: String,
     listener_port: u16,
-    replication_clients: RwLock<u16>,
+    // the offsets acknowledged by the connected replicas by their address,
+    // WAIT is notified of every acknowledgement
+    replica_offsets: watch::Sender<HashMap<String, u64>>,
+    // the id of the next client connection, like redis starting at 1
+    next_client_id: AtomicU64,
+    users: Vec<AclUser>,
+    ready: AtomicBool,
+    // a replica is connected to its leader
+    master_link_up: AtomicBool,
+    command_lock: RwLock<()>,
+    // parameters of CONFIG GET/SET, they are only stored
+    parameters: RwLock<HashMap<String, String>>,
+    slowlog: RwLock<Slowlog>,
+    latency: RwLock<LatencyMonitor>,
+    pubsub: PubSub,
 }
 
+// Like repl-ping-replica-period, the leader pings its replicas in this
+// interval. The pings are part of the replication stream and advance the offset.
+const REPLICA_PING_PERIOD: Duration = Duration::from_secs(10);
+
+const DEFAULT_PARAMETERS: &[(&str, &str)] = &[
+    ("maxmemory", "0"),
+    ("save", "3600 1 300 100 60 10000"),
+    ("appendonly", "no"),
+    ("slowlog-log-slower-than", "10000"),
+    ("slowlog-max-len", "128"),
+    ("latency-monitor-threshold", "0"),
+    ("proto-max-bulk-len", "536870912"),
+    ("hash-max-listpack-entries", "128"),
+    ("hash-max-listpack-value", "64"),
+];
+
 impl ServerConfig {
     pub fn new(role: ServerRole, listener_port: u16) -> Self {
         Self {
             role,
             master_replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
-            master_repl_offset: 0,
+            master_repl_offset: Arc::new(AtomicU64::new(0)),
+            node_id: "e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca".to_string(),
             listener_port,
-            replication_clients: RwLock::new(0),
+            replica_offsets: watch::channel(HashMap::new()).0,
+            next_client_id: AtomicU64::new(1),
+            users: vec![AclUser::default_user()],
+            ready: AtomicBool::new(false),
+            master_link_up: AtomicBool::new(false),
+            command_lock: RwLock::new(()),
+            parameters: RwLock::new(
+                DEFAULT_PARAMETERS
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            slowlog: RwLock::new(Slowlog::default()),
+            latency: RwLock::new(LatencyMonitor::default()),
+            pubsub: PubSub::default(),
+        }
+    }
+
+    pub fn new_client_id(&self) -> u64 {
+        self.next_client_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    pub fn set_master_link_up(&self, up: bool) {
+        self.master_link_up.store(up, Ordering::SeqCst);
+    }
+
+    pub fn master_link_up(&self) -> bool {
+        self.master_link_up.load(Ordering::SeqCst)
+    }
+
+    pub fn master_repl_offset(&self) -> u64 {
+        self.master_repl_offset.load(Ordering::SeqCst)
+    }
+
+    // All parameters matching the glob pattern, sorted by name.
+    pub async fn get_parameters(&self, pattern: &str) -> Vec<(String, String)> {
+        let pattern = pattern.to_lowercase();
+        let parameters = self.parameters.read().await;
+        let mut matching = parameters
+            .iter()
+            .filter(|(name, _)| glob_match(&pattern, name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        matching.sort();
+        matching
+    }
+
+    pub async fn set_parameter(&self, name: &str, value: &str) -> Result<()> {
+        let name = name.to_lowercase();
+        let valid = match name.as_str() {
+            "maxmemory"
+            | "slowlog-max-len"
+            | "latency-monitor-threshold"
+            | "hash-max-listpack-entries"
+            | "hash-max-listpack-value" => value.parse::<u64>().is_ok(),
+            "slowlog-log-slower-than" => value.parse::<i64>().is_ok(),
+            "proto-max-bulk-len" => value.parse::<usize>().is_ok_and(|len| len > 0),
+            "appendonly" => matches!(value, "yes" | "no"),
+            "save" => value
+                .split_whitespace()
+                .all(|number| number.parse::<u64>().is_ok()),
+            _ => bail!(
+                "Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            ),
+        };
+        if !valid {
+            bail!(
+                "CONFIG SET failed (possibly related to argument '{}') - invalid value '{}'",
+                name,
+                value
+            );
         }
+
+        self.parameters
+            .write()
+            .await
+            .insert(name, value.to_string());
+        Ok(())
     }
 
-    pub async fn add_replication_client(&self) {
-        let mut count = self.replication_clients.write().await;
-        *count += 1;
+    // Read on every HSET so CONFIG SET applies to the next conversion,
+    // hashes that are already converted stay so.
+    pub async fn listpack_limits(&self) -> ListpackLimits {
+        let parameters = self.parameters.read().await;
+        let limit = |name: &str| parameters[name].parse::<usize>().unwrap_or_default();
+        ListpackLimits {
+            max_entries: limit("hash-max-listpack-entries"),
+            max_value: limit("hash-max-listpack-value"),
+        }
     }
 
-    pub async fn remove_replication_client(&self) {
-        let mut count = self.replication_clients.write().await;
-        assert!(*count > 1, "remove non-existing client");
-        *count -= 1;
+    // The limits for parsing data of clients, read on every pass so
+    // CONFIG SET takes effect on open connections.
+    pub async fn parse_limits(&self) -> ParseLimits {
+        let max_bulk_len = self.parameters.read().await["proto-max-bulk-len"]
+            .parse::<usize>()
+            .unwrap_or(usize::MAX);
+        ParseLimits {
+            max_bulk_len,
+            max_inline_len: PROTO_INLINE_MAX_SIZE,
+        }
     }
 
-    pub async fn active_replication_clients(&self) -> u16 {
-        let count = self.replication_clients.read().await;
-        *count
+    // Stores the command in the slowlog if it took longer than
+    // slowlog-log-slower-than, a negative threshold disables the log.
+    pub async fn record_slow_command(&self, micros: u64, args: &[Message], client_addr: &str) {
+        let (threshold, max_len) = {
+            let parameters = self.parameters.read().await;
+            let threshold = parameters["slowlog-log-slower-than"]
+                .parse::<i64>()
+                .unwrap_or(-1);
+            let max_len = parameters["slowlog-max-len"]
+                .parse::<usize>()
+                .unwrap_or_default();
+            (threshold, max_len)
+        };
+        if threshold < 0 || micros < threshold as u64 {
+            return;
+        }
+
+        self.slowlog
+            .write()
+            .await
+            .record(micros, args, client_addr, max_len);
+    }
+
+    // Records a latency spike of the event if it reached
+    // latency-monitor-threshold, a threshold of 0 disables the monitor.
+    pub async fn record_latency(&self, event: &str, millis: u64) {
+        let threshold = self.parameters.read().await["latency-monitor-threshold"]
+            .parse::<u64>()
+            .unwrap_or_default();
+        if threshold == 0 || millis < threshold {
+            return;
+        }
+
+        self.latency.write().await.record(event, millis);
     }
+
+    pub fn add_replication_client(&self, addr: &str) {
+        self.replica_offsets.send_modify(|offsets| {
+            offsets.insert(addr.to_string(), 0);
+        });
+    }
+
+    pub fn remove_replication_client(&self, addr: &str) {
+        self.replica_offsets.send_modify(|offsets| {
+            offsets.remove(addr);
+        });
+    }
+
+    pub fn active_replication_clients(&self) -> usize {
+        self.replica_offsets.borrow().len()
+    }
+
+    pub fn acknowledge_replica(&self, addr: &str, offset: u64) {
+        self.replica_offsets
+            .send_if_modified(|offsets| match offsets.get_mut(addr) {
+                Some(acknowledged) => {
+                    *acknowledged = offset;
+                    true
+                }
+                None => false,
+            });
+    }
+
+    // The number of replicas that acknowledged the offset.
+    pub fn acknowledged_replicas(&self, offset: u64) -> usize {
+        count_acknowledged(&self.replica_offsets.borrow(), offset)
+    }
+
+    // Waits until numreplicas replicas acknowledged the offset or the timeout
+    // passed. Returns the number of replicas that acknowledged it.
+    pub async fn wait_for_replicas(
+        &self,
+        numreplicas: usize,
+        offset: u64,
+        timeout: Option<Duration>,
+    ) -> usize {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut offsets = self.replica_offsets.subscribe();
+
+        loop {
+            let count = count_acknowledged(&offsets.borrow_and_update(), offset);
+            // without any writes there is nothing to wait for
+            if offset == 0 || count >= numreplicas {
+                return count;
+            }
+
+            // the sender is owned by self, changed cannot fail
+            let changed = offsets.changed();
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline, changed).await.is_err() {
+                        return self.acknowledged_replicas(offset);
+                    }
+                }
+                None => _ = changed.await,
+            }
+        }
+    }
+
+    // Only sent with replicas connected, the offset stays 0 until then.
+    fn ping_replicas(&self, sender: &Sender<Message>) {
+        if self.active_replication_clients() > 0 {
+            distribute_message(
+                sender,
+                &self.master_repl_offset,
+                &Command::get_ping_command(),
+            );
+        }
+    }
+
+    pub fn spawn_replica_heartbeat(self: Arc<Self>, sender: Sender<Message>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(REPLICA_PING_PERIOD);
+            loop {
+                ticker.tick().await;
+                self.ping_replicas(&sender);
+            }
+        })
+    }
+}
+
+fn count_acknowledged(offsets: &HashMap<String, u64>, offset: u64) -> usize {
+    offsets
+        .values()
+        .filter(|acknowledged| **acknowledged >= offset)
+        .count()
 }
 
 #[tokio::main]
@@ -103,22 +390,42 @@ async fn main() {
 
     println!("Using port {port}");
 
-    let db = Arc::new(Db::new());
     let config = Arc::new(ServerConfig::new(role, args.port));
 
     let (tx, rx) = broadcast::channel(20);
     std::mem::drop(rx);
 
+    // a replica does not expire keys itself, it waits for the DEL of the leader
+    let db = if config.role == ServerRole::Follower {
+        Arc::new(Db::new_follower())
+    } else {
+        let db = Arc::new(Db::new_leader(
+            tx.clone(),
+            config.master_repl_offset.clone(),
+        ));
+        config.clone().spawn_replica_heartbeat(tx.clone());
+        db.clone().spawn_expiry_sweeper();
+        db
+    };
+
     if config.role == ServerRole::Follower {
         let leader_addr = args.get_leader_addr().expect("replicaof not set correctly");
         let db_cloned = db.clone();
         let tx_cloned = tx.clone();
-        let handler = ReplicationHandler::new(db_cloned, tx_cloned);
-        let listener_port = config.listener_port;
+        let handler = ReplicationHandler::new(db_cloned, config.clone(), tx_cloned);
+        tokio::spawn(replication_client::start_replication(
+            config.clone(),
+            leader_addr,
+            handler,
+        ));
+    }
+
+    if let Some(admin_port) = args.admin_port {
+        let config_cloned = config.clone();
         tokio::spawn(async move {
-            replication_client::start_replication(listener_port, leader_addr, handler)
+            admin::start(config_cloned, admin_port)
                 .await
-                .unwrap_or_else(|error| eprintln!("replication: {:?}", error));
+                .unwrap_or_else(|error| eprintln!("admin: {:?}", error));
         });
     }
 
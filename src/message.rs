@@ -1,31 +1,91 @@
 use core::fmt;
+use std::hash::{Hash, Hasher};
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Message {
-    //    Null,
+    Null,
     SimpleString(String),
+    Error(String),
     BulkString(String),
+    // A bulk string that is not valid UTF-8, like a DUMP payload.
+    BulkBytes(Vec<u8>),
     NullBulkString,
     Integer(i64),
     Array(Vec<Message>),
+    Map(Vec<(Message, Message)>),
+    Set(Vec<Message>),
+    Double(f64),
+    Boolean(bool),
+    // RESP3 metadata attached to the following value
+    Attribute {
+        attrs: Vec<(Message, Message)>,
+        value: Box<Message>,
+    },
+    // RESP3 out of band data like published messages
+    Push(Vec<Message>),
     RdbFile(Vec<u8>),
 }
 
+// Messages are used as keys of the db. A NaN double is not equal to
+// itself, but doubles only appear in replies and are never stored as keys.
+impl Eq for Message {}
+
+impl Hash for Message {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Null | Self::NullBulkString => {}
+            Self::SimpleString(the_str) | Self::Error(the_str) | Self::BulkString(the_str) => {
+                the_str.hash(state)
+            }
+            Self::Integer(the_int) => the_int.hash(state),
+            Self::Array(vec) | Self::Set(vec) | Self::Push(vec) => vec.hash(state),
+            Self::Map(pairs) => pairs.hash(state),
+            // 0.0 and -0.0 are equal, so they must hash the same
+            Self::Double(the_double) => {
+                let the_double = if *the_double == 0.0 { 0.0 } else { *the_double };
+                the_double.to_bits().hash(state)
+            }
+            Self::Boolean(the_bool) => the_bool.hash(state),
+            Self::Attribute { attrs, value } => {
+                attrs.hash(state);
+                value.hash(state);
+            }
+            Self::BulkBytes(content) | Self::RdbFile(content) => content.hash(state),
+        }
+    }
+}
+
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            // Message::Null => write!(f, "null"),
+            Self::Null => write!(f, "null"),
             Self::SimpleString(the_str) => write!(f, "simple string `{}`", the_str),
+            Self::Error(the_str) => write!(f, "error `{}`", the_str),
             Self::BulkString(the_str) => write!(f, "bulk string `{}`", the_str),
+            Self::BulkBytes(content) => write!(f, "bulk bytes, len {}", content.len()),
             Self::NullBulkString => write!(f, "null bulk string"),
             Self::Integer(the_int) => write!(f, "integer `{}`", the_int),
             Self::Array(vec) => {
-                if vec.len() == 0 {
+                if vec.is_empty() {
                     write!(f, "array with zero items")
                 } else {
                     write!(f, "array with `{}` items, first: `{}`", vec.len(), vec[0])
                 }
             }
+            Self::Map(pairs) => write!(f, "map with `{}` entries", pairs.len()),
+            Self::Set(vec) => write!(f, "set with `{}` items", vec.len()),
+            Self::Double(the_double) => write!(f, "double `{}`", the_double),
+            Self::Boolean(the_bool) => write!(f, "boolean `{}`", the_bool),
+            Self::Attribute { attrs, value } => {
+                write!(
+                    f,
+                    "attribute with `{}` entries, value: `{}`",
+                    attrs.len(),
+                    value
+                )
+            }
+            Self::Push(vec) => write!(f, "push with `{}` items", vec.len()),
             Self::RdbFile(content) => write!(f, "rdb file, len {}", content.len()),
         }
     }
@@ -45,15 +105,19 @@ fn add_len(len: usize, data: &mut Vec<u8>) {
 impl Message {
     pub fn to_data(&self) -> Vec<u8> {
         match self {
-            // Message::Null => {
-            //     vec![b'_', b'\r', b'\n']
-            // }
+            Self::Null => b"_\r\n".to_vec(),
             Self::SimpleString(the_str) => {
                 let mut data = vec![b'+'];
                 data.extend_from_slice(the_str.as_bytes());
                 add_cr_nl(&mut data);
                 data
             }
+            Self::Error(the_str) => {
+                let mut data = vec![b'-'];
+                data.extend_from_slice(the_str.as_bytes());
+                add_cr_nl(&mut data);
+                data
+            }
             Self::BulkString(the_str) => {
                 let mut data = vec![b'$'];
                 add_len(the_str.len(), &mut data);
@@ -61,6 +125,13 @@ impl Message {
                 add_cr_nl(&mut data);
                 data
             }
+            Self::BulkBytes(content) => {
+                let mut data = vec![b'$'];
+                add_len(content.len(), &mut data);
+                data.extend(content);
+                add_cr_nl(&mut data);
+                data
+            }
             Self::NullBulkString => b"$-1\r\n".to_vec(),
             Self::Integer(the_int) => {
                 let mut data = vec![b':'];
@@ -76,6 +147,61 @@ impl Message {
                 }
                 data
             }
+            Self::Map(pairs) => {
+                let mut data = vec![b'%'];
+                add_len(pairs.len(), &mut data);
+                for (key, value) in pairs {
+                    data.extend(key.to_data());
+                    data.extend(value.to_data());
+                }
+                data
+            }
+            Self::Set(set) => {
+                let mut data = vec![b'~'];
+                add_len(set.len(), &mut data);
+                for item in set {
+                    data.extend(item.to_data());
+                }
+                data
+            }
+            Self::Double(the_double) => {
+                let mut data = vec![b','];
+                let the_double = if the_double.is_nan() {
+                    "nan".to_string()
+                } else if the_double.is_infinite() {
+                    if *the_double > 0.0 { "inf" } else { "-inf" }.to_string()
+                } else {
+                    the_double.to_string()
+                };
+                data.extend(the_double.as_bytes());
+                add_cr_nl(&mut data);
+                data
+            }
+            Self::Boolean(the_bool) => {
+                if *the_bool {
+                    b"#t\r\n".to_vec()
+                } else {
+                    b"#f\r\n".to_vec()
+                }
+            }
+            Self::Attribute { attrs, value } => {
+                let mut data = vec![b'|'];
+                add_len(attrs.len(), &mut data);
+                for (key, attr_value) in attrs {
+                    data.extend(key.to_data());
+                    data.extend(attr_value.to_data());
+                }
+                data.extend(value.to_data());
+                data
+            }
+            Self::Push(items) => {
+                let mut data = vec![b'>'];
+                add_len(items.len(), &mut data);
+                for item in items {
+                    data.extend(item.to_data());
+                }
+                data
+            }
             Self::RdbFile(content) => {
                 let mut data = vec![b'$'];
                 add_len(content.len(), &mut data);
@@ -85,8 +211,74 @@ impl Message {
         }
     }
 
+    // Text stays a BulkString, only binary data becomes BulkBytes.
+    pub fn bulk_from_bytes(content: Vec<u8>) -> Message {
+        match String::from_utf8(content) {
+            Ok(the_str) => Message::BulkString(the_str),
+            Err(err) => Message::BulkBytes(err.into_bytes()),
+        }
+    }
+
+    // The content of a string message.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::SimpleString(the_str) | Self::BulkString(the_str) => Some(the_str.as_bytes()),
+            Self::BulkBytes(content) => Some(content),
+            _ => None,
+        }
+    }
+
+    // RESP3 has a dedicated null, RESP2 uses the null bulk string.
+    pub fn null_for(protocol: u8) -> Message {
+        if protocol >= 3 {
+            Message::Null
+        } else {
+            Message::NullBulkString
+        }
+    }
+
+    // RESP3 has a dedicated map, RESP2 flattens the pairs into an array.
+    pub fn map_for(protocol: u8, pairs: Vec<(Message, Message)>) -> Message {
+        if protocol >= 3 {
+            Message::Map(pairs)
+        } else {
+            Message::Array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(key, value)| [key, value])
+                    .collect(),
+            )
+        }
+    }
+
+    // RESP3 marks pushed data, RESP2 only has an array for it.
+    pub fn push_for(protocol: u8, items: Vec<Message>) -> Message {
+        if protocol >= 3 {
+            Message::Push(items)
+        } else {
+            Message::Array(items)
+        }
+    }
+
+    // Replies are built with RESP2 nulls, this swaps them for the null of
+    // the connection's protocol.
+    pub fn with_nulls_for(self, protocol: u8) -> Message {
+        match self {
+            Self::NullBulkString | Self::Null => Message::null_for(protocol),
+            Self::Array(vec) => Self::Array(
+                vec.into_iter()
+                    .map(|item| item.with_nulls_for(protocol))
+                    .collect(),
+            ),
+            message => message,
+        }
+    }
+
     pub fn rdb_file_from_hex(hex_string: &str) -> Message {
-        assert!(hex_string.len() % 2 == 0, "hex string length must be even");
+        assert!(
+            hex_string.len().is_multiple_of(2),
+            "hex string length must be even"
+        );
 
         let bytes = (0..hex_string.len())
             .step_by(2)
@@ -112,6 +304,14 @@ mod tests {
         assert_eq!(expected, m.to_data());
     }
 
+    #[test]
+    fn test_error() {
+        let m = Message::Error("ERR something".to_string());
+        let expected = create_vec("-ERR something\r\n");
+
+        assert_eq!(expected, m.to_data());
+    }
+
     #[test]
     fn test_bulk_string() {
         let m = Message::BulkString("hell\no".to_string());
@@ -120,6 +320,18 @@ mod tests {
         assert_eq!(expected, m.to_data());
     }
 
+    #[test]
+    fn test_bulk_bytes() {
+        let m = Message::bulk_from_bytes(vec![0, 0xff]);
+        let expected = b"$2\r\n\x00\xff\r\n".to_vec();
+
+        assert_eq!(expected, m.to_data());
+        assert_eq!(
+            Message::BulkString("text".to_string()),
+            Message::bulk_from_bytes(b"text".to_vec())
+        );
+    }
+
     #[test]
     fn test_array() {
         let m = Message::Array(vec![
@@ -131,6 +343,113 @@ mod tests {
         assert_eq!(expected, m.to_data());
     }
 
+    #[test]
+    fn test_attribute() {
+        let m = Message::Attribute {
+            attrs: vec![(
+                Message::SimpleString("ttl".to_string()),
+                Message::Integer(3),
+            )],
+            value: Box::new(Message::SimpleString("OK".to_string())),
+        };
+        let expected = create_vec("|1\r\n+ttl\r\n:3\r\n+OK\r\n");
+
+        assert_eq!(expected, m.to_data());
+    }
+
+    #[test]
+    fn test_null() {
+        assert_eq!(create_vec("_\r\n"), Message::Null.to_data());
+    }
+
+    #[test]
+    fn test_map() {
+        let m = Message::Map(vec![
+            (
+                Message::BulkString("field".to_string()),
+                Message::BulkString("value".to_string()),
+            ),
+            (
+                Message::SimpleString("count".to_string()),
+                Message::Integer(2),
+            ),
+        ]);
+        let expected = create_vec("%2\r\n$5\r\nfield\r\n$5\r\nvalue\r\n+count\r\n:2\r\n");
+
+        assert_eq!(expected, m.to_data());
+    }
+
+    #[test]
+    fn test_set() {
+        let m = Message::Set(vec![Message::Integer(1), Message::Boolean(true)]);
+        let expected = create_vec("~2\r\n:1\r\n#t\r\n");
+
+        assert_eq!(expected, m.to_data());
+    }
+
+    #[test]
+    fn test_double() {
+        assert_eq!(create_vec(",1.5\r\n"), Message::Double(1.5).to_data());
+        assert_eq!(create_vec(",-3\r\n"), Message::Double(-3.0).to_data());
+        assert_eq!(
+            create_vec(",inf\r\n"),
+            Message::Double(f64::INFINITY).to_data()
+        );
+        assert_eq!(
+            create_vec(",-inf\r\n"),
+            Message::Double(f64::NEG_INFINITY).to_data()
+        );
+        assert_eq!(create_vec(",nan\r\n"), Message::Double(f64::NAN).to_data());
+    }
+
+    #[test]
+    fn test_boolean() {
+        assert_eq!(create_vec("#t\r\n"), Message::Boolean(true).to_data());
+        assert_eq!(create_vec("#f\r\n"), Message::Boolean(false).to_data());
+    }
+
+    #[test]
+    fn test_nulls_for_protocol() {
+        let reply = Message::Array(vec![Message::NullBulkString, Message::Integer(1)]);
+
+        assert_eq!(reply.clone(), reply.clone().with_nulls_for(2));
+        assert_eq!(
+            Message::Array(vec![Message::Null, Message::Integer(1)]),
+            reply.with_nulls_for(3)
+        );
+    }
+
+    #[test]
+    fn test_map_for_protocol() {
+        let pairs = vec![(Message::BulkString("a".to_string()), Message::Integer(1))];
+
+        assert_eq!(
+            Message::Array(vec![
+                Message::BulkString("a".to_string()),
+                Message::Integer(1)
+            ]),
+            Message::map_for(2, pairs.clone())
+        );
+        assert_eq!(Message::Map(pairs.clone()), Message::map_for(3, pairs));
+    }
+
+    #[test]
+    fn test_push() {
+        let items = vec![
+            Message::BulkString("message".to_string()),
+            Message::Integer(1),
+        ];
+
+        assert_eq!(
+            create_vec(">2\r\n$7\r\nmessage\r\n:1\r\n"),
+            Message::push_for(3, items.clone()).to_data()
+        );
+        assert_eq!(
+            create_vec("*2\r\n$7\r\nmessage\r\n:1\r\n"),
+            Message::push_for(2, items).to_data()
+        );
+    }
+
     #[test]
     fn test_integer() {
         let m = Message::Integer(-293);
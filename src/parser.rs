@@ -1,4 +1,4 @@
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use std::string::FromUtf8Error;
 use thiserror::Error;
 
@@ -14,102 +14,239 @@ pub enum ParseError {
     InvalidString(Bytes),
     #[error("not a valid message `{0:?}`")]
     InvalidSizeContent(Vec<u8>),
+    #[error("not a valid value `{0}`")]
+    InvalidValue(String),
     #[error("unknown message type `{0}`")]
     UnknownMessage(char),
-    #[error("no data was passed")]
-    NoData,
+    #[error("invalid bulk length")]
+    InvalidBulkLength,
+    #[error("too big inline request")]
+    InlineTooBig,
+    #[error("too deeply nested message")]
+    TooDeep,
+    #[error("incomplete message")]
+    Incomplete,
 }
 
 type Result<T> = std::result::Result<T, ParseError>;
 
-type ParsedData = (Message, BytesMut);
+// The parsed message and the data after it.
+type ParsedData<'a> = (Message, &'a [u8]);
 
-pub fn parse_data(mut data: BytesMut) -> Result<Vec<Message>> {
+// Like redis, a line without linebreak is not buffered beyond 64KB.
+pub const PROTO_INLINE_MAX_SIZE: usize = 64 * 1024;
+
+// Arrays, maps, sets and attributes are parsed recursively, deeper nesting
+// is rejected before it can exhaust the stack.
+const MAX_NESTING_DEPTH: usize = 64;
+
+// Bounds for data sent by clients, bigger messages are rejected
+// instead of being buffered until they are complete.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseLimits {
+    pub max_bulk_len: usize,
+    pub max_inline_len: usize,
+}
+
+impl ParseLimits {
+    pub const UNLIMITED: ParseLimits = ParseLimits {
+        max_bulk_len: usize::MAX,
+        max_inline_len: usize::MAX,
+    };
+}
+
+// Parses all complete messages in the buffer and removes their bytes from it.
+// A trailing incomplete message is left in the buffer so more data can be read.
+#[cfg(test)]
+pub fn parse_data(buffer: &mut BytesMut) -> Result<Vec<Message>> {
+    parse_messages(buffer, usize::MAX, &ParseLimits::UNLIMITED)
+}
+
+// Like parse_data, but stops after limit messages. The rest stays in the buffer.
+pub fn parse_messages(
+    buffer: &mut BytesMut,
+    limit: usize,
+    limits: &ParseLimits,
+) -> Result<Vec<Message>> {
     let mut result = vec![];
+    let mut data = &buffer[..];
 
-    while data.len() > 0 {
-        match parse(data) {
+    while !data.is_empty() && result.len() < limit {
+        match parse_limited(data, limits, 0) {
             Ok((message, rest)) => {
                 result.push(message);
                 data = rest;
             }
+            Err(ParseError::Incomplete) => {
+                if data.len() > limits.max_inline_len && find_linebreak(data).is_none() {
+                    return Err(ParseError::InlineTooBig);
+                }
+                break;
+            }
             Err(err) => return Err(err),
         }
     }
 
+    let consumed = buffer.len() - data.len();
+    buffer.advance(consumed);
     Ok(result)
 }
 
-fn parse(mut data: BytesMut) -> Result<ParsedData> {
-    if data.len() == 0 {
-        return Err(ParseError::NoData);
+// The rdb file of a full resync is sent like a bulk string without the
+// ending linebreak. Only the replication handshake expects it, client data
+// is never taken for one. None while the file is incomplete.
+pub fn parse_rdb_file(buffer: &mut BytesMut) -> Result<Option<Message>> {
+    let data = match buffer.split_first() {
+        None => return Ok(None),
+        Some((&b'$', data)) => data,
+        Some((&other, _)) => return Err(ParseError::UnknownMessage(other as char)),
+    };
+    let (size, data) = match read_number(data) {
+        Ok(result) => result,
+        Err(ParseError::Incomplete) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if data.len() < size {
+        return Ok(None);
+    }
+
+    let rdb_file = Message::RdbFile(data[..size].to_vec());
+    let consumed = buffer.len() - data.len() + size;
+    buffer.advance(consumed);
+    Ok(Some(rdb_file))
+}
+
+#[cfg(test)]
+fn parse(data: BytesMut) -> Result<(Message, BytesMut)> {
+    let (message, rest) = parse_limited(&data, &ParseLimits::UNLIMITED, 0)?;
+    Ok((message, BytesMut::from(rest)))
+}
+
+fn parse_limited<'a>(data: &'a [u8], limits: &ParseLimits, depth: usize) -> Result<ParsedData<'a>> {
+    let Some((&type_spec, data)) = data.split_first() else {
+        return Err(ParseError::Incomplete);
+    };
+    if depth > MAX_NESTING_DEPTH {
+        return Err(ParseError::TooDeep);
     }
 
-    let type_spec = data.split_to(1);
-    match type_spec[0] {
+    match type_spec {
         b'+' => parse_simple_string(data),
-        b'$' => parse_bulk_string(data),
+        b'-' => parse_error(data),
+        b'$' => parse_bulk_string_limited(data, limits),
         b':' => parse_integer(data),
-        b'*' => parse_array(data),
+        b'*' => parse_array_limited(data, limits, depth + 1),
+        b'|' => parse_attribute(data, limits, depth + 1),
+        b'%' => parse_map(data, limits, depth + 1),
+        b'~' => parse_set(data, limits, depth + 1),
+        b'>' => parse_push(data, limits, depth + 1),
+        b',' => parse_double(data),
+        b'#' => parse_boolean(data),
+        b'_' => parse_null(data),
         rest => Err(ParseError::UnknownMessage(rest as char)),
     }
 }
 
-fn parse_simple_string(mut data: BytesMut) -> Result<ParsedData> {
-    match find_linebreak(&data) {
+fn parse_simple_string(data: &[u8]) -> Result<ParsedData<'_>> {
+    let (line, rest) = read_line(data)?;
+    Ok((Message::SimpleString(line), rest))
+}
+
+fn parse_error(data: &[u8]) -> Result<ParsedData<'_>> {
+    let (line, rest) = read_line(data)?;
+    Ok((Message::Error(line), rest))
+}
+
+fn parse_double(data: &[u8]) -> Result<ParsedData<'_>> {
+    let (line, rest) = read_line(data)?;
+    match line.parse::<f64>() {
+        Ok(the_double) => Ok((Message::Double(the_double), rest)),
+        Err(_) => Err(ParseError::InvalidValue(line)),
+    }
+}
+
+fn parse_boolean(data: &[u8]) -> Result<ParsedData<'_>> {
+    let (line, rest) = read_line(data)?;
+    match line.as_str() {
+        "t" => Ok((Message::Boolean(true), rest)),
+        "f" => Ok((Message::Boolean(false), rest)),
+        _ => Err(ParseError::InvalidValue(line)),
+    }
+}
+
+fn parse_null(data: &[u8]) -> Result<ParsedData<'_>> {
+    let (line, rest) = read_line(data)?;
+    if !line.is_empty() {
+        return Err(ParseError::InvalidValue(line));
+    }
+    Ok((Message::Null, rest))
+}
+
+fn read_line(data: &[u8]) -> Result<(String, &[u8])> {
+    match find_linebreak(data) {
         Some(pos) => {
-            let rest = data.split_off(pos + 2);
-            let result = String::from_utf8(data[..pos].to_vec())?;
-            return Ok((Message::SimpleString(result), rest));
+            let line = String::from_utf8(data[..pos].to_vec())?;
+            Ok((line, &data[pos + 2..]))
         }
-        None => Err(ParseError::InvalidString(data.freeze())),
+        None => Err(ParseError::Incomplete),
     }
 }
 
-fn parse_bulk_string(mut data: BytesMut) -> Result<ParsedData> {
+#[cfg(test)]
+fn parse_bulk_string(data: BytesMut) -> Result<(Message, BytesMut)> {
+    let (message, rest) = parse_bulk_string_limited(&data, &ParseLimits::UNLIMITED)?;
+    Ok((message, BytesMut::from(rest)))
+}
+
+fn parse_bulk_string_limited<'a>(data: &'a [u8], limits: &ParseLimits) -> Result<ParsedData<'a>> {
     if data.is_empty() {
-        return Err(ParseError::NoData);
+        return Err(ParseError::Incomplete);
     }
 
     match data[0] {
         b'-' => {
-            if &data[1..4] != b"1\r\n" {
+            if data.len() < 4 {
+                Err(ParseError::Incomplete)
+            } else if &data[1..4] != b"1\r\n" {
                 Err(ParseError::InvalidSizeContent(data.to_vec()))
             } else {
-                Ok((Message::NullBulkString, data.split_off(4)))
+                Ok((Message::NullBulkString, &data[4..]))
             }
         }
+        b'?' => parse_streamed_bulk_string(data, limits),
         _ => match read_number(data) {
-            Ok((size, mut data)) => {
-                const REDIS_MAGIC: &[u8; 5] = b"REDIS";
-                if size > 5
-                    // the rdb file has no ending linebreak
-                    && (data.len() == size || &data[size + 1..size + 2] != b"\r\n")
-                    && &data[..5] == REDIS_MAGIC
-                {
-                    return Ok((
-                        Message::RdbFile(data[..size].to_vec()),
-                        data.split_off(size),
-                    ));
+            Ok((size, data)) => {
+                if size > limits.max_bulk_len {
+                    return Err(ParseError::InvalidBulkLength);
+                }
+                if data.len() < size {
+                    return Err(ParseError::Incomplete);
+                }
+
+                if data.len() < size + 2 {
+                    return Err(ParseError::Incomplete);
+                }
+                if &data[size..size + 2] != b"\r\n" {
+                    return Err(ParseError::InvalidString(Bytes::copy_from_slice(data)));
                 }
 
-                let bulk_string = String::from_utf8(data[..size].to_vec())?;
-                Ok((Message::BulkString(bulk_string), data.split_off(size + 2)))
+                let bulk_string = Message::bulk_from_bytes(data[..size].to_vec());
+                Ok((bulk_string, &data[size + 2..]))
             }
             Err(err) => Err(err),
         },
     }
 }
 
-fn parse_integer(mut data: BytesMut) -> Result<ParsedData> {
-    let negative = if data[0] == b'-' {
-        data = data.split_off(1);
-        true
-    } else if data[0] == b'+' {
-        data = data.split_off(1);
-        false
-    } else {
-        false
+fn parse_integer(data: &[u8]) -> Result<ParsedData<'_>> {
+    if data.is_empty() {
+        return Err(ParseError::Incomplete);
+    }
+
+    let (negative, data) = match data[0] {
+        b'-' => (true, &data[1..]),
+        b'+' => (false, &data[1..]),
+        _ => (false, data),
     };
 
     match read_number(data) {
@@ -124,12 +261,59 @@ fn parse_integer(mut data: BytesMut) -> Result<ParsedData> {
     }
 }
 
-fn parse_array(data: BytesMut) -> Result<ParsedData> {
+// A chunked bulk string `$?\r\n` consists of `;<len>\r\n<data>\r\n` parts,
+// a part of length zero ends it.
+fn parse_streamed_bulk_string<'a>(data: &'a [u8], limits: &ParseLimits) -> Result<ParsedData<'a>> {
+    let mut data = skip_streamed_marker(data)?;
+    let mut content = vec![];
+
+    loop {
+        if data.is_empty() {
+            return Err(ParseError::Incomplete);
+        }
+        if data[0] != b';' {
+            return Err(ParseError::InvalidSizeContent(data.to_vec()));
+        }
+
+        let (size, rest) = read_number(&data[1..])?;
+        if content.len() + size > limits.max_bulk_len {
+            return Err(ParseError::InvalidBulkLength);
+        }
+        if size == 0 {
+            return Ok((Message::bulk_from_bytes(content), rest));
+        }
+        if rest.len() < size + 2 {
+            return Err(ParseError::Incomplete);
+        }
+        if &rest[size..size + 2] != b"\r\n" {
+            return Err(ParseError::InvalidString(Bytes::copy_from_slice(rest)));
+        }
+
+        content.extend_from_slice(&rest[..size]);
+        data = &rest[size + 2..];
+    }
+}
+
+#[cfg(test)]
+fn parse_array(data: BytesMut) -> Result<(Message, BytesMut)> {
+    let (message, rest) = parse_array_limited(&data, &ParseLimits::UNLIMITED, 1)?;
+    Ok((message, BytesMut::from(rest)))
+}
+
+fn parse_array_limited<'a>(
+    data: &'a [u8],
+    limits: &ParseLimits,
+    depth: usize,
+) -> Result<ParsedData<'a>> {
+    if data.first() == Some(&b'?') {
+        return parse_streamed_array(data, limits, depth);
+    }
+
     match read_number(data) {
         Ok((array_len, mut data)) => {
             let mut result = vec![];
             for _ in 0..array_len {
-                match parse(data) {
+                match parse_limited(data, limits, depth) {
                     Ok((message, rest_data)) => {
                         result.push(message);
                         data = rest_data;
@@ -143,13 +327,108 @@ fn parse_array(data: BytesMut) -> Result<ParsedData> {
     }
 }
 
-fn read_number(mut data: BytesMut) -> Result<(usize, BytesMut)> {
-    match find_linebreak(&data[..]) {
+// A streamed array `*?\r\n` contains elements until the `.\r\n` terminator.
+fn parse_streamed_array<'a>(
+    data: &'a [u8],
+    limits: &ParseLimits,
+    depth: usize,
+) -> Result<ParsedData<'a>> {
+    let mut data = skip_streamed_marker(data)?;
+    let mut result = vec![];
+
+    loop {
+        if data.first() == Some(&b'.') {
+            if data.len() < 3 {
+                return Err(ParseError::Incomplete);
+            }
+            if &data[..3] != b".\r\n" {
+                return Err(ParseError::InvalidSizeContent(data.to_vec()));
+            }
+            return Ok((Message::Array(result), &data[3..]));
+        }
+
+        let (message, rest) = parse_limited(data, limits, depth)?;
+        result.push(message);
+        data = rest;
+    }
+}
+
+fn skip_streamed_marker(data: &[u8]) -> Result<&[u8]> {
+    if data.len() < 3 {
+        return Err(ParseError::Incomplete);
+    }
+    if &data[..3] != b"?\r\n" {
+        return Err(ParseError::InvalidSizeContent(data.to_vec()));
+    }
+
+    Ok(&data[3..])
+}
+
+fn parse_map<'a>(data: &'a [u8], limits: &ParseLimits, depth: usize) -> Result<ParsedData<'a>> {
+    let (map_len, mut data) = read_number(data)?;
+    let mut pairs = vec![];
+    for _ in 0..map_len {
+        let (key, rest) = parse_limited(data, limits, depth)?;
+        let (value, rest) = parse_limited(rest, limits, depth)?;
+        pairs.push((key, value));
+        data = rest;
+    }
+    Ok((Message::Map(pairs), data))
+}
+
+fn parse_set<'a>(data: &'a [u8], limits: &ParseLimits, depth: usize) -> Result<ParsedData<'a>> {
+    let (set_len, mut data) = read_number(data)?;
+    let mut set = vec![];
+    for _ in 0..set_len {
+        let (message, rest) = parse_limited(data, limits, depth)?;
+        set.push(message);
+        data = rest;
+    }
+    Ok((Message::Set(set), data))
+}
+
+fn parse_push<'a>(data: &'a [u8], limits: &ParseLimits, depth: usize) -> Result<ParsedData<'a>> {
+    let (push_len, mut data) = read_number(data)?;
+    let mut items = vec![];
+    for _ in 0..push_len {
+        let (message, rest) = parse_limited(data, limits, depth)?;
+        items.push(message);
+        data = rest;
+    }
+    Ok((Message::Push(items), data))
+}
+
+fn parse_attribute<'a>(
+    data: &'a [u8],
+    limits: &ParseLimits,
+    depth: usize,
+) -> Result<ParsedData<'a>> {
+    let (attrs_len, mut data) = read_number(data)?;
+    let mut attrs = vec![];
+    for _ in 0..attrs_len {
+        let (key, rest) = parse_limited(data, limits, depth)?;
+        let (value, rest) = parse_limited(rest, limits, depth)?;
+        attrs.push((key, value));
+        data = rest;
+    }
+
+    let (value, rest) = parse_limited(data, limits, depth)?;
+    Ok((
+        Message::Attribute {
+            attrs,
+            value: Box::new(value),
+        },
+        rest,
+    ))
+}
+
+fn read_number(data: &[u8]) -> Result<(usize, &[u8])> {
+    match find_linebreak(data) {
         Some(pos) => match convert_to_number(&data[..pos]) {
-            Ok(size) => Ok((size, data.split_off(pos + 2))),
+            Ok(size) => Ok((size, &data[pos + 2..])),
             Err(err) => Err(err),
         },
-        None => Err(ParseError::InvalidSizeContent(data.to_vec())),
+        None => Err(ParseError::Incomplete),
     }
 }
 
@@ -168,17 +447,7 @@ fn convert_to_number(data: &[u8]) -> Result<usize> {
 
 // returns the position of \r in \r\n
 fn find_linebreak(data: &[u8]) -> Option<usize> {
-    let mut i = 0;
-
-    while i < data.len() - 1 {
-        if data[i] == b'\r' && data[i + 1] == b'\n' {
-            return Some(i);
-        }
-
-        i += 1;
-    }
-
-    None
+    data.windows(2).position(|window| window == b"\r\n")
 }
 
 #[cfg(test)]
@@ -199,8 +468,8 @@ mod tests {
         let data = str_to_bytes(input);
         let rest = str_to_bytes(rest);
         assert_eq!(
-            parse_simple_string(data),
-            Ok((Message::SimpleString(expected.to_string()), rest))
+            parse_simple_string(&data),
+            Ok((Message::SimpleString(expected.to_string()), &rest[..]))
         );
     }
 
@@ -237,6 +506,43 @@ mod tests {
         assert_eq!(Some(5), pos);
     }
 
+    #[test]
+    fn test_read_to_linebreak_empty() {
+        assert_eq!(None, find_linebreak(b""));
+    }
+
+    #[test]
+    fn test_read_to_linebreak_one_byte() {
+        assert_eq!(None, find_linebreak(b"\r"));
+        assert_eq!(None, find_linebreak(b"\n"));
+    }
+
+    #[test]
+    fn test_read_to_linebreak_carriage_return_at_end() {
+        assert_eq!(None, find_linebreak(b"Hello\r"));
+        assert_eq!(Some(0), find_linebreak(b"\r\n"));
+    }
+
+    #[test]
+    fn test_parse_data_array_one_byte_at_a_time() {
+        let input = b"*2\r\n:-12\r\n+ok\r\n";
+        let mut buffer = BytesMut::new();
+
+        for byte in &input[..input.len() - 1] {
+            buffer.extend_from_slice(&[*byte]);
+            assert!(parse_data(&mut buffer).unwrap().is_empty());
+        }
+
+        buffer.extend_from_slice(&input[input.len() - 1..]);
+        assert_eq!(
+            vec![Message::Array(vec![
+                Message::Integer(-12),
+                Message::SimpleString("ok".to_string()),
+            ])],
+            parse_data(&mut buffer).unwrap()
+        );
+    }
+
     #[test]
     fn test_convert_to_number() {
         let result = convert_to_number(&[51, 52]);
@@ -256,9 +562,18 @@ mod tests {
     #[test]
     fn test_simple_string_missing_ending() {
         let data = str_to_bytes("NoCarriageReturnNewline");
+        assert_eq!(parse_simple_string(&data), Err(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn test_error() {
+        let data = str_to_bytes("-ERR unknown command\r\n+next\r\n");
         assert_eq!(
-            parse_simple_string(data.clone()),
-            Err(ParseError::InvalidString(data.freeze()))
+            parse(data),
+            Ok((
+                Message::Error("ERR unknown command".to_string()),
+                str_to_bytes("+next\r\n")
+            ))
         );
     }
 
@@ -276,6 +591,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_binary_bulk_string() {
+        let (message, rest) = parse_bulk_string(BytesMut::from(&b"2\r\n\x00\xff\r\n"[..])).unwrap();
+
+        assert_eq!(Message::BulkBytes(vec![0, 0xff]), message);
+        assert!(rest.is_empty());
+    }
+
     #[test]
     fn test_empty_bulk_string() {
         assert_bulk_string("0\r\n\r\n", "");
@@ -292,22 +615,17 @@ mod tests {
 
     #[test]
     fn test_integer() {
-        let data = str_to_bytes("-1939\r\n");
         assert_eq!(
-            parse_integer(data),
-            Ok((Message::Integer(-1939), BytesMut::new()))
+            parse_integer(b"-1939\r\n"),
+            Ok((Message::Integer(-1939), &b""[..]))
         );
-
-        let data = str_to_bytes("1939\r\naab");
         assert_eq!(
-            parse_integer(data),
-            Ok((Message::Integer(1939), BytesMut::from("aab")))
+            parse_integer(b"1939\r\naab"),
+            Ok((Message::Integer(1939), &b"aab"[..]))
         );
-
-        let data = str_to_bytes("+234\r\n");
         assert_eq!(
-            parse_integer(data),
-            Ok((Message::Integer(234), BytesMut::new()))
+            parse_integer(b"+234\r\n"),
+            Ok((Message::Integer(234), &b""[..]))
         );
     }
 
@@ -335,6 +653,115 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_streamed_array() {
+        assert_array(
+            "?\r\n:1\r\n$3\r\ntwo\r\n.\r\nrest",
+            vec![Message::Integer(1), Message::BulkString("two".to_string())],
+            "rest",
+        );
+        assert_array("?\r\n.\r\n", vec![], "");
+    }
+
+    #[test]
+    fn test_incomplete_streamed_array() {
+        let data = str_to_bytes("?\r\n:1\r\n.");
+        assert_eq!(parse_array(data), Err(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn test_chunked_bulk_string() {
+        assert_bulk_string_with_rest(
+            "?\r\n;4\r\nHell\r\n;5\r\no wor\r\n;2\r\nld\r\n;0\r\nrest",
+            "Hello world",
+            "rest",
+        );
+    }
+
+    #[test]
+    fn test_incomplete_chunked_bulk_string() {
+        let data = str_to_bytes("?\r\n;4\r\nHell\r\n;5\r\no w");
+        assert_eq!(parse_bulk_string(data), Err(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn test_attribute_with_simple_string() {
+        let data = str_to_bytes("|1\r\n+key-popularity\r\n*1\r\n:7\r\n+OK\r\nrest");
+        assert_eq!(
+            parse(data),
+            Ok((
+                Message::Attribute {
+                    attrs: vec![(
+                        Message::SimpleString("key-popularity".to_string()),
+                        Message::Array(vec![Message::Integer(7)]),
+                    )],
+                    value: Box::new(Message::SimpleString("OK".to_string())),
+                },
+                BytesMut::from("rest")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_incomplete_attribute() {
+        let data = str_to_bytes("|1\r\n+key\r\n+value\r\n");
+        assert_eq!(parse(data), Err(ParseError::Incomplete));
+    }
+
+    fn assert_round_trip(message: Message) {
+        let mut data = BytesMut::from(&message.to_data()[..]);
+        assert_eq!(vec![message], parse_data(&mut data).unwrap());
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_resp3_round_trip() {
+        assert_round_trip(Message::Null);
+        assert_round_trip(Message::Boolean(true));
+        assert_round_trip(Message::Boolean(false));
+        assert_round_trip(Message::Double(-1.25));
+        assert_round_trip(Message::Double(f64::INFINITY));
+        assert_round_trip(Message::Double(f64::NEG_INFINITY));
+        assert_round_trip(Message::Set(vec![
+            Message::Integer(1),
+            Message::BulkString("two".to_string()),
+        ]));
+        assert_round_trip(Message::Map(vec![(
+            Message::BulkString("key".to_string()),
+            Message::Array(vec![Message::Null, Message::Double(0.5)]),
+        )]));
+    }
+
+    #[test]
+    fn test_nan_double() {
+        let data = str_to_bytes(",nan\r\n");
+        if let Ok((Message::Double(the_double), _)) = parse(data) {
+            assert!(the_double.is_nan());
+        } else {
+            panic!("nan not parsed as double");
+        }
+    }
+
+    #[test]
+    fn test_invalid_resp3_values() {
+        assert_eq!(
+            parse(str_to_bytes("#x\r\n")),
+            Err(ParseError::InvalidValue("x".to_string()))
+        );
+        assert_eq!(
+            parse(str_to_bytes(",1.2.3\r\n")),
+            Err(ParseError::InvalidValue("1.2.3".to_string()))
+        );
+        assert_eq!(
+            parse(str_to_bytes("_x\r\n")),
+            Err(ParseError::InvalidValue("x".to_string()))
+        );
+        assert_eq!(
+            parse(str_to_bytes("%1\r\n+key\r\n")),
+            Err(ParseError::Incomplete)
+        );
+    }
+
     #[test]
     fn test_parse() {
         let data = str_to_bytes("+simple\r\n");
@@ -346,9 +773,9 @@ mod tests {
 
     #[test]
     fn test_parse_data_multiple_messages() {
-        let data = str_to_bytes("*3\r\n$3\r\nSET\r\n$3\r\nbar\r\n$3\r\n456\r\n*3\r\n$3\r\nSET\r\n$3\r\nbaz\r\n$3\r\n789\r\n");
+        let mut data = str_to_bytes("*3\r\n$3\r\nSET\r\n$3\r\nbar\r\n$3\r\n456\r\n*3\r\n$3\r\nSET\r\n$3\r\nbaz\r\n$3\r\n789\r\n");
         assert_eq!(
-            parse_data(data).unwrap(),
+            parse_data(&mut data).unwrap(),
             vec![
                 Message::Array(vec![
                     Message::BulkString("SET".to_string()),
@@ -365,23 +792,189 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_data_rdb_file() {
+    fn test_parse_messages_stops_at_limit() {
+        let mut data = str_to_bytes("+one\r\n+two\r\n+three\r\n");
+
+        assert_eq!(
+            parse_messages(&mut data, 2, &ParseLimits::UNLIMITED).unwrap(),
+            vec![
+                Message::SimpleString("one".to_string()),
+                Message::SimpleString("two".to_string()),
+            ]
+        );
+        assert_eq!(data, str_to_bytes("+three\r\n"));
+    }
+
+    #[test]
+    fn test_parse_rdb_file() {
         let rdb = Message::rdb_file_from_hex(RDB_HEX);
         let data = rdb.to_data();
 
-        assert_eq!(parse_data(BytesMut::from(&data[..])).unwrap()[0], rdb);
+        let mut buffer = BytesMut::from(&data[..data.len() - 1]);
+        assert_eq!(None, parse_rdb_file(&mut buffer).unwrap());
+        buffer.extend_from_slice(&data[data.len() - 1..]);
+        assert_eq!(Some(rdb), parse_rdb_file(&mut buffer).unwrap());
+        assert!(buffer.is_empty());
     }
 
     #[test]
-    fn test_parse_data_rdb_file_and_message() {
+    fn test_parse_rdb_file_and_message() {
         let rdb = Message::rdb_file_from_hex(RDB_HEX);
         let mut data = rdb.to_data();
         let message2 = Message::SimpleString("theother".to_string());
         data.extend_from_slice(&message2.to_data());
 
+        let mut buffer = BytesMut::from(&data[..]);
+        assert_eq!(Some(rdb), parse_rdb_file(&mut buffer).unwrap());
+        assert_eq!(vec![message2], parse_data(&mut buffer).unwrap());
+    }
+
+    #[test]
+    fn test_client_bulk_starting_like_rdb_file_waits_for_linebreak() {
+        let mut buffer = str_to_bytes("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$8\r\nREDIS123");
+        assert!(parse_data(&mut buffer).unwrap().is_empty());
+
+        buffer.extend_from_slice(b"\r\n");
+        assert_eq!(
+            vec![Message::Array(vec![
+                Message::BulkString("SET".to_string()),
+                Message::BulkString("k".to_string()),
+                Message::BulkString("REDIS123".to_string()),
+            ])],
+            parse_data(&mut buffer).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_deep_nesting_is_rejected() {
+        let mut buffer = BytesMut::from("*1\r\n".repeat(100000).as_str());
+        assert_eq!(Err(ParseError::TooDeep), parse_data(&mut buffer));
+
+        let mut buffer = BytesMut::from(format!("{}:1\r\n", "*1\r\n".repeat(10)).as_str());
+        assert_eq!(1, parse_data(&mut buffer).unwrap().len());
+    }
+
+    #[test]
+    fn test_incomplete_bulk_string() {
+        let data = str_to_bytes("5\r\nHel");
+        assert_eq!(parse_bulk_string(data), Err(ParseError::Incomplete));
+
+        let data = str_to_bytes("5\r\nHello");
+        assert_eq!(parse_bulk_string(data), Err(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn test_bulk_string_missing_terminator() {
+        let data = str_to_bytes("5\r\nHelloXX");
+        assert_eq!(
+            parse_bulk_string(data),
+            Err(ParseError::InvalidString(Bytes::from("HelloXX")))
+        );
+    }
+
+    #[test]
+    fn test_parse_data_keeps_incomplete_message() {
+        let mut data = str_to_bytes("+first\r\n*2\r\n$4\r\nECHO\r\n$2\r\nh");
+
         assert_eq!(
-            parse_data(BytesMut::from(&data[..])).unwrap(),
-            vec![rdb, message2]
+            parse_data(&mut data).unwrap(),
+            vec![Message::SimpleString("first".to_string())]
         );
+        assert_eq!(data, str_to_bytes("*2\r\n$4\r\nECHO\r\n$2\r\nh"));
+
+        data.extend_from_slice(b"i\r\n");
+        assert_eq!(
+            parse_data(&mut data).unwrap(),
+            vec![Message::Array(vec![
+                Message::BulkString("ECHO".to_string()),
+                Message::BulkString("hi".to_string()),
+            ])]
+        );
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_parse_data_bulk_string_one_byte_at_a_time() {
+        let input = b"$12\r\nHello\r\nThere\r\n";
+        let mut buffer = BytesMut::new();
+
+        for (i, byte) in input.iter().enumerate() {
+            buffer.extend_from_slice(&[*byte]);
+            let messages = parse_data(&mut buffer).unwrap();
+
+            if i + 1 < input.len() {
+                assert!(messages.is_empty());
+                assert_eq!(&input[..=i], &buffer[..]);
+            } else {
+                assert_eq!(
+                    vec![Message::BulkString("Hello\r\nThere".to_string())],
+                    messages
+                );
+                assert!(buffer.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_data_large_bulk_string_split_in_two() {
+        let value = "x".repeat(5000);
+        let data = Message::BulkString(value.clone()).to_data();
+        let mut buffer = BytesMut::from(&data[..1024]);
+
+        assert!(parse_data(&mut buffer).unwrap().is_empty());
+        assert_eq!(1024, buffer.len());
+
+        buffer.extend_from_slice(&data[1024..]);
+        assert_eq!(
+            vec![Message::BulkString(value)],
+            parse_data(&mut buffer).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_messages_rejects_bulk_over_limit() {
+        let limits = ParseLimits {
+            max_bulk_len: 4,
+            max_inline_len: PROTO_INLINE_MAX_SIZE,
+        };
+
+        let mut data = str_to_bytes("*1\r\n$4\r\nabcd\r\n");
+        assert_eq!(1, parse_messages(&mut data, 1, &limits).unwrap().len());
+
+        // rejected on the length, before the content arrived
+        let mut data = str_to_bytes("*1\r\n$5\r\nab");
+        assert_eq!(
+            parse_messages(&mut data, 1, &limits),
+            Err(ParseError::InvalidBulkLength)
+        );
+
+        let mut data = str_to_bytes("$?\r\n;3\r\nabc\r\n;2\r\nde\r\n;0\r\n");
+        assert_eq!(
+            parse_messages(&mut data, 1, &limits),
+            Err(ParseError::InvalidBulkLength)
+        );
+    }
+
+    #[test]
+    fn test_parse_messages_rejects_line_over_inline_limit() {
+        let limits = ParseLimits {
+            max_bulk_len: usize::MAX,
+            max_inline_len: 8,
+        };
+
+        let mut data = str_to_bytes("+one\r\n+1234567");
+        assert_eq!(1, parse_messages(&mut data, 2, &limits).unwrap().len());
+
+        data.extend_from_slice(b"9");
+        assert_eq!(
+            parse_messages(&mut data, 2, &limits),
+            Err(ParseError::InlineTooBig)
+        );
+    }
+
+    #[test]
+    fn test_parse_data_unknown_type() {
+        let mut data = str_to_bytes("?what\r\n");
+        assert_eq!(parse_data(&mut data), Err(ParseError::UnknownMessage('?')));
     }
 }
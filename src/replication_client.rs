@@ -1,97 +1,169 @@
+use std::{sync::Arc, time::Duration};
+
 use anyhow::{bail, Context, Result};
-use bytes::BytesMut;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::{TcpStream, ToSocketAddrs},
+    time::sleep,
 };
 
 use crate::{
-    command_parser::Command, handler::replication::ReplicationHandler, message::Message,
-    parser::parse_data,
+    command_parser::Command, frame_reader::FrameReader, handler::replication::ReplicationHandler,
+    message::Message, ServerConfig,
 };
 
+// Wait time before reconnecting to the leader, doubled after every failed
+// attempt up to the maximum.
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+// Keeps the replica connected, after the connection to the leader is lost
+// the handshake is started again.
 pub async fn start_replication(
-    listener_port: u16,
-    leader_addr: impl ToSocketAddrs,
+    config: Arc<ServerConfig>,
+    leader_addr: impl ToSocketAddrs + Clone,
     mut handler: ReplicationHandler,
+) {
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+
+    loop {
+        let result = replicate(&config, leader_addr.clone(), &mut handler).await;
+        if config.master_link_up() {
+            backoff = RECONNECT_INITIAL_BACKOFF;
+        }
+        config.set_master_link_up(false);
+
+        if let Err(error) = result {
+            eprintln!("replication: {:?}", error);
+        }
+        println!("reconnecting to leader in {:?}", backoff);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+    }
+}
+
+async fn replicate(
+    config: &ServerConfig,
+    leader_addr: impl ToSocketAddrs,
+    handler: &mut ReplicationHandler,
 ) -> Result<()> {
-    let mut stream = TcpStream::connect(leader_addr).await?;
+    let stream = TcpStream::connect(leader_addr).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = FrameReader::new(reader);
 
-    send_message(Command::get_ping_command(), &mut stream).await?;
-    let reply = get_reply(&mut stream).await.context("leader ping")?;
+    send_message(Command::get_ping_command(), &mut writer).await?;
+    let reply = reader.read_message().await.context("leader ping")?;
     ReplicationHandler::check_ping_reply(&reply)?;
 
     send_message(
-        Command::get_replconf_command("listening-port", listener_port),
-        &mut stream,
+        Command::get_replconf_command("listening-port", config.listener_port),
+        &mut writer,
     )
     .await?;
-    let reply = get_reply(&mut stream).await.context("replconf port")?;
+    let reply = reader.read_message().await.context("replconf port")?;
     ReplicationHandler::check_replconf_reply(&reply).context("replconf listening port")?;
 
-    send_message(Command::get_replconf_command("capa", "psync2"), &mut stream).await?;
-    let reply = get_reply(&mut stream).await.context("replconf capa")?;
+    send_message(Command::get_replconf_command("capa", "psync2"), &mut writer).await?;
+    let reply = reader.read_message().await.context("replconf capa")?;
     ReplicationHandler::check_replconf_reply(&reply).context("capa")?;
 
-    send_message(Command::get_psync_command("?", -1), &mut stream).await?;
-    let replies = read_from_leader(&mut stream).await.context("psync")?;
-    ReplicationHandler::check_psync_reply(&replies[0])?;
-
-    if replies.len() == 1 {
-        // the reply to psync did not contain the rdb file, read it separately
-        let _rdb_file = read_from_leader(&mut stream)
-            .await
-            .context("replication rdb file")?;
-    } else if replies.len() > 2 {
-        handle_messages(&replies[2..], &mut stream, &mut handler).await?;
-    }
+    send_message(Command::get_psync_command("?", -1), &mut writer).await?;
+    let reply = reader.read_message().await.context("psync")?;
+    let offset = ReplicationHandler::check_psync_reply(&reply)?;
 
-    loop {
-        let repl_messages = read_from_leader(&mut stream).await?;
-        handle_messages(&repl_messages, &mut stream, &mut handler).await?;
+    let rdb_file = reader
+        .read_rdb_file()
+        .await
+        .context("replication rdb file")?;
+    if !matches!(rdb_file, Message::RdbFile(_)) {
+        bail!("expected rdb file, got {}", rdb_file);
     }
+
+    // the offset starts again with a full resync
+    handler.reset_acknowledged(offset);
+    config.set_master_link_up(true);
+    handle_messages(&mut reader, &mut writer, handler).await
 }
 
-async fn send_message(message: Message, stream: &mut TcpStream) -> Result<()> {
-    stream.write_all(&message.to_data()).await?;
+async fn send_message(message: Message, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+    writer.write_all(&message.to_data()).await?;
     Ok(())
 }
 
-async fn read_from_leader_raw(stream: &mut TcpStream) -> Result<BytesMut> {
-    let mut buffer = BytesMut::with_capacity(1024);
-    let n = stream.read_buf(&mut buffer).await?;
-    if n == 0 {
-        bail!("connection closed by leader");
+async fn handle_messages(
+    reader: &mut FrameReader<impl AsyncRead + Unpin>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    handler: &mut ReplicationHandler,
+) -> Result<()> {
+    loop {
+        let message = reader.read_message().await?;
+        if let Some(reply) = handler.handle(&message).await? {
+            send_message(reply, writer).await?;
+        }
     }
-
-    Ok(buffer.split())
 }
 
-async fn read_from_leader(stream: &mut TcpStream) -> Result<Vec<Message>> {
-    let buffer = read_from_leader_raw(stream).await?;
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, sync::broadcast, time::timeout};
+
+    use crate::{db::Db, ServerRole};
+
+    use super::*;
+
+    // Answers the handshake of the replica like a leader.
+    async fn accept_replica(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.split();
+        let mut reader = FrameReader::new(reader);
+
+        let replies = [
+            Message::SimpleString("PONG".to_string()),
+            Message::SimpleString("OK".to_string()),
+            Message::SimpleString("OK".to_string()),
+            Message::SimpleString(
+                "FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0".to_string(),
+            ),
+        ];
+        for reply in replies {
+            reader.read_message().await.unwrap();
+            writer.write_all(&reply.to_data()).await.unwrap();
+        }
+        let rdb_file = Message::RdbFile(b"REDIS0011\xff".to_vec());
+        writer.write_all(&rdb_file.to_data()).await.unwrap();
 
-    Ok(parse_data(buffer)?)
-}
+        stream
+    }
 
-async fn get_reply(stream: &mut TcpStream) -> Result<Message> {
-    let mut res = read_from_leader(stream).await?;
-    if res.len() != 1 {
-        bail!("Exactly one reply expected");
+    async fn wait_for_link(config: &ServerConfig, up: bool) {
+        timeout(Duration::from_secs(1), async {
+            while config.master_link_up() != up {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("link status did not change");
     }
 
-    Ok(res.swap_remove(0))
-}
+    #[tokio::test]
+    async fn test_reconnect_after_dropped_connection() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(ServerConfig::new(ServerRole::Follower, 0));
+        let (tx, _) = broadcast::channel(1);
+        let handler = ReplicationHandler::new(Arc::new(Db::new()), config.clone(), tx);
+        let replication = tokio::spawn(start_replication(config.clone(), addr, handler));
 
-async fn handle_messages(
-    repl_messages: &[Message],
-    stream: &mut TcpStream,
-    handler: &mut ReplicationHandler,
-) -> Result<()> {
-    for message in repl_messages {
-        if let Some(reply) = handler.handle(message).await? {
-            send_message(reply, stream).await?;
-        }
-    }
+        let stream = accept_replica(&listener).await;
+        wait_for_link(&config, true).await;
 
-    Ok(())
+        drop(stream);
+        wait_for_link(&config, false).await;
+
+        let reconnect = timeout(Duration::from_secs(2), accept_replica(&listener)).await;
+        assert!(reconnect.is_ok(), "replica did not reconnect");
+        wait_for_link(&config, true).await;
+
+        replication.abort();
+    }
 }
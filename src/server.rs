@@ -3,16 +3,25 @@ use std::sync::Arc;
 use anyhow::Result;
 use bytes::BytesMut;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::broadcast::Sender,
+    sync::broadcast::{Receiver, Sender},
+    task::yield_now,
 };
 
 use crate::{
-    db::Db, handler::client_server::MessageHandler, message::Message, parser::parse_data,
+    command_parser::{parse_command, Command},
+    db::Db,
+    handler::client_server::MessageHandler,
+    message::Message,
+    parser::{parse_messages, ParseError, ParseLimits},
     ServerConfig,
 };
 
+// Commands of a pipeline handled in one go before the replies are sent
+// and other tasks get a chance to run.
+const PIPELINE_BATCH_SIZE: usize = 128;
+
 struct ServerState {
     handler: MessageHandler,
     stream: TcpStream,
@@ -22,11 +31,12 @@ struct ServerState {
 
 pub async fn start(config: Arc<ServerConfig>, db: Arc<Db>, tx: Sender<Message>) -> Result<()> {
     let listener = TcpListener::bind(("127.0.0.1", config.listener_port)).await?;
+    config.set_ready();
 
     loop {
         let stream = listener.accept().await;
         match stream {
-            Ok((stream, _)) => {
+            Ok((stream, client_addr)) => {
                 println!("accepted new connection");
                 let db_cloned = db.clone();
                 let config_cloned = config.clone();
@@ -34,8 +44,10 @@ pub async fn start(config: Arc<ServerConfig>, db: Arc<Db>, tx: Sender<Message>)
                 let tx_cloned = tx.clone();
                 let o_tx_cloned2 = Some(tx.clone());
                 tokio::spawn(async move {
+                    let mut handler = MessageHandler::new(db_cloned, config_cloned, tx_cloned);
+                    handler.set_client_addr(client_addr.to_string());
                     let state = ServerState {
-                        handler: MessageHandler::new(db_cloned, config_cloned, tx_cloned),
+                        handler,
                         stream,
                         sender: o_tx_cloned2,
                         config: config_cloned2,
@@ -56,14 +68,32 @@ async fn handle_connection(mut state: ServerState) -> Result<()> {
     let mut buffer = BytesMut::with_capacity(1024);
 
     loop {
-        let n = state.stream.read_buf(&mut buffer).await?;
+        let limits = state.config.parse_limits().await;
+        let messages = match parse_messages(&mut buffer, PIPELINE_BATCH_SIZE, &limits) {
+            Ok(messages) => messages,
+            Err(err) => return reply_protocol_error(&mut state.stream, err).await,
+        };
 
-        if n == 0 {
-            println!("Connection closed by client");
-            return Ok(());
-        }
+        // Only read more when every complete command in the buffer is
+        // handled, a pipelining client cannot grow the buffer without bound.
+        // While waiting, messages of subscribed channels are pushed.
+        if messages.is_empty() {
+            let n = tokio::select! {
+                n = state.stream.read_buf(&mut buffer) => n?,
+                push = state.handler.next_push() => {
+                    if let Some(push) = push {
+                        state.stream.write_all(&push).await?;
+                    }
+                    continue;
+                }
+            };
 
-        let messages = parse_data(buffer.split())?;
+            if n == 0 {
+                println!("Connection closed by client");
+                return Ok(());
+            }
+            continue;
+        }
 
         for message in messages {
             println!("Received from client: {}", message);
@@ -76,11 +106,15 @@ async fn handle_connection(mut state: ServerState) -> Result<()> {
         }
 
         if state.handler.replication_client_acknowleged() {
-            state.config.add_replication_client().await;
+            let replica = state.handler.client_addr().to_string();
+            state.config.add_replication_client(&replica);
             let res = handle_replication_client(&mut state).await;
-            state.config.remove_replication_client().await;
+            state.config.remove_replication_client(&replica);
             return res;
         }
+
+        // let other connections run between the batches of a long pipeline
+        yield_now().await;
     }
 }
 
@@ -90,16 +124,301 @@ async fn handle_replication_client(state: &mut ServerState) -> Result<()> {
     // TODO: check if this is the correct logic to not have any receiver open.
     // The main goal is to open the receiver when it is needed, for that
     // the sender is used. Does a resubscribe lead to the receiver to just fill up?
-    let mut rx = sender.subscribe();
+    let rx = sender.subscribe();
     std::mem::drop(sender);
+    let replica = state.handler.client_addr().to_string();
+
+    replicate_to(&mut state.stream, &state.config, &replica, rx).await
+}
+
+// Sends the writes to the replica and reads back the offsets it
+// acknowledges, the replica sends nothing else.
+async fn replicate_to(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    config: &ServerConfig,
+    replica: &str,
+    mut rx: Receiver<Message>,
+) -> Result<()> {
+    let mut buffer = BytesMut::with_capacity(1024);
 
     loop {
-        let message = rx.recv().await?;
-        write_all(&mut state.stream, message).await?;
+        tokio::select! {
+            message = rx.recv() => write_all(stream, message?).await?,
+            n = stream.read_buf(&mut buffer) => {
+                if n? == 0 {
+                    println!("replica {} closed the connection", replica);
+                    return Ok(());
+                }
+                for message in parse_messages(&mut buffer, usize::MAX, &ParseLimits::UNLIMITED)? {
+                    acknowledge(config, replica, &message);
+                }
+            }
+        }
+    }
+}
+
+fn acknowledge(config: &ServerConfig, replica: &str, message: &Message) {
+    match parse_command(message) {
+        Ok(Command::Replconf {
+            name,
+            value: Message::BulkString(offset),
+        }) if name.to_uppercase() == "ACK" => match offset.parse::<u64>() {
+            Ok(offset) => config.acknowledge_replica(replica, offset),
+            Err(_) => eprintln!("replica {} sent invalid offset {}", replica, offset),
+        },
+        _ => eprintln!("unexpected message from replica {}: {}", replica, message),
     }
 }
 
-async fn write_all(stream: &mut TcpStream, message: Message) -> Result<()> {
+// A framing error leaves the stream desynchronized, the only safe option is
+// to report it and close the connection.
+async fn reply_protocol_error(
+    stream: &mut (impl AsyncWrite + Unpin),
+    err: ParseError,
+) -> Result<()> {
+    println!("Protocol error: {}", err);
+    let message = Message::Error(format!("ERR Protocol error: {}", err));
+    write_all(stream, message).await
+}
+
+async fn write_all(stream: &mut (impl AsyncWrite + Unpin), message: Message) -> Result<()> {
     stream.write_all(&message.to_data()).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{sync::broadcast, time::sleep};
+
+    use crate::ServerRole;
+
+    use super::*;
+
+    async fn connect_client() -> TcpStream {
+        let db = Arc::new(Db::new());
+        let config = Arc::new(ServerConfig::new(ServerRole::Leader, 0));
+        connect_client_to(db, config).await
+    }
+
+    // A connection to a server sharing the db and config with others.
+    async fn connect_client_to(db: Arc<Db>, config: Arc<ServerConfig>) -> TcpStream {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let (tx, _) = broadcast::channel(1);
+        let state = ServerState {
+            handler: MessageHandler::new(db, config.clone(), tx.clone()),
+            stream,
+            sender: Some(tx),
+            config,
+        };
+        tokio::spawn(handle_connection(state));
+
+        client
+    }
+
+    async fn read_reply(client: &mut TcpStream) -> Vec<u8> {
+        let mut buffer = vec![0; 1024];
+        let n = client.read(&mut buffer).await.unwrap();
+        buffer.truncate(n);
+        buffer
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_message_is_buffered() {
+        let mut client = connect_client().await;
+
+        client.write_all(b"*1\r\n$4\r\nPI").await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        client.write_all(b"NG\r\n").await.unwrap();
+
+        assert_eq!(b"$4\r\nPONG\r\n".to_vec(), read_reply(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn test_large_pipeline_gets_all_replies() {
+        let mut client = connect_client().await;
+        let count = PIPELINE_BATCH_SIZE * 10 + 3;
+
+        let mut pipeline = vec![];
+        for i in 0..count {
+            let echo = Message::Array(vec![
+                Message::BulkString("ECHO".to_string()),
+                Message::BulkString(i.to_string()),
+            ]);
+            pipeline.extend(echo.to_data());
+        }
+        let mut expected = vec![];
+        for i in 0..count {
+            expected.extend(Message::BulkString(i.to_string()).to_data());
+        }
+
+        let (mut reader, mut writer) = client.split();
+        let write = async {
+            writer.write_all(&pipeline).await.unwrap();
+        };
+        let read = async {
+            let mut replies = vec![];
+            while replies.len() < expected.len() {
+                let n = reader.read_buf(&mut replies).await.unwrap();
+                assert!(n > 0, "connection closed early");
+            }
+            replies
+        };
+        let (_, replies) = tokio::join!(write, read);
+
+        assert_eq!(expected, replies);
+    }
+
+    fn command(parts: &[&str]) -> Vec<u8> {
+        Message::Array(
+            parts
+                .iter()
+                .map(|part| Message::BulkString(part.to_string()))
+                .collect(),
+        )
+        .to_data()
+    }
+
+    #[tokio::test]
+    async fn test_published_message_is_pushed_to_subscriber() {
+        let db = Arc::new(Db::new());
+        let config = Arc::new(ServerConfig::new(ServerRole::Leader, 0));
+        let mut subscriber = connect_client_to(db.clone(), config.clone()).await;
+        let mut publisher = connect_client_to(db, config).await;
+
+        subscriber
+            .write_all(&command(&["SUBSCRIBE", "news"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n".to_vec(),
+            read_reply(&mut subscriber).await
+        );
+
+        publisher
+            .write_all(&command(&["PUBLISH", "news", "hello"]))
+            .await
+            .unwrap();
+        assert_eq!(b":1\r\n".to_vec(), read_reply(&mut publisher).await);
+        assert_eq!(
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n".to_vec(),
+            read_reply(&mut subscriber).await
+        );
+
+        subscriber
+            .write_all(&command(&["UNSUBSCRIBE"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            b"*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n".to_vec(),
+            read_reply(&mut subscriber).await
+        );
+        publisher
+            .write_all(&command(&["PUBLISH", "news", "hello"]))
+            .await
+            .unwrap();
+        assert_eq!(b":0\r\n".to_vec(), read_reply(&mut publisher).await);
+    }
+
+    #[tokio::test]
+    async fn test_lowered_proto_max_bulk_len_rejects_bulk_string() {
+        let mut client = connect_client().await;
+        let set = command(&["SET", "key", "0123456789"]);
+
+        client.write_all(&set).await.unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), read_reply(&mut client).await);
+
+        client
+            .write_all(&command(&["CONFIG", "SET", "proto-max-bulk-len", "9"]))
+            .await
+            .unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), read_reply(&mut client).await);
+
+        client.write_all(&set).await.unwrap();
+        assert_eq!(
+            b"-ERR Protocol error: invalid bulk length\r\n".to_vec(),
+            read_reply(&mut client).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_binary_value_is_returned_unchanged() {
+        let mut client = connect_client().await;
+        let value = b"a\x00b\xff\xfe";
+
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\na\x00b\xff\xfe\r\n")
+            .await
+            .unwrap();
+        assert_eq!(b"+OK\r\n".to_vec(), read_reply(&mut client).await);
+
+        client.write_all(&command(&["GET", "key"])).await.unwrap();
+        let mut expected = b"$5\r\n".to_vec();
+        expected.extend(value);
+        expected.extend(b"\r\n");
+        assert_eq!(expected, read_reply(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn test_replica_acknowledgements_update_its_offset() {
+        let config = Arc::new(ServerConfig::new(ServerRole::Leader, 0));
+        let replica = "127.0.0.1:6380";
+        config.add_replication_client(replica);
+        let (tx, rx) = broadcast::channel(4);
+        let (mut replica_stream, leader_stream) = tokio::io::duplex(1024);
+
+        let config_cloned = config.clone();
+        let replication = tokio::spawn(async move {
+            let mut leader_stream = leader_stream;
+            replicate_to(&mut leader_stream, &config_cloned, replica, rx).await
+        });
+
+        let set = Message::Array(vec![
+            Message::BulkString("SET".to_string()),
+            Message::BulkString("key".to_string()),
+            Message::BulkString("value".to_string()),
+        ]);
+        let getack = Command::get_replconf_command("GETACK", "*");
+        tx.send(set.clone()).unwrap();
+        tx.send(getack.clone()).unwrap();
+
+        let mut expected = set.to_data();
+        expected.extend(getack.to_data());
+        let mut received = vec![0; expected.len()];
+        replica_stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(expected, received);
+
+        let offset = set.to_data().len() as u64;
+        replica_stream
+            .write_all(&Command::get_replconf_command("ACK", offset).to_data())
+            .await
+            .unwrap();
+        assert_eq!(
+            1,
+            config
+                .wait_for_replicas(1, offset, Some(Duration::from_secs(1)))
+                .await
+        );
+
+        drop(replica_stream);
+        assert!(replication.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_message_replies_protocol_error_and_closes() {
+        let mut client = connect_client().await;
+
+        client.write_all(b"?what\r\n").await.unwrap();
+
+        assert_eq!(
+            b"-ERR Protocol error: unknown message type `?`\r\n".to_vec(),
+            read_reply(&mut client).await
+        );
+        assert!(read_reply(&mut client).await.is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+
+use chrono::Utc;
+
+use crate::message::Message;
+
+// Like redis, long commands are shortened before they are stored.
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+pub struct SlowlogEntry {
+    id: u64,
+    timestamp: i64,
+    micros: u64,
+    args: Vec<Message>,
+    client_addr: String,
+}
+
+// The slowest commands, newest first, bounded by slowlog-max-len.
+#[derive(Default)]
+pub struct Slowlog {
+    entries: VecDeque<SlowlogEntry>,
+    next_id: u64,
+}
+
+impl Slowlog {
+    pub fn record(&mut self, micros: u64, args: &[Message], client_addr: &str, max_len: usize) {
+        self.entries.push_front(SlowlogEntry {
+            id: self.next_id,
+            timestamp: Utc::now().timestamp(),
+            micros,
+            args: shorten_args(args),
+            client_addr: client_addr.to_string(),
+        });
+        self.next_id += 1;
+        self.entries.truncate(max_len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    // The newest `count` entries in the format of SLOWLOG GET.
+    pub fn get(&self, count: usize) -> Message {
+        Message::Array(
+            self.entries
+                .iter()
+                .take(count)
+                .map(SlowlogEntry::to_message)
+                .collect(),
+        )
+    }
+}
+
+impl SlowlogEntry {
+    fn to_message(&self) -> Message {
+        Message::Array(vec![
+            Message::Integer(self.id as i64),
+            Message::Integer(self.timestamp),
+            Message::Integer(self.micros as i64),
+            Message::Array(self.args.clone()),
+            Message::BulkString(self.client_addr.clone()),
+            // client names are not supported
+            Message::BulkString(String::new()),
+        ])
+    }
+}
+
+fn shorten_args(args: &[Message]) -> Vec<Message> {
+    let mut shortened = args
+        .iter()
+        .take(if args.len() > MAX_ARGS {
+            MAX_ARGS - 1
+        } else {
+            MAX_ARGS
+        })
+        .map(|arg| match arg {
+            Message::BulkString(value) if value.len() > MAX_ARG_LEN => {
+                let mut end = MAX_ARG_LEN;
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Message::BulkString(format!(
+                    "{}... ({} more bytes)",
+                    &value[..end],
+                    value.len() - end
+                ))
+            }
+            arg => arg.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    if args.len() > MAX_ARGS {
+        shortened.push(Message::BulkString(format!(
+            "... ({} more arguments)",
+            args.len() - MAX_ARGS + 1
+        )));
+    }
+    shortened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(count: usize) -> Vec<Message> {
+        (0..count)
+            .map(|i| Message::BulkString(i.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_record_is_bounded_and_newest_first() {
+        let mut slowlog = Slowlog::default();
+        for micros in 0..5 {
+            slowlog.record(micros, &args(1), "127.0.0.1:1234", 3);
+        }
+
+        assert_eq!(3, slowlog.len());
+        if let Message::Array(entries) = slowlog.get(1) {
+            assert_eq!(1, entries.len());
+            if let Message::Array(fields) = &entries[0] {
+                assert_eq!(Message::Integer(4), fields[0]);
+                assert_eq!(Message::Integer(4), fields[2]);
+            } else {
+                panic!("entry is not an array");
+            }
+        } else {
+            panic!("slowlog is not an array");
+        }
+
+        slowlog.reset();
+        assert_eq!(0, slowlog.len());
+    }
+
+    #[test]
+    fn test_long_commands_are_shortened() {
+        let shortened = shorten_args(&args(40));
+        assert_eq!(MAX_ARGS, shortened.len());
+        assert_eq!(
+            Message::BulkString("... (9 more arguments)".to_string()),
+            shortened[MAX_ARGS - 1]
+        );
+
+        let shortened = shorten_args(&[Message::BulkString("x".repeat(130))]);
+        assert_eq!(
+            Message::BulkString(format!("{}... (2 more bytes)", "x".repeat(128))),
+            shortened[0]
+        );
+    }
+}